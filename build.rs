@@ -0,0 +1,165 @@
+// Generates the C header from the declarations in src/capi.rs into OUT_DIR.
+// The committed include/saka.h is checked against it by a test in capi.rs,
+// so it can't drift from the code: after changing the API, copy the
+// generated header over it.
+//
+// The C API is written in a small subset of Rust this understands: the
+// leading comment of the file, `pub const` integers, the opaque
+// `pub struct SakaEmulator` and `extern "C"` functions over C types. Plain
+// `//` comments right before an item are copied to the header, `///` docs
+// are Rust only. Blank lines between items are kept.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SOURCE: &str = "src/capi.rs";
+const HEADER: &str = "saka.h";
+
+// Build scripts run in the package root, so SOURCE is found from there
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", SOURCE);
+
+    let source = fs::read_to_string(SOURCE)?;
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| io::Error::other("OUT_DIR isn't set"))?;
+    fs::write(Path::new(&out_dir).join(HEADER), generate(&source))
+}
+
+fn generate(source: &str) -> String {
+    let mut lines = source.lines().peekable();
+    let mut out = String::from("/*\n");
+    while let Some(line) = lines.next_if(|line| line.starts_with("//")) {
+        out += &format!(" *{}\n", &line[2..]);
+    }
+    out += " *\n * Generated from src/capi.rs by build.rs, don't edit.\n */\n\n";
+    out += "#ifndef SAKA_H\n#define SAKA_H\n\n";
+    out += "#include <stddef.h>\n#include <stdint.h>\n\n";
+    out += "#ifdef __cplusplus\nextern \"C\" {\n#endif\n";
+
+    let mut comment = Vec::new();
+    let mut blank = true;
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        if trimmed == "#[cfg(test)]" {
+            break;
+        }
+        let declaration = if let Some(constant) = trimmed.strip_prefix("pub const ") {
+            Some(define(constant))
+        } else if let Some(name) = trimmed.strip_prefix("pub struct ") {
+            let name = name.trim_end_matches(" {").trim_end_matches(';');
+            Some(format!("typedef struct {} {};", name, name))
+        } else if trimmed.contains("extern \"C\" fn ") {
+            let mut signature = trimmed.to_string();
+            while !signature.contains('{') {
+                signature += " ";
+                signature += lines.next().unwrap().trim();
+            }
+            Some(function(&signature))
+        } else {
+            None
+        };
+
+        if let Some(declaration) = declaration {
+            if blank {
+                out += "\n";
+            }
+            out += &c_comment(&comment);
+            out += &declaration;
+            out += "\n";
+            comment.clear();
+            blank = false;
+        } else if let Some(text) = trimmed.strip_prefix("// ") {
+            comment.push(text.to_string());
+        } else if !trimmed.starts_with("///") && !trimmed.starts_with("#[") {
+            comment.clear();
+            blank |= trimmed.is_empty();
+        }
+    }
+
+    out += "\n#ifdef __cplusplus\n}\n#endif\n\n#endif /* SAKA_H */\n";
+    out
+}
+
+fn c_comment(lines: &[String]) -> String {
+    match lines {
+        [] => String::new(),
+        [line] => format!("/* {} */\n", line),
+        [first, rest @ ..] => {
+            let mut out = format!("/* {}\n", first);
+            for line in rest {
+                out += &format!(" * {}\n", line);
+            }
+            out.insert_str(out.len() - 1, " */");
+            out
+        }
+    }
+}
+
+// `NAME: TYPE = VALUE;` as a #define, negative values in parentheses
+fn define(constant: &str) -> String {
+    let (name, rest) = constant.split_once(':').unwrap();
+    let value = rest.split_once('=').unwrap().1.trim().trim_end_matches(';');
+    if value.starts_with('-') {
+        format!("#define {} ({})", name, value)
+    } else {
+        format!("#define {} {}", name, value)
+    }
+}
+
+// `pub unsafe extern "C" fn name(arg: Type, ...) -> Type {` as a prototype
+fn function(signature: &str) -> String {
+    let rest = signature.split("fn ").nth(1).unwrap();
+    let (name, rest) = rest.split_once('(').unwrap();
+    let (params, rest) = rest.rsplit_once(')').unwrap();
+    let params: Vec<String> = params
+        .split(',')
+        .map(str::trim)
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, ty) = param.split_once(':').unwrap();
+            declarator(ty, name.trim())
+        })
+        .collect();
+    let params = match params.is_empty() {
+        true => "void".to_string(),
+        false => params.join(", "),
+    };
+    let ret = match rest.split_once("->") {
+        Some((_, ret)) => ret.trim_end_matches('{'),
+        None => "()",
+    };
+    format!("{};", declarator(ret, &format!("{}({})", name, params)))
+}
+
+// `name` declared with the C spelling of Rust type `ty`
+fn declarator(ty: &str, name: &str) -> String {
+    let ty = c_type(ty);
+    match ty.ends_with('*') {
+        true => format!("{}{}", ty, name),
+        false => format!("{} {}", ty, name),
+    }
+}
+
+fn c_type(ty: &str) -> String {
+    let ty = ty.trim();
+    if let Some(pointee) = ty.strip_prefix("*const ") {
+        return format!("const {} *", c_type(pointee));
+    }
+    if let Some(pointee) = ty.strip_prefix("*mut ") {
+        return format!("{} *", c_type(pointee));
+    }
+    match ty {
+        "()" => "void",
+        "c_int" => "int",
+        "c_uint" => "unsigned int",
+        "c_float" => "float",
+        "u8" => "uint8_t",
+        "u32" => "uint32_t",
+        "usize" => "size_t",
+        "SakaEmulator" => "SakaEmulator",
+        _ => panic!("{} has no C type in build.rs", ty),
+    }
+    .to_string()
+}
@@ -0,0 +1,83 @@
+/*
+ * C API of saka-nes-simulator, exported by the cdylib build of the crate.
+ *
+ * An emulator is an opaque handle from saka_create, freed with saka_destroy.
+ * Functions return SAKA_OK or a negative error code. Panics never cross the
+ * API: they are returned as SAKA_ERROR_PANIC and unload the ROM, since the
+ * machine may have been left half updated. A handle must only be used from
+ * one thread at a time.
+ *
+ * Generated from src/capi.rs by build.rs, don't edit.
+ */
+
+#ifndef SAKA_H
+#define SAKA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SAKA_OK 0
+#define SAKA_ERROR_NULL_POINTER (-1)
+/* No ROM is loaded */
+#define SAKA_ERROR_NO_ROM (-2)
+#define SAKA_ERROR_INVALID_ROM (-3)
+#define SAKA_ERROR_INVALID_STATE (-4)
+#define SAKA_ERROR_BUFFER_TOO_SMALL (-5)
+#define SAKA_ERROR_INVALID_ARGUMENT (-6)
+#define SAKA_ERROR_PANIC (-7)
+
+#define SAKA_SCREEN_WIDTH 256
+#define SAKA_SCREEN_HEIGHT 240
+
+/* Controller buttons, or'ed together for saka_set_input */
+#define SAKA_BUTTON_A 0x01
+#define SAKA_BUTTON_B 0x02
+#define SAKA_BUTTON_SELECT 0x04
+#define SAKA_BUTTON_START 0x08
+#define SAKA_BUTTON_UP 0x10
+#define SAKA_BUTTON_DOWN 0x20
+#define SAKA_BUTTON_LEFT 0x40
+#define SAKA_BUTTON_RIGHT 0x80
+
+typedef struct SakaEmulator SakaEmulator;
+
+SakaEmulator *saka_create(void);
+
+void saka_destroy(SakaEmulator *emulator);
+
+/* Loads an iNES or NES 2.0 image, replacing any loaded ROM. The data is
+ * copied */
+int saka_load_rom(SakaEmulator *emulator, const uint8_t *data, size_t len);
+
+/* Kept across ROM loads */
+int saka_set_sample_rate(SakaEmulator *emulator, unsigned int sample_rate);
+
+int saka_run_frame(SakaEmulator *emulator);
+
+/* port is 0 or 1 */
+int saka_set_input(SakaEmulator *emulator, unsigned int port, uint8_t buttons);
+
+/* SAKA_SCREEN_WIDTH * SAKA_SCREEN_HEIGHT pixels in 0x00RRGGBB form, or NULL
+ * without a ROM. Valid until the next call taking the emulator */
+const uint32_t *saka_get_framebuffer(SakaEmulator *emulator);
+
+/* Takes up to capacity mono samples generated by saka_run_frame, setting
+ * count to how many were copied. At most a second of samples is kept */
+int saka_get_audio(SakaEmulator *emulator, float *samples, size_t capacity, size_t *count);
+
+/* Sets size to the size of the state and copies it if it fits in capacity
+ * bytes, otherwise returns SAKA_ERROR_BUFFER_TOO_SMALL. Pass NULL to ask for
+ * the size */
+int saka_save_state(SakaEmulator *emulator, uint8_t *data, size_t capacity, size_t *size);
+
+int saka_load_state(SakaEmulator *emulator, const uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SAKA_H */
@@ -0,0 +1,240 @@
+// Dmc implements the delta modulation channel
+// https://wiki.nesdev.com/w/index.php/APU_DMC
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+// Rates in CPU cycles (NTSC)
+const DMC_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub irq_flag: bool,
+    looping: bool,
+    timer_period: u16,
+    timer_value: u16,
+    // 7-bit output level
+    value: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    // Output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    // Memory reader
+    sample_buffer: Option<u8>,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq_flag: false,
+            looping: false,
+            timer_period: DMC_TABLE[0],
+            timer_value: 0,
+            value: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            sample_buffer: None,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.timer_period = DMC_TABLE[(value & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            }
+            // -DDD DDDD
+            1 => self.value = value & 0x7F,
+            // Sample address = %11AAAAAA.AA000000
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            // Sample length = %LLLL.LLLL0001
+            3 => self.sample_length = ((value as u16) << 4) | 1,
+            _ => (),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address the memory reader wants to fetch, if the sample buffer is empty
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.current_address = match self.current_address {
+            0xFFFF => 0x8000,
+            address => address + 1,
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn step_timer(&mut self) {
+        if self.timer_value > 0 {
+            self.timer_value -= 1;
+            return;
+        }
+        self.timer_value = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.value <= 125 {
+                    self.value += 2;
+                }
+            } else if self.value >= 2 {
+                self.value -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.value
+    }
+}
+
+impl SaveState for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_flag);
+        w.write_bool(self.looping);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer_value);
+        w.write_u8(self.value);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        w.write_u8(self.shift_register);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = r.read_bool()?;
+        self.irq_flag = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        if self.timer_period == 0 {
+            return Err(StateError::Invalid("zero DMC timer period"));
+        }
+        self.timer_value = r.read_u16()?;
+        self.value = r.read_u8()?;
+        if self.value > 127 {
+            return Err(StateError::Invalid("DMC output level out of range"));
+        }
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        self.shift_register = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        if !(1..=8).contains(&self.bits_remaining) {
+            return Err(StateError::Invalid("DMC bit count out of range"));
+        }
+        self.silence = r.read_bool()?;
+        self.sample_buffer = match (r.read_bool()?, r.read_u8()?) {
+            (true, value) => Some(value),
+            (false, _) => None,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Saves `dmc` and loads it into a new one
+    fn reload(dmc: &Dmc) -> Result<(), StateError> {
+        let mut w = StateWriter::new();
+        dmc.save_state(&mut w);
+        Dmc::new().load_state(&mut StateReader::new(&w.into_inner()))
+    }
+
+    #[test]
+    fn test_reject_state() {
+        assert_eq!(reload(&Dmc::new()), Ok(()));
+
+        let mut dmc = Dmc::new();
+        dmc.timer_period = 0;
+        assert_eq!(
+            reload(&dmc),
+            Err(StateError::Invalid("zero DMC timer period"))
+        );
+
+        let mut dmc = Dmc::new();
+        dmc.value = 0x80;
+        assert_eq!(
+            reload(&dmc),
+            Err(StateError::Invalid("DMC output level out of range"))
+        );
+
+        for bits_remaining in [0, 9] {
+            let mut dmc = Dmc::new();
+            dmc.bits_remaining = bits_remaining;
+            assert_eq!(
+                reload(&dmc),
+                Err(StateError::Invalid("DMC bit count out of range"))
+            );
+        }
+    }
+}
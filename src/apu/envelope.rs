@@ -0,0 +1,88 @@
+// Envelope generator shared by the pulse and noise channels
+// https://wiki.nesdev.com/w/index.php/APU_Envelope
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    // Used both as the constant volume and as the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write_control(&mut self, value: u8) {
+        self.looping = value & 0b0010_0000 != 0;
+        self.constant_volume = value & 0b0001_0000 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Clocked by the frame counter every quarter frame
+    pub fn step(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant_volume);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant_volume = r.read_bool()?;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        if self.volume > 15 || self.decay > 15 {
+            return Err(StateError::Invalid("envelope volume out of range"));
+        }
+        Ok(())
+    }
+}
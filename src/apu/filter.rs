@@ -0,0 +1,57 @@
+// First order IIR filters approximating the analog output stage of the NES
+// https://wiki.nesdev.com/w/index.php/APU_Mixer
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use std::f32::consts::PI;
+
+pub struct Filter {
+    b0: f32,
+    b1: f32,
+    a1: f32,
+    prev_x: f32,
+    prev_y: f32,
+}
+
+impl Filter {
+    pub fn low_pass(sample_rate: f32, cutoff: f32) -> Self {
+        let c = sample_rate / PI / cutoff;
+        let a0i = 1.0 / (1.0 + c);
+        Filter::new(a0i, a0i, (1.0 - c) * a0i)
+    }
+
+    pub fn high_pass(sample_rate: f32, cutoff: f32) -> Self {
+        let c = sample_rate / PI / cutoff;
+        let a0i = 1.0 / (1.0 + c);
+        Filter::new(c * a0i, -c * a0i, (1.0 - c) * a0i)
+    }
+
+    fn new(b0: f32, b1: f32, a1: f32) -> Self {
+        Filter {
+            b0,
+            b1,
+            a1,
+            prev_x: 0.0,
+            prev_y: 0.0,
+        }
+    }
+
+    pub fn step(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.prev_x - self.a1 * self.prev_y;
+        self.prev_x = x;
+        self.prev_y = y;
+        y
+    }
+}
+
+impl SaveState for Filter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_f32(self.prev_x);
+        w.write_f32(self.prev_y);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.prev_x = r.read_f32()?;
+        self.prev_y = r.read_f32()?;
+        Ok(())
+    }
+}
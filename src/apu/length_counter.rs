@@ -0,0 +1,65 @@
+// Length counter shared by the pulse, triangle and noise channels
+// https://wiki.nesdev.com/w/index.php/APU_Length_Counter
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            value: 0,
+        }
+    }
+
+    // Disabling the channel through $4015 immediately silences it
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    // Clocked by the frame counter every half frame
+    pub fn step(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -1 +1,375 @@
+// Apu implements the audio processing unit of the Ricoh 2A03
+// https://wiki.nesdev.com/w/index.php/APU
 
+mod dmc;
+mod envelope;
+mod filter;
+mod length_counter;
+mod noise;
+mod pulse;
+mod triangle;
+
+use self::dmc::Dmc;
+use self::filter::Filter;
+use self::noise::Noise;
+use self::pulse::{Pulse, PulseChannel};
+use self::triangle::Triangle;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+pub const CPU_FREQUENCY: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum FrameMode {
+    FourStep,
+    FiveStep,
+}
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    // CPU cycles elapsed since power on
+    cycles: u64,
+
+    // Frame counter
+    // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    frame_mode: FrameMode,
+    frame_cycle: u32,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    // Non-linear mixer lookup tables
+    // https://wiki.nesdev.com/w/index.php/APU_Mixer#Lookup_Table
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+
+    sample_rate: u32,
+    sample_timer: f64,
+    filters: [Filter; 3],
+    // At most a second of samples, dropping the oldest when the host falls
+    // behind or never takes them
+    samples: VecDeque<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut pulse_table = [0f32; 31];
+        for (i, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / i as f32 + 100.0);
+        }
+        let mut tnd_table = [0f32; 203];
+        for (i, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / i as f32 + 100.0);
+        }
+
+        Apu {
+            pulse_1: Pulse::new(PulseChannel::One),
+            pulse_2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            cycles: 0,
+            frame_mode: FrameMode::FourStep,
+            frame_cycle: 0,
+            irq_inhibit: false,
+            frame_irq: false,
+            pulse_table,
+            tnd_table,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_timer: 0.0,
+            filters: Apu::filters(DEFAULT_SAMPLE_RATE),
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn filters(sample_rate: u32) -> [Filter; 3] {
+        let sample_rate = sample_rate as f32;
+        [
+            Filter::high_pass(sample_rate, 90.0),
+            Filter::high_pass(sample_rate, 440.0),
+            Filter::low_pass(sample_rate, 14_000.0),
+        ]
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_timer = 0.0;
+        self.filters = Apu::filters(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Returns the samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples).into()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq_flag
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse_1.length_counter.active() {
+            status |= 0b0000_0001;
+        }
+        if self.pulse_2.length_counter.active() {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length_counter.active() {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length_counter.active() {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.active() {
+            status |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_flag {
+            status |= 0b1000_0000;
+        }
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write_register(address - 0x4000, value),
+            0x4004..=0x4007 => self.pulse_2.write_register(address - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write_register(address - 0x4008, value),
+            0x400C..=0x400F => self.noise.write_register(address - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write_register(address - 0x4010, value),
+            0x4015 => {
+                self.pulse_1
+                    .length_counter
+                    .set_enabled(value & 0b0000_0001 != 0);
+                self.pulse_2
+                    .length_counter
+                    .set_enabled(value & 0b0000_0010 != 0);
+                self.triangle
+                    .length_counter
+                    .set_enabled(value & 0b0000_0100 != 0);
+                self.noise
+                    .length_counter
+                    .set_enabled(value & 0b0000_1000 != 0);
+                self.dmc.set_enabled(value & 0b0001_0000 != 0);
+            }
+            0x4017 => {
+                self.frame_mode = if value & 0x80 == 0 {
+                    FrameMode::FourStep
+                } else {
+                    FrameMode::FiveStep
+                };
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // Selecting the 5-step mode clocks the units immediately
+                if self.frame_mode == FrameMode::FiveStep {
+                    self.step_quarter_frame();
+                    self.step_half_frame();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Address the DMC memory reader needs fetched by the bus, if any
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn fill_dmc_sample_buffer(&mut self, value: u8) {
+        self.dmc.fill_sample_buffer(value);
+    }
+
+    // Advances the APU by one CPU cycle
+    pub fn step(&mut self) {
+        self.cycles += 1;
+
+        self.triangle.step_timer();
+        self.noise.step_timer();
+        self.dmc.step_timer();
+        if self.cycles.is_multiple_of(2) {
+            self.pulse_1.step_timer();
+            self.pulse_2.step_timer();
+        }
+
+        self.step_frame_counter();
+
+        self.sample_timer += self.sample_rate as f64;
+        if self.sample_timer >= CPU_FREQUENCY {
+            self.sample_timer -= CPU_FREQUENCY;
+            let sample = self.mix();
+            let sample = self.filters.iter_mut().fold(sample, |s, f| f.step(s));
+            if self.samples.len() >= self.sample_rate as usize {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+        }
+    }
+
+    fn step_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        match (self.frame_mode, self.frame_cycle) {
+            (_, 7457) | (_, 22371) => self.step_quarter_frame(),
+            (_, 14913) => {
+                self.step_quarter_frame();
+                self.step_half_frame();
+            }
+            (FrameMode::FourStep, 29828) => self.set_frame_irq(),
+            (FrameMode::FourStep, 29829) => {
+                self.step_quarter_frame();
+                self.step_half_frame();
+                self.set_frame_irq();
+            }
+            (FrameMode::FourStep, 29830) => {
+                self.set_frame_irq();
+                self.frame_cycle = 0;
+            }
+            (FrameMode::FiveStep, 37281) => {
+                self.step_quarter_frame();
+                self.step_half_frame();
+            }
+            (FrameMode::FiveStep, 37282) => self.frame_cycle = 0,
+            _ => (),
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn step_quarter_frame(&mut self) {
+        self.pulse_1.envelope.step();
+        self.pulse_2.envelope.step();
+        self.noise.envelope.step();
+        self.triangle.step_counter();
+    }
+
+    // Length counters and sweep units
+    fn step_half_frame(&mut self) {
+        self.pulse_1.length_counter.step();
+        self.pulse_2.length_counter.step();
+        self.triangle.length_counter.step();
+        self.noise.length_counter.step();
+        self.pulse_1.step_sweep();
+        self.pulse_2.step_sweep();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse_1.output() + self.pulse_2.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+}
+
+impl SaveState for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_u64(self.cycles);
+        w.write_bool(self.frame_mode == FrameMode::FiveStep);
+        w.write_u32(self.frame_cycle);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_f64(self.sample_timer);
+        for filter in &self.filters {
+            filter.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_1.load_state(r)?;
+        self.pulse_2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.cycles = r.read_u64()?;
+        self.frame_mode = if r.read_bool()? {
+            FrameMode::FiveStep
+        } else {
+            FrameMode::FourStep
+        };
+        self.frame_cycle = r.read_u32()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.sample_timer = r.read_f64()?;
+        for filter in &mut self.filters {
+            filter.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_status() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4003, 0b0000_1000); // length index 1 => 254
+        assert_eq!(apu.read_status() & 1, 1);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status() & 1, 0);
+
+        // Loading the length counter is ignored while the channel is disabled
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status() & 1, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        for _ in 0..29828 {
+            apu.step();
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert_eq!(apu.read_status() & 0x40, 0);
+
+        apu.write_register(0x4017, 0x40);
+        for _ in 0..29830 {
+            apu.step();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48_000);
+        for _ in 0..CPU_FREQUENCY as usize {
+            apu.step();
+        }
+        assert_eq!(apu.take_samples().len(), 48_000);
+        assert!(apu.take_samples().is_empty());
+
+        // Samples nobody takes don't pile up
+        for _ in 0..3 * CPU_FREQUENCY as usize {
+            apu.step();
+        }
+        assert_eq!(apu.take_samples().len(), 48_000);
+    }
+}
@@ -0,0 +1,100 @@
+// Noise implements the pseudo-random noise channel
+// https://wiki.nesdev.com/w/index.php/APU_Noise
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+// Timer periods in CPU cycles (NTSC)
+const NOISE_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub struct Noise {
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+    // Short mode feeds back bit 6 instead of bit 1
+    short_mode: bool,
+    shift_register: u16,
+    timer_period: u16,
+    timer_value: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            short_mode: false,
+            shift_register: 1,
+            timer_period: NOISE_TABLE[0],
+            timer_value: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // --LC VVVV
+            0 => {
+                self.length_counter.halt = value & 0b0010_0000 != 0;
+                self.envelope.write_control(value);
+            }
+            // M--- PPPP
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.timer_period = NOISE_TABLE[(value & 0x0F) as usize];
+            }
+            // LLLL L---
+            3 => {
+                self.length_counter.load(value >> 3);
+                self.envelope.restart();
+            }
+            _ => (),
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period - 1;
+            let shift = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register & 1) ^ ((self.shift_register >> shift) & 1);
+            self.shift_register >>= 1;
+            self.shift_register |= feedback << 14;
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl SaveState for Noise {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length_counter.save_state(w);
+        w.write_bool(self.short_mode);
+        w.write_u16(self.shift_register);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer_value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        self.short_mode = r.read_bool()?;
+        self.shift_register = r.read_u16()?;
+        self.timer_period = r.read_u16()?;
+        if self.timer_period == 0 {
+            return Err(StateError::Invalid("zero noise timer period"));
+        }
+        self.timer_value = r.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+// Pulse implements the two square wave channels
+// https://wiki.nesdev.com/w/index.php/APU_Pulse
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+pub struct Pulse {
+    channel: PulseChannel,
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+    duty_mode: u8,
+    duty_value: u8,
+    timer_period: u16,
+    timer_value: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_value: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            channel,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            duty_mode: 0,
+            duty_value: 0,
+            timer_period: 0,
+            timer_value: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_value: 0,
+            sweep_reload: false,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // DDLC VVVV
+            0 => {
+                self.duty_mode = value >> 6;
+                self.length_counter.halt = value & 0b0010_0000 != 0;
+                self.envelope.write_control(value);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length_counter.load(value >> 3);
+                self.envelope.restart();
+                self.duty_value = 0;
+            }
+            _ => (),
+        }
+    }
+
+    // Clocked every APU cycle (every other CPU cycle)
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period;
+            self.duty_value = (self.duty_value + 1) % 8;
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    // Clocked by the frame counter every half frame
+    pub fn step_sweep(&mut self) {
+        if self.sweep_value == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.target_period();
+        }
+        if self.sweep_value == 0 || self.sweep_reload {
+            self.sweep_value = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_value -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let delta = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            // Pulse 1 negates with one's complement, pulse 2 with two's complement
+            match self.channel {
+                PulseChannel::One => self.timer_period.saturating_sub(delta + 1),
+                PulseChannel::Two => self.timer_period.saturating_sub(delta),
+            }
+        } else {
+            self.timer_period + delta
+        }
+    }
+
+    // The sweep unit mutes the channel even when it is disabled
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || (!self.sweep_negate && self.target_period() > 0x7FF)
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active()
+            || DUTY_TABLE[self.duty_mode as usize][self.duty_value as usize] == 0
+            || self.muted()
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length_counter.save_state(w);
+        w.write_u8(self.duty_mode);
+        w.write_u8(self.duty_value);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer_value);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_value);
+        w.write_bool(self.sweep_reload);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.length_counter.load_state(r)?;
+        self.duty_mode = r.read_u8()?;
+        if self.duty_mode > 3 {
+            return Err(StateError::Invalid("unknown pulse duty cycle"));
+        }
+        self.duty_value = r.read_u8()?;
+        if self.duty_value > 7 {
+            return Err(StateError::Invalid("pulse duty step out of range"));
+        }
+        self.timer_period = r.read_u16()?;
+        if self.timer_period > 0x7FF {
+            return Err(StateError::Invalid("pulse timer period out of range"));
+        }
+        self.timer_value = r.read_u16()?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        if self.sweep_shift >= 8 {
+            return Err(StateError::Invalid("sweep shift out of range"));
+        }
+        self.sweep_value = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+// Triangle implements the triangle wave channel
+// https://wiki.nesdev.com/w/index.php/APU_Triangle
+
+use super::length_counter::LengthCounter;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+pub struct Triangle {
+    pub length_counter: LengthCounter,
+    control: bool,
+    counter_period: u8,
+    counter_value: u8,
+    counter_reload: bool,
+    timer_period: u16,
+    timer_value: u16,
+    sequence: u8,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            length_counter: LengthCounter::new(),
+            control: false,
+            counter_period: 0,
+            counter_value: 0,
+            counter_reload: false,
+            timer_period: 0,
+            timer_value: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            // CRRR RRRR
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length_counter.halt = self.control;
+                self.counter_period = value & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0xFF00) | value as u16,
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length_counter.load(value >> 3);
+                self.counter_reload = true;
+            }
+            _ => (),
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn step_timer(&mut self) {
+        if self.timer_value == 0 {
+            self.timer_value = self.timer_period;
+            if self.length_counter.active() && self.counter_value > 0 {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer_value -= 1;
+        }
+    }
+
+    // Linear counter, clocked by the frame counter every quarter frame
+    pub fn step_counter(&mut self) {
+        if self.counter_reload {
+            self.counter_value = self.counter_period;
+        } else if self.counter_value > 0 {
+            self.counter_value -= 1;
+        }
+        if !self.control {
+            self.counter_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        // Ultrasonic periods hold the midpoint level to avoid popping
+        if self.timer_period < 2 {
+            return 7;
+        }
+        TRIANGLE_TABLE[self.sequence as usize]
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.length_counter.save_state(w);
+        w.write_bool(self.control);
+        w.write_u8(self.counter_period);
+        w.write_u8(self.counter_value);
+        w.write_bool(self.counter_reload);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer_value);
+        w.write_u8(self.sequence);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter.load_state(r)?;
+        self.control = r.read_bool()?;
+        self.counter_period = r.read_u8()?;
+        self.counter_value = r.read_u8()?;
+        self.counter_reload = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer_value = r.read_u16()?;
+        self.sequence = r.read_u8()?;
+        if self.sequence as usize >= TRIANGLE_TABLE.len() {
+            return Err(StateError::Invalid("triangle step out of range"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+// Runs a ROM without a display and dumps what it produced, for regression
+// testing games in CI.
+//
+//   saka-headless <rom> [--movie <fm2>] [--frames <n>] [--until <addr>=<value>]
+//                 [--png <file>] [--wav <file>] [--json <file>]
+//
+// The run stops after --frames frames (600 by default), once the internal RAM
+// byte at <addr> holds <value>, or when the movie runs out of input. The JSON
+// summary goes to stdout unless --json is given. The exit status is 1 if the
+// movie desynced and 2 for any other error.
+
+use saka_nes_simulator::{crc32, Movie, Nes, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs;
+use std::process::ExitCode;
+
+const DEFAULT_FRAMES: u64 = 600;
+const SAMPLE_RATE: u32 = 44100;
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    rom: String,
+    movie: Option<String>,
+    frames: Option<u64>,
+    until: Option<(u16, u8)>,
+    png: Option<String>,
+    wav: Option<String>,
+    json: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum StopReason {
+    Frames,
+    Condition,
+    MovieEnd,
+}
+
+impl StopReason {
+    fn name(self) -> &'static str {
+        match self {
+            StopReason::Frames => "frames",
+            StopReason::Condition => "condition",
+            StopReason::MovieEnd => "movie_end",
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    pretty_env_logger::init();
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!(
+                "usage: saka-headless <rom> [--movie <fm2>] [--frames <n>] \
+                 [--until <addr>=<value>] [--png <file>] [--wav <file>] [--json <file>]"
+            );
+            return ExitCode::from(2);
+        }
+    };
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--movie" => options.movie = Some(value()?),
+            "--frames" => {
+                let frames = value()?;
+                let frames = frames
+                    .parse()
+                    .map_err(|_| format!("bad frame count: {}", frames))?;
+                options.frames = Some(frames);
+            }
+            "--until" => options.until = Some(parse_condition(&value()?)?),
+            "--png" => options.png = Some(value()?),
+            "--wav" => options.wav = Some(value()?),
+            "--json" => options.json = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    if options.rom.is_empty() {
+        return Err("no ROM given".to_string());
+    }
+    Ok(options)
+}
+
+// "<addr>=<value>" with both numbers in hex, optionally prefixed by $ or 0x
+fn parse_condition(condition: &str) -> Result<(u16, u8), String> {
+    let error = || format!("bad condition, expected <addr>=<value>: {}", condition);
+    let hex = |s: &str| {
+        let s = s.trim();
+        let s = s
+            .strip_prefix('$')
+            .or_else(|| s.strip_prefix("0x"))
+            .unwrap_or(s);
+        u16::from_str_radix(s, 16).ok()
+    };
+    let (address, value) = condition.split_once('=').ok_or_else(error)?;
+    let address = hex(address).filter(|&a| a < 0x2000).ok_or_else(error)?;
+    let value = hex(value).filter(|&v| v <= 0xFF).ok_or_else(error)?;
+    Ok((address, value as u8))
+}
+
+// Returns whether the movie, if any, played back without desyncing
+fn run(options: &Options) -> Result<bool, String> {
+    let rom = fs::read(&options.rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    let mut nes = Nes::from_rom(&rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    nes.set_sample_rate(SAMPLE_RATE);
+
+    let playing = options.movie.is_some();
+    if let Some(path) = &options.movie {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let movie = Movie::parse_fm2(&text).map_err(|e| format!("{}: {}", path, e))?;
+        nes.play_movie(movie)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    // A movie runs to its end unless told otherwise
+    let max_frames = match options.frames {
+        Some(frames) => frames,
+        None if playing => u64::MAX,
+        None => DEFAULT_FRAMES,
+    };
+    let mut samples = Vec::new();
+    let mut frames = 0;
+    let reason = loop {
+        if frames >= max_frames {
+            break StopReason::Frames;
+        }
+        if playing && nes.movie_finished() {
+            break StopReason::MovieEnd;
+        }
+        nes.run_frame();
+        frames += 1;
+        let frame_samples = nes.audio_samples();
+        if options.wav.is_some() {
+            samples.extend(frame_samples);
+        }
+        if let Some((address, value)) = options.until {
+            if nes.ram()[address as usize % 0x800] == value {
+                break StopReason::Condition;
+            }
+        }
+    };
+
+    if let Some(path) = &options.png {
+        fs::write(path, encode_png(nes.frame_buffer())).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(path) = &options.wav {
+        fs::write(path, encode_wav(&samples)).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    let desync = nes.movie_desync();
+    let summary = summary(&options.rom, &nes, frames, reason, desync);
+    match &options.json {
+        Some(path) => fs::write(path, summary).map_err(|e| format!("{}: {}", path, e))?,
+        None => print!("{}", summary),
+    }
+    Ok(desync.is_none())
+}
+
+fn summary(rom: &str, nes: &Nes, frames: u64, reason: StopReason, desync: Option<u64>) -> String {
+    let frame_buffer: Vec<u8> = nes
+        .frame_buffer()
+        .iter()
+        .flat_map(|pixel| pixel.to_le_bytes())
+        .collect();
+    let desync = match desync {
+        Some(frame) => frame.to_string(),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\n  \"rom\": {},\n  \"rom_crc32\": \"{:08x}\",\n  \"frames\": {},\n  \
+         \"frame_count\": {},\n  \"cycles\": {},\n  \"stop_reason\": \"{}\",\n  \
+         \"frame_buffer_crc32\": \"{:08x}\",\n  \"movie_desync\": {}\n}}\n",
+        json_string(rom),
+        nes.rom_crc32(),
+        frames,
+        nes.frame_count(),
+        nes.cycles(),
+        reason.name(),
+        crc32(&frame_buffer),
+        desync
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 8-bit RGB PNG. The image data is zlib compressed with stored blocks only,
+// which keeps this small and is still a valid PNG
+fn encode_png(frame_buffer: &[u32]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(SCREEN_HEIGHT * (1 + SCREEN_WIDTH * 3));
+    for row in frame_buffer.chunks(SCREEN_WIDTH) {
+        // Filter type None
+        raw.push(0);
+        for pixel in row {
+            raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(SCREEN_HEIGHT as u32).to_be_bytes());
+    // Bit depth 8, color type RGB, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary
+    let mut output = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        output.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        output.extend_from_slice(&len.to_le_bytes());
+        output.extend_from_slice(&(!len).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// 16-bit mono PCM
+fn encode_wav(samples: &[f32]) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = args("game.nes --frames 120 --until $00F0=0x3 --png out.png").unwrap();
+        assert_eq!(options.rom, "game.nes");
+        assert_eq!(options.frames, Some(120));
+        assert_eq!(options.until, Some((0x00F0, 0x03)));
+        assert_eq!(options.png.as_deref(), Some("out.png"));
+
+        assert!(args("").is_err());
+        assert!(args("game.nes --frames").is_err());
+        assert!(args("game.nes --until 2000=1").is_err());
+        assert!(args("game.nes --until 10=100").is_err());
+        assert!(args("game.nes other.nes").is_err());
+    }
+
+    #[test]
+    fn test_png() {
+        let png = encode_png(&vec![0x00FF8000; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        // Pixels are stored as RGB after each row's filter byte
+        assert_eq!(&png[41 + 7..41 + 11], [0, 0xFF, 0x80, 0x00]);
+    }
+}
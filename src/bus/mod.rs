@@ -1,4 +1,15 @@
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cdl::CodeDataLogger;
 use crate::controller::Controller;
+use crate::debugger::{disassemble, Access, AddressSpace, Debugger, Registers};
+use crate::ppu::Ppu;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use crate::trace::Tracer;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const RAM_SIZE: usize = 0x800;
 
 // SystemBus is a virtual bus that connects all the components of the system
 // 1. CPU cycle sync
@@ -11,9 +22,22 @@ pub struct SystemBus {
     // CPU cycle sync
     pub cycles: u64,
     // CPU stall cycles
-    stall_cycles: u8,
-    // pub controller_0: Controller,
-    // pub controller_1: Controller,
+    pub stall_cycles: u8,
+    // 2KB internal RAM, mirrored every 0x800 bytes up to 0x1FFF
+    ram: [u8; RAM_SIZE],
+    // Level of the PPU /NMI output and whether a falling edge is waiting for the CPU
+    nmi_line: bool,
+    nmi_edge: bool,
+    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub controller_0: Controller,
+    pub controller_1: Controller,
+    pub debugger: Option<Box<Debugger>>,
+    pub tracer: Option<Box<Tracer>>,
+    // Whether a debugger, tracer or code/data logger is attached, cached so
+    // emulation without any skips looking for them
+    instrumented: bool,
 }
 
 impl SystemBus {
@@ -21,21 +45,262 @@ impl SystemBus {
         Self {
             cycles: 0,
             stall_cycles: 0,
+            ram: [0; RAM_SIZE],
+            nmi_line: false,
+            nmi_edge: false,
+            cartridge: None,
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            controller_0: Controller::new(),
+            controller_1: Controller::new(),
+            debugger: None,
+            tracer: None,
+            instrumented: false,
         }
     }
 
+    pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
+        self.ppu.set_cartridge(cartridge.clone());
+        self.cartridge = Some(cartridge);
+    }
+
     pub fn tick(&mut self) {
         self.cycles += 1;
-        let cycles = self.cycles;
-        // TODO: sync with APU
+        self.apu.step();
+        // The DMC memory reader halts the CPU while it fetches a sample byte
+        if let Some(address) = self.apu.dmc_fetch_address() {
+            let value = self.read_as(address, CodeDataLogger::PCM);
+            self.apu.fill_dmc_sample_buffer(value);
+            self.stall_cycles = self.stall_cycles.saturating_add(4);
+        }
 
-        // SYNC with nmi
+        // 3 PPU ticks per CPU cycle
+        for _ in 0..3 {
+            self.ppu.step();
+        }
+
+        // SYNC with nmi, the CPU's NMI input is edge sensitive
+        let nmi_line = self.ppu.nmi_line();
+        if nmi_line && !self.nmi_line {
+            self.nmi_edge = true;
+        }
+        self.nmi_line = nmi_line;
+    }
 
-        // TODO: sync with PPU, 3 PPU ticks per CPU cycle
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
     }
 
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn cartridge(&self) -> Option<&Rc<RefCell<Cartridge>>> {
+        self.cartridge.as_ref()
+    }
+
+    // Returns whether an NMI edge was detected since the last poll
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_edge, false)
+    }
+
+    // The IRQ input is level sensitive and shared by the cartridge and the APU
     pub fn irq(&self) -> bool {
-        let cartridge_irq = false;
-        cartridge_irq
+        let cartridge_irq = match self.cartridge {
+            Some(ref c) => c.borrow().irq_flag(),
+            None => false,
+        };
+        cartridge_irq || self.apu.irq()
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        self.read_as(address, CodeDataLogger::DATA)
+    }
+
+    // Reads for the CPU, logging cartridge reads to the code/data logger
+    // with `cdl_flags`
+    fn read_as(&mut self, address: u16, cdl_flags: u8) -> u8 {
+        if self.debugger.is_none() {
+            return self.read_memory(address, cdl_flags);
+        }
+        let ppu_address = self.ppu.vram_address();
+        let value = self.read_memory(address, cdl_flags);
+        self.report_access(address, ppu_address, Access::Read, value);
+        value
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.debugger.is_none() {
+            return self.write_memory(address, value);
+        }
+        let ppu_address = self.ppu.vram_address();
+        self.write_memory(address, value);
+        self.report_access(address, ppu_address, Access::Write, value);
+    }
+
+    // Reads without side effects. I/O registers read as 0
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize % RAM_SIZE],
+            0x4020..=0xFFFF => match self.cartridge {
+                Some(ref c) => c.borrow().read_prg_byte(address),
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    // Offset into the PRG ROM of the byte mapped at a CPU address
+    pub fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        self.cartridge
+            .as_ref()
+            .and_then(|c| c.borrow().prg_rom_offset(address))
+    }
+
+    pub fn instrumented(&self) -> bool {
+        self.instrumented
+    }
+
+    // Must be called after attaching or detaching a debugger, tracer or
+    // code/data logger
+    pub(crate) fn update_instrumentation(&mut self) {
+        let logging = self.code_data_logging();
+        self.ppu.set_code_data_logging(logging);
+        self.instrumented = self.debugger.is_some() || self.tracer.is_some() || logging;
+    }
+
+    pub fn code_data_logging(&self) -> bool {
+        match self.cartridge {
+            Some(ref c) => c.borrow().code_data_logger().is_some(),
+            None => false,
+        }
+    }
+
+    // Logs the instruction at PC as code, called before it runs
+    pub(crate) fn log_instruction(&mut self, registers: Registers) {
+        let instruction = disassemble(self, registers.pc, &registers, None);
+        if let Some(ref c) = self.cartridge {
+            c.borrow_mut().log_instruction(&instruction);
+        }
+    }
+
+    // Tells the debugger about a CPU access, and about the PPU access it
+    // made at `ppu_address` if it went through PPUDATA
+    fn report_access(&mut self, address: u16, ppu_address: u16, access: Access, value: u8) {
+        let debugger = self.debugger.as_mut().unwrap();
+        debugger.check_access(AddressSpace::Cpu, access, address, value);
+        if (0x2000..=0x3FFF).contains(&address) && address % 8 == 7 {
+            debugger.check_access(AddressSpace::Ppu, access, ppu_address, value);
+        }
+    }
+
+    // CPU memory map
+    // https://wiki.nesdev.com/w/index.php/CPU_memory_map
+    fn read_memory(&mut self, address: u16, cdl_flags: u8) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize % RAM_SIZE],
+            // PPU registers, mirrored every 8 bytes
+            0x2000..=0x3FFF => self.ppu.read_register(address),
+            0x4015 => self.apu.read_status(),
+            // APU registers are write only
+            0x4000..=0x4014 => 0,
+            0x4016 => self.controller_0.read_register(),
+            0x4017 => self.controller_1.read_register(),
+            // APU and I/O functionality that is normally disabled
+            0x4018..=0x401F => 0,
+            0x4020..=0xFFFF => match self.cartridge {
+                Some(ref c) => {
+                    let mut cartridge = c.borrow_mut();
+                    if self.instrumented {
+                        cartridge.log_prg_access(address, cdl_flags);
+                    }
+                    let value = cartridge.read_prg_byte(address);
+                    cartridge.apply_cheats(address, value)
+                }
+                None => 0,
+            },
+        }
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize % RAM_SIZE] = value,
+            // PPU registers, mirrored every 8 bytes
+            0x2000..=0x3FFF => self.ppu.write_register(address, value),
+            0x4014 => self.oam_dma(value),
+            0x4000..=0x4015 => self.apu.write_register(address, value),
+            0x4016 => {
+                self.controller_0.write_register(value);
+                self.controller_1.write_register(value);
+            }
+            0x4017 => self.apu.write_register(address, value),
+            0x4018..=0x401F => (),
+            0x4020..=0xFFFF => {
+                if let Some(ref c) = self.cartridge {
+                    c.borrow_mut().write_prg_byte(address, value);
+                }
+            }
+        }
+    }
+
+    // Copies a 256 byte page into OAM, suspending the CPU for 513 cycles
+    // plus one more when the transfer starts on an odd CPU cycle
+    // https://wiki.nesdev.com/w/index.php/PPU_registers#OAMDMA
+    fn oam_dma(&mut self, page: u8) {
+        self.tick();
+        if self.cycles % 2 == 1 {
+            self.tick();
+        }
+        let base = (page as u16) << 8;
+        for offset in 0..0x100 {
+            self.tick();
+            let value = self.read(base + offset);
+            self.tick();
+            self.ppu.write_oam(value);
+        }
+    }
+}
+
+impl SaveState for SystemBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.cycles);
+        w.write_u8(self.stall_cycles);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.nmi_line);
+        w.write_bool(self.nmi_edge);
+        w.write_section(b"PPU ", &self.ppu);
+        w.write_section(b"APU ", &self.apu);
+        w.write_section(b"CTL0", &self.controller_0);
+        w.write_section(b"CTL1", &self.controller_1);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.cycles = r.read_u64()?;
+        self.stall_cycles = r.read_u8()?;
+        r.read_bytes(&mut self.ram)?;
+        self.nmi_line = r.read_bool()?;
+        self.nmi_edge = r.read_bool()?;
+        r.read_section(b"PPU ", &mut self.ppu)?;
+        r.read_section(b"APU ", &mut self.apu)?;
+        r.read_section(b"CTL0", &mut self.controller_0)?;
+        r.read_section(b"CTL1", &mut self.controller_1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ram_mirroring() {
+        let mut bus = SystemBus::new();
+        bus.write(0x0001, 0xAB);
+        assert_eq!(bus.read(0x0801), 0xAB);
+        assert_eq!(bus.read(0x1001), 0xAB);
+        assert_eq!(bus.read(0x1801), 0xAB);
+
+        bus.write(0x1FFF, 0xCD);
+        assert_eq!(bus.read(0x07FF), 0xCD);
     }
 }
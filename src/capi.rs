@@ -0,0 +1,437 @@
+// C API of saka-nes-simulator, exported by the cdylib build of the crate.
+//
+// An emulator is an opaque handle from saka_create, freed with saka_destroy.
+// Functions return SAKA_OK or a negative error code. Panics never cross the
+// API: they are returned as SAKA_ERROR_PANIC and unload the ROM, since the
+// machine may have been left half updated. A handle must only be used from
+// one thread at a time.
+
+// build.rs generates the C header from this file, see there for the Rust
+// it understands. include/saka.h is a copy of it, kept in sync by a test
+
+use crate::controller::Button;
+use crate::nes::Nes;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::os::raw::{c_float, c_int, c_uint};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+pub const SAKA_OK: c_int = 0;
+pub const SAKA_ERROR_NULL_POINTER: c_int = -1;
+// No ROM is loaded
+pub const SAKA_ERROR_NO_ROM: c_int = -2;
+pub const SAKA_ERROR_INVALID_ROM: c_int = -3;
+pub const SAKA_ERROR_INVALID_STATE: c_int = -4;
+pub const SAKA_ERROR_BUFFER_TOO_SMALL: c_int = -5;
+pub const SAKA_ERROR_INVALID_ARGUMENT: c_int = -6;
+pub const SAKA_ERROR_PANIC: c_int = -7;
+
+pub const SAKA_SCREEN_WIDTH: c_uint = 256;
+pub const SAKA_SCREEN_HEIGHT: c_uint = 240;
+
+// Controller buttons, or'ed together for saka_set_input
+pub const SAKA_BUTTON_A: u8 = 0x01;
+pub const SAKA_BUTTON_B: u8 = 0x02;
+pub const SAKA_BUTTON_SELECT: u8 = 0x04;
+pub const SAKA_BUTTON_START: u8 = 0x08;
+pub const SAKA_BUTTON_UP: u8 = 0x10;
+pub const SAKA_BUTTON_DOWN: u8 = 0x20;
+pub const SAKA_BUTTON_LEFT: u8 = 0x40;
+pub const SAKA_BUTTON_RIGHT: u8 = 0x80;
+
+// The header has literals, these keep them the emulator's values
+const _: () = assert!(
+    SAKA_SCREEN_WIDTH as usize == SCREEN_WIDTH && SAKA_SCREEN_HEIGHT as usize == SCREEN_HEIGHT
+);
+const _: () = assert!(
+    SAKA_BUTTON_A == Button::A as u8
+        && SAKA_BUTTON_B == Button::B as u8
+        && SAKA_BUTTON_SELECT == Button::Select as u8
+        && SAKA_BUTTON_START == Button::Start as u8
+        && SAKA_BUTTON_UP == Button::Up as u8
+        && SAKA_BUTTON_DOWN == Button::Down as u8
+        && SAKA_BUTTON_LEFT == Button::Left as u8
+        && SAKA_BUTTON_RIGHT == Button::Right as u8
+);
+
+pub struct SakaEmulator {
+    nes: Option<Nes>,
+    sample_rate: Option<u32>,
+    // Samples generated but not yet taken by saka_get_audio, at most a
+    // second of them, dropping the oldest when the host falls behind
+    audio: Vec<f32>,
+}
+
+// Runs `f` on the loaded machine, turning panics into SAKA_ERROR_PANIC
+unsafe fn with_nes(
+    emulator: *mut SakaEmulator,
+    f: impl FnOnce(&mut Nes, &mut Vec<f32>) -> c_int,
+) -> c_int {
+    let Some(emulator) = emulator.as_mut() else {
+        return SAKA_ERROR_NULL_POINTER;
+    };
+    let Some(nes) = emulator.nes.as_mut() else {
+        return SAKA_ERROR_NO_ROM;
+    };
+    let audio = &mut emulator.audio;
+    match panic::catch_unwind(AssertUnwindSafe(|| f(nes, audio))) {
+        Ok(result) => result,
+        Err(_) => {
+            emulator.nes = None;
+            emulator.audio.clear();
+            SAKA_ERROR_PANIC
+        }
+    }
+}
+
+// A slice from a C buffer, which may be NULL when empty
+unsafe fn buffer<'a, T>(data: *const T, len: usize) -> Option<&'a [T]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn saka_create() -> *mut SakaEmulator {
+    Box::into_raw(Box::new(SakaEmulator {
+        nes: None,
+        sample_rate: None,
+        audio: Vec::new(),
+    }))
+}
+
+/// # Safety
+/// `emulator` must come from saka_create and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn saka_destroy(emulator: *mut SakaEmulator) {
+    if !emulator.is_null() {
+        let emulator = Box::from_raw(emulator);
+        // A panic while dropping the machine must not unwind into C
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(emulator)));
+    }
+}
+
+// Loads an iNES or NES 2.0 image, replacing any loaded ROM. The data is
+// copied
+/// # Safety
+/// `emulator` must come from saka_create and `data` point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn saka_load_rom(
+    emulator: *mut SakaEmulator,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let (Some(emulator), Some(rom)) = (emulator.as_mut(), buffer(data, len)) else {
+        return SAKA_ERROR_NULL_POINTER;
+    };
+    emulator.nes = None;
+    emulator.audio.clear();
+    let sample_rate = emulator.sample_rate;
+    match panic::catch_unwind(|| Nes::from_rom(rom)) {
+        Ok(Ok(mut nes)) => {
+            if let Some(sample_rate) = sample_rate {
+                nes.set_sample_rate(sample_rate);
+            }
+            emulator.nes = Some(nes);
+            SAKA_OK
+        }
+        Ok(Err(_)) => SAKA_ERROR_INVALID_ROM,
+        Err(_) => SAKA_ERROR_PANIC,
+    }
+}
+
+// Kept across ROM loads
+/// # Safety
+/// `emulator` must come from saka_create.
+#[no_mangle]
+pub unsafe extern "C" fn saka_set_sample_rate(
+    emulator: *mut SakaEmulator,
+    sample_rate: c_uint,
+) -> c_int {
+    let Some(emulator) = emulator.as_mut() else {
+        return SAKA_ERROR_NULL_POINTER;
+    };
+    if sample_rate == 0 {
+        return SAKA_ERROR_INVALID_ARGUMENT;
+    }
+    emulator.sample_rate = Some(sample_rate);
+    if emulator.nes.is_none() {
+        return SAKA_OK;
+    }
+    with_nes(emulator, |nes, _| {
+        nes.set_sample_rate(sample_rate);
+        SAKA_OK
+    })
+}
+
+/// # Safety
+/// `emulator` must come from saka_create.
+#[no_mangle]
+pub unsafe extern "C" fn saka_run_frame(emulator: *mut SakaEmulator) -> c_int {
+    with_nes(emulator, |nes, audio| {
+        nes.run_frame();
+        audio.extend(nes.audio_samples());
+        let excess = audio.len().saturating_sub(nes.sample_rate() as usize);
+        audio.drain(..excess);
+        SAKA_OK
+    })
+}
+
+// port is 0 or 1
+/// # Safety
+/// `emulator` must come from saka_create.
+#[no_mangle]
+pub unsafe extern "C" fn saka_set_input(
+    emulator: *mut SakaEmulator,
+    port: c_uint,
+    buttons: u8,
+) -> c_int {
+    if port > 1 {
+        return SAKA_ERROR_INVALID_ARGUMENT;
+    }
+    with_nes(emulator, |nes, _| {
+        nes.set_buttons(port as usize, buttons);
+        SAKA_OK
+    })
+}
+
+// SAKA_SCREEN_WIDTH * SAKA_SCREEN_HEIGHT pixels in 0x00RRGGBB form, or NULL
+// without a ROM. Valid until the next call taking the emulator
+/// # Safety
+/// `emulator` must come from saka_create. The pixels are only valid until
+/// the next call taking the emulator.
+#[no_mangle]
+pub unsafe extern "C" fn saka_get_framebuffer(emulator: *mut SakaEmulator) -> *const u32 {
+    match emulator.as_ref().and_then(|e| e.nes.as_ref()) {
+        Some(nes) => nes.frame_buffer().as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+// Takes up to capacity mono samples generated by saka_run_frame, setting
+// count to how many were copied. At most a second of samples is kept
+/// # Safety
+/// `emulator` must come from saka_create and `samples` have room for
+/// `capacity` floats.
+#[no_mangle]
+pub unsafe extern "C" fn saka_get_audio(
+    emulator: *mut SakaEmulator,
+    samples: *mut c_float,
+    capacity: usize,
+    count: *mut usize,
+) -> c_int {
+    if count.is_null() || (samples.is_null() && capacity > 0) {
+        return SAKA_ERROR_NULL_POINTER;
+    }
+    with_nes(emulator, |_, audio| {
+        let n = audio.len().min(capacity);
+        if n > 0 {
+            slice::from_raw_parts_mut(samples, n).copy_from_slice(&audio[..n]);
+        }
+        audio.drain(..n);
+        *count = n;
+        SAKA_OK
+    })
+}
+
+// Sets size to the size of the state and copies it if it fits in capacity
+// bytes, otherwise returns SAKA_ERROR_BUFFER_TOO_SMALL. Pass NULL to ask for
+// the size
+/// # Safety
+/// `emulator` must come from saka_create, `data` have room for `capacity`
+/// bytes and `size` be writable. `size` is set to the state's size even when
+/// the buffer is too small, so a NULL buffer asks for the size.
+#[no_mangle]
+pub unsafe extern "C" fn saka_save_state(
+    emulator: *mut SakaEmulator,
+    data: *mut u8,
+    capacity: usize,
+    size: *mut usize,
+) -> c_int {
+    if size.is_null() {
+        return SAKA_ERROR_NULL_POINTER;
+    }
+    with_nes(emulator, |nes, _| {
+        let state = nes.save_state();
+        *size = state.len();
+        if data.is_null() || capacity < state.len() {
+            return SAKA_ERROR_BUFFER_TOO_SMALL;
+        }
+        slice::from_raw_parts_mut(data, state.len()).copy_from_slice(&state);
+        SAKA_OK
+    })
+}
+
+/// # Safety
+/// `emulator` must come from saka_create and `data` point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn saka_load_state(
+    emulator: *mut SakaEmulator,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let Some(state) = buffer(data, len) else {
+        return SAKA_ERROR_NULL_POINTER;
+    };
+    with_nes(emulator, |nes, audio| match nes.load_state(state) {
+        Ok(()) => {
+            audio.clear();
+            SAKA_OK
+        }
+        Err(_) => SAKA_ERROR_INVALID_STATE,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::nrom;
+
+    const HEADER: &str = include_str!("../include/saka.h");
+    const GENERATED: &str = include_str!(concat!(env!("OUT_DIR"), "/saka.h"));
+
+    fn build_rom() -> Vec<u8> {
+        nrom(&[0x4C, 0x00, 0x80])
+    }
+
+    #[test]
+    fn test_api() {
+        unsafe {
+            let emulator = saka_create();
+            assert_eq!(saka_run_frame(emulator), SAKA_ERROR_NO_ROM);
+            assert!(saka_get_framebuffer(emulator).is_null());
+            assert_eq!(
+                saka_load_rom(emulator, [0u8; 4].as_ptr(), 4),
+                SAKA_ERROR_INVALID_ROM
+            );
+
+            let rom = build_rom();
+            assert_eq!(saka_set_sample_rate(emulator, 48000), SAKA_OK);
+            assert_eq!(saka_load_rom(emulator, rom.as_ptr(), rom.len()), SAKA_OK);
+            assert_eq!(saka_set_input(emulator, 2, 0), SAKA_ERROR_INVALID_ARGUMENT);
+            assert_eq!(saka_set_input(emulator, 0, 0x01), SAKA_OK);
+            assert_eq!(saka_run_frame(emulator), SAKA_OK);
+            assert!(!saka_get_framebuffer(emulator).is_null());
+
+            let mut samples = vec![0.0; 4000];
+            let mut count = 0;
+            let result = saka_get_audio(emulator, samples.as_mut_ptr(), 10, &mut count);
+            assert_eq!((result, count), (SAKA_OK, 10));
+            saka_get_audio(emulator, samples.as_mut_ptr(), samples.len(), &mut count);
+            assert!(count > 700 && count < 900);
+
+            let mut size = 0;
+            let result = saka_save_state(emulator, ptr::null_mut(), 0, &mut size);
+            assert_eq!(result, SAKA_ERROR_BUFFER_TOO_SMALL);
+            let mut state = vec![0; size];
+            assert_eq!(
+                saka_save_state(emulator, state.as_mut_ptr(), size, &mut size),
+                SAKA_OK
+            );
+            assert_eq!(saka_run_frame(emulator), SAKA_OK);
+            assert_eq!(saka_load_state(emulator, state.as_ptr(), size), SAKA_OK);
+            assert_eq!(
+                saka_load_state(emulator, state.as_ptr(), 8),
+                SAKA_ERROR_INVALID_STATE
+            );
+            assert_eq!(
+                saka_load_state(emulator, ptr::null(), 8),
+                SAKA_ERROR_NULL_POINTER
+            );
+
+            saka_destroy(emulator);
+        }
+        let result = unsafe { saka_run_frame(ptr::null_mut()) };
+        assert_eq!(result, SAKA_ERROR_NULL_POINTER);
+    }
+
+    #[test]
+    fn test_panic() {
+        let emulator = saka_create();
+        unsafe {
+            let rom = build_rom();
+            saka_load_rom(emulator, rom.as_ptr(), rom.len());
+            let result = with_nes(emulator, |_, _| panic!("test panic"));
+            assert_eq!(result, SAKA_ERROR_PANIC);
+            assert_eq!(saka_run_frame(emulator), SAKA_ERROR_NO_ROM);
+            saka_destroy(emulator);
+        }
+    }
+
+    #[test]
+    fn test_audio_limit() {
+        let emulator = saka_create();
+        unsafe {
+            let rom = build_rom();
+            saka_set_sample_rate(emulator, 8000);
+            saka_load_rom(emulator, rom.as_ptr(), rom.len());
+            for _ in 0..120 {
+                saka_run_frame(emulator);
+            }
+            let mut samples = vec![0.0; 10000];
+            let mut count = 0;
+            saka_get_audio(emulator, samples.as_mut_ptr(), samples.len(), &mut count);
+            assert_eq!(count, 8000);
+            saka_destroy(emulator);
+        }
+    }
+
+    #[test]
+    fn test_header_up_to_date() {
+        assert!(
+            HEADER == GENERATED,
+            "include/saka.h is out of date, copy {}/saka.h over it",
+            env!("OUT_DIR")
+        );
+    }
+
+    // build.rs generates the header, check it covers the code
+    #[test]
+    fn test_header() {
+        let source = include_str!("capi.rs");
+        for line in source.lines() {
+            if let Some(rest) = line.split("extern \"C\" fn ").nth(1) {
+                let name = rest.split('(').next().unwrap();
+                assert!(
+                    HEADER.contains(&format!("{}(", name)),
+                    "{} isn't declared",
+                    name
+                );
+            }
+        }
+        let codes = [
+            ("SAKA_OK", SAKA_OK),
+            ("SAKA_ERROR_NULL_POINTER", SAKA_ERROR_NULL_POINTER),
+            ("SAKA_ERROR_NO_ROM", SAKA_ERROR_NO_ROM),
+            ("SAKA_ERROR_INVALID_ROM", SAKA_ERROR_INVALID_ROM),
+            ("SAKA_ERROR_INVALID_STATE", SAKA_ERROR_INVALID_STATE),
+            ("SAKA_ERROR_BUFFER_TOO_SMALL", SAKA_ERROR_BUFFER_TOO_SMALL),
+            ("SAKA_ERROR_INVALID_ARGUMENT", SAKA_ERROR_INVALID_ARGUMENT),
+            ("SAKA_ERROR_PANIC", SAKA_ERROR_PANIC),
+            ("SAKA_SCREEN_WIDTH", SAKA_SCREEN_WIDTH as c_int),
+            ("SAKA_SCREEN_HEIGHT", SAKA_SCREEN_HEIGHT as c_int),
+        ];
+        for (name, value) in codes {
+            let define = match value < 0 {
+                true => format!("#define {} ({})\n", name, value),
+                false => format!("#define {} {}\n", name, value),
+            };
+            assert!(HEADER.contains(&define), "{} doesn't match", name);
+        }
+        for (name, button) in [
+            ("A", Button::A),
+            ("START", Button::Start),
+            ("RIGHT", Button::Right),
+        ] {
+            let define = format!("#define SAKA_BUTTON_{} 0x{:02X}\n", name, button as u8);
+            assert!(
+                HEADER.contains(&define),
+                "SAKA_BUTTON_{} doesn't match",
+                name
+            );
+        }
+    }
+}
@@ -1,5 +1,15 @@
 use super::headers::Header;
 use super::pager::Pager;
+use super::CartridgeError;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const PRG_RAM_PAGE_SIZE: usize = 0x2000;
+const CHR_RAM_PAGE_SIZE: usize = 0x2000;
+const CHR_RAM_BANK_SIZE: usize = 0x400;
+const TRAINER_OFFSET: usize = 0x1000;
+// Largest pages any mapper switches, which smaller ROMs are mirrored to fill
+const PRG_ROM_PAGE_SIZE: usize = 0x4000;
+const CHR_ROM_PAGE_SIZE: usize = 0x2000;
 
 pub struct Data {
     pub header: Header,
@@ -10,14 +20,66 @@ pub struct Data {
 }
 
 impl Data {
-    pub fn new(data: &[u8]) -> Self {
-        let header = Header::new(data);
-        Data {
-            header: header,
-            prg_rom: Pager::new(data[header.prg_rom_range()].to_vec()),
-            chr_rom: Pager::new(data[header.chr_rom_range()].to_vec()),
-            prg_ram: Pager::new(vec![0u8; header.prg_ram_bytes()]),
-            chr_ram: Pager::new(vec![0u8; header.chr_ram_bytes()]),
+    pub fn new(data: &[u8]) -> Result<Self, CartridgeError> {
+        let header = Header::new(data)?;
+        if data.len() < header.file_size() {
+            return Err(CartridgeError::Truncated {
+                expected: header.file_size(),
+                actual: data.len(),
+            });
         }
+        // The trainer is loaded at $7000-$71FF
+        let mut prg_ram = vec![0u8; Data::prg_ram_size(&header)];
+        prg_ram[TRAINER_OFFSET..TRAINER_OFFSET + header.trainer_range().len()]
+            .copy_from_slice(&data[header.trainer_range()]);
+        Ok(Data {
+            header,
+            prg_rom: Pager::new(mirror(&data[header.prg_rom_range()], PRG_ROM_PAGE_SIZE)),
+            chr_rom: Pager::new(mirror(&data[header.chr_rom_range()], CHR_ROM_PAGE_SIZE)),
+            prg_ram: Pager::new(prg_ram),
+            chr_ram: Pager::new(vec![0u8; Data::chr_ram_size(&header)]),
+        })
+    }
+
+    // Mappers address PRG RAM in 8 KB pages at $6000-$7FFF, so it is
+    // never smaller than one page even when the header asks for less
+    fn prg_ram_size(header: &Header) -> usize {
+        (header.prg_ram_size + header.prg_nvram_size)
+            .max(PRG_RAM_PAGE_SIZE)
+            .next_multiple_of(PRG_RAM_PAGE_SIZE)
+    }
+
+    // Boards without CHR ROM get at least one 8 KB page of CHR RAM
+    fn chr_ram_size(header: &Header) -> usize {
+        let size = header.chr_ram_size + header.chr_nvram_size;
+        if header.chr_rom_size == 0 {
+            size.max(CHR_RAM_PAGE_SIZE)
+                .next_multiple_of(CHR_RAM_PAGE_SIZE)
+        } else {
+            size.next_multiple_of(CHR_RAM_BANK_SIZE)
+        }
+    }
+}
+
+// Repeats a ROM smaller than a page until it fills one, as the unconnected
+// address lines of e.g. an 8 KiB NROM board do
+fn mirror(rom: &[u8], page_size: usize) -> Vec<u8> {
+    if rom.is_empty() || rom.len() >= page_size {
+        rom.to_vec()
+    } else {
+        rom.iter().copied().cycle().take(page_size).collect()
+    }
+}
+
+impl SaveState for Data {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram.data);
+        w.write_bytes(&self.chr_ram.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram.data)?;
+        r.read_bytes(&mut self.chr_ram.data)?;
+        Ok(())
     }
 }
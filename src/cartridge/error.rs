@@ -0,0 +1,57 @@
+use std::fmt;
+
+// Reasons a ROM image can be rejected when building a cartridge
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartridgeError {
+    // The file does not start with "NES\x1A"
+    BadMagic,
+    // The file is shorter than its header says
+    Truncated { expected: usize, actual: usize },
+    UnsupportedMapper(u16),
+    UnsupportedNes2Feature(&'static str),
+    // The header describes sizes no board can have
+    InconsistentSizes(&'static str),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::BadMagic => write!(f, "not an iNES file"),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "file is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n),
+            CartridgeError::UnsupportedNes2Feature(feature) => {
+                write!(f, "unsupported NES 2.0 feature: {}", feature)
+            }
+            CartridgeError::InconsistentSizes(reason) => {
+                write!(f, "inconsistent header sizes: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveRamError {
+    NotBatteryBacked,
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for SaveRamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveRamError::NotBatteryBacked => write!(f, "cartridge has no battery backed RAM"),
+            SaveRamError::SizeMismatch { expected, actual } => write!(
+                f,
+                "save RAM is {} bytes but the cartridge has {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaveRamError {}
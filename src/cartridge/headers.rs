@@ -1,63 +1,347 @@
-use super::Mirroring;
+use super::{CartridgeError, Mirroring};
 use std::ops::Range;
 
 const PRG_ROM_PAGE_SIZE: usize = 0x4000;
 const PRG_RAM_PAGE_SIZE: usize = 0x2000;
 const CHR_ROM_PAGE_SIZE: usize = 0x2000;
 const CHR_RAM_PAGE_SIZE: usize = 0x2000;
+// Smallest banks any mapper switches
+const PRG_ROM_BANK_SIZE: usize = 0x2000;
+const CHR_ROM_BANK_SIZE: usize = 0x400;
 
-#[derive(Copy, Clone)]
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 0x200;
+const MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    INes,
+    Nes2,
+}
+
+// CPU/PPU timing the board was made for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu: u8, hardware: u8 },
+    Playchoice10,
+    // Extended console types, see byte 13 in the NES 2.0 spec
+    Extended(u8),
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct Header {
-    pub mapper_number: u8,
+    pub format: Format,
+    pub mapper_number: u16,
+    pub submapper: u8,
     pub mirroring: Mirroring,
-    pub prg_rom_pages: usize,
-    pub prg_ram_pages: usize,
-    pub chr_rom_pages: usize,
-    pub preamable: bool,
+    // PRG RAM (or NVRAM) is kept alive by a battery
+    pub battery: bool,
+    // A 512 byte trainer sits between the header and PRG ROM
+    pub trainer: bool,
+    // Sizes in bytes. PRG and CHR memory is split into volatile RAM and
+    // battery backed NVRAM, which iNES 1.0 doesn't distinguish
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub expansion_device: u8,
+    pub misc_rom_count: u8,
 }
 
 impl Header {
-    pub fn new(data: &[u8]) -> Self {
+    // https://wiki.nesdev.com/w/index.php/INES
+    // https://wiki.nesdev.com/w/index.php/NES_2.0
+    pub fn new(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.len() < MAGIC.len() || data[0..4] != MAGIC {
+            return Err(CartridgeError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+
+        let header = if data[7] & 0x0C == 0x08 {
+            Header::nes2(data)?
+        } else {
+            Header::ines(data)
+        };
+        // Keeps the ROM ranges from overflowing, no file is this big anyway
+        if header
+            .trainer_range()
+            .end
+            .checked_add(header.prg_rom_size)
+            .and_then(|end| end.checked_add(header.chr_rom_size))
+            .is_none()
+        {
+            return Err(CartridgeError::UnsupportedNes2Feature(
+                "ROM size beyond the address space",
+            ));
+        }
+        if header.prg_rom_size == 0 {
+            return Err(CartridgeError::InconsistentSizes("no PRG ROM"));
+        }
+        if header.prg_rom_size % PRG_ROM_BANK_SIZE != 0 {
+            return Err(CartridgeError::InconsistentSizes(
+                "PRG ROM is not a multiple of 8 KiB",
+            ));
+        }
+        if header.chr_rom_size % CHR_ROM_BANK_SIZE != 0 {
+            return Err(CartridgeError::InconsistentSizes(
+                "CHR ROM is not a multiple of 1 KiB",
+            ));
+        }
+        Ok(header)
+    }
+
+    fn ines(data: &[u8]) -> Self {
+        // Old dumping tools wrote their name over bytes 7-15, e.g.
+        // "DiskDude!", so none of those can be trusted when 12-15 aren't zero
+        let mut data = <[u8; HEADER_SIZE]>::try_from(&data[..HEADER_SIZE]).unwrap();
+        if data[12..].iter().any(|&b| b != 0) {
+            data[7..].fill(0);
+        }
+        let chr_rom_size = data[5] as usize * CHR_ROM_PAGE_SIZE;
+        // A value of 0 means 8 KB for compatibility
+        let prg_ram_size = data[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+        let battery = data[6] & 0x02 != 0;
         Header {
-            preamable: data[0..4] == [0x4E, 0x45, 0x53, 0x1A],
-            mirroring: if data[6] & 0x01 == 0 {
-                Mirroring::Horizontal
+            format: Format::INes,
+            mapper_number: ((data[6] >> 4) | (data[7] & 0xF0)) as u16,
+            submapper: 0,
+            mirroring: Header::mirroring(&data),
+            battery,
+            trainer: data[6] & 0x04 != 0,
+            prg_rom_size: data[4] as usize * PRG_ROM_PAGE_SIZE,
+            chr_rom_size,
+            prg_ram_size: if battery { 0 } else { prg_ram_size },
+            prg_nvram_size: if battery { prg_ram_size } else { 0 },
+            chr_ram_size: if chr_rom_size == 0 {
+                CHR_RAM_PAGE_SIZE
+            } else {
+                0
+            },
+            chr_nvram_size: 0,
+            timing: if data[9] & 0x01 == 0 {
+                Timing::Ntsc
             } else {
-                Mirroring::Vertical
+                Timing::Pal
+            },
+            console_type: match data[7] & 0x03 {
+                1 => ConsoleType::VsSystem {
+                    ppu: 0,
+                    hardware: 0,
+                },
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Nes,
+            },
+            expansion_device: 0,
+            misc_rom_count: 0,
+        }
+    }
+
+    fn nes2(data: &[u8]) -> Result<Self, CartridgeError> {
+        Ok(Header {
+            format: Format::Nes2,
+            mapper_number: (data[6] >> 4) as u16
+                | (data[7] & 0xF0) as u16
+                | ((data[8] & 0x0F) as u16) << 8,
+            submapper: data[8] >> 4,
+            mirroring: Header::mirroring(data),
+            battery: data[6] & 0x02 != 0,
+            trainer: data[6] & 0x04 != 0,
+            prg_rom_size: Header::rom_size(data[4], data[9] & 0x0F, PRG_ROM_PAGE_SIZE)?,
+            chr_rom_size: Header::rom_size(data[5], data[9] >> 4, CHR_ROM_PAGE_SIZE)?,
+            prg_ram_size: Header::ram_size(data[10] & 0x0F),
+            prg_nvram_size: Header::ram_size(data[10] >> 4),
+            chr_ram_size: Header::ram_size(data[11] & 0x0F),
+            chr_nvram_size: Header::ram_size(data[11] >> 4),
+            timing: match data[12] & 0x03 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            },
+            console_type: match data[7] & 0x03 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem {
+                    ppu: data[13] & 0x0F,
+                    hardware: data[13] >> 4,
+                },
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Extended(data[13] & 0x0F),
             },
-            prg_rom_pages: data[4] as usize,
-            prg_ram_pages: if data[8] == 0 { 1 } else { data[8] } as usize,
-            chr_rom_pages: data[5] as usize,
-            mapper_number: (data[6] >> 4) | (data[7] & 0xF0),
+            expansion_device: data[15] & 0x3F,
+            misc_rom_count: data[14] & 0x03,
+        })
+    }
+
+    fn mirroring(data: &[u8]) -> Mirroring {
+        if data[6] & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if data[6] & 0x01 == 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    // The MSB nibble 0xF switches the LSB to an exponent-multiplier form,
+    // EEEE EEMM meaning 2^E * (MM * 2 + 1) bytes
+    fn rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, CartridgeError> {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            1usize
+                .checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .ok_or(CartridgeError::UnsupportedNes2Feature(
+                    "ROM size beyond the address space",
+                ))
+        } else {
+            Ok((((msb as usize) << 8) | lsb as usize) * page_size)
+        }
+    }
+
+    // RAM sizes are given as a shift count, 64 << n bytes, with 0 meaning none
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.chr_rom_range().end
+    }
+
+    pub fn trainer_range(&self) -> Range<usize> {
+        if self.trainer {
+            HEADER_SIZE..HEADER_SIZE + TRAINER_SIZE
+        } else {
+            HEADER_SIZE..HEADER_SIZE
         }
     }
 
     pub fn prg_rom_range(&self) -> Range<usize> {
-        16..16 + self.prg_rom_bytes()
+        let start = self.trainer_range().end;
+        start..start + self.prg_rom_size
     }
 
     pub fn chr_rom_range(&self) -> Range<usize> {
         let prg_range = self.prg_rom_range();
-        prg_range.end..prg_range.end + self.chr_rom_bytes()
+        prg_range.end..prg_range.end + self.chr_rom_size
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    pub fn prg_rom_bytes(&self) -> usize {
-        self.prg_rom_pages * PRG_ROM_PAGE_SIZE
+    fn build_header(bytes: [u8; 12]) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..].copy_from_slice(&bytes);
+        header
     }
 
-    pub fn prg_ram_bytes(&self) -> usize {
-        self.prg_ram_pages * PRG_RAM_PAGE_SIZE
+    #[test]
+    fn test_ines() {
+        let data = build_header([2, 1, 0x41, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let header = Header::new(&data).unwrap();
+        assert_eq!(header.format, Format::INes);
+        assert_eq!(header.mapper_number, 0x44);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.prg_ram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0);
+
+        // "DiskDude!" in bytes 7-15
+        let data = build_header([
+            2, 0, 0x10, b'D', b'i', b's', b'k', b'D', b'u', b'd', b'e', b'!',
+        ]);
+        let header = Header::new(&data).unwrap();
+        assert_eq!(header.mapper_number, 1);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.prg_ram_size, 0x2000);
+        assert_eq!(header.timing, Timing::Ntsc);
     }
 
-    pub fn chr_rom_bytes(&self) -> usize {
-        self.chr_rom_pages * CHR_ROM_PAGE_SIZE
+    #[test]
+    fn test_nes2() {
+        let data = build_header([
+            0x02, 0x01, 0x40, 0x49, 0x51, 0x00, 0x70, 0x07, 0x01, 0x21, 0x02, 0x05,
+        ]);
+        let header = Header::new(&data).unwrap();
+        assert_eq!(header.format, Format::Nes2);
+        assert_eq!(header.mapper_number, 0x144);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(
+            header.console_type,
+            ConsoleType::VsSystem {
+                ppu: 1,
+                hardware: 2
+            }
+        );
+        assert_eq!(header.misc_rom_count, 2);
+        assert_eq!(header.expansion_device, 5);
     }
 
-    pub fn chr_ram_bytes(&self) -> usize {
-        if self.chr_rom_pages == 0 {
-            CHR_RAM_PAGE_SIZE
-        } else {
-            0
-        }
+    #[test]
+    fn test_nes2_exponent_sizes() {
+        // 2^15 * 3 bytes of PRG ROM, 2^13 * 1 bytes of CHR ROM
+        let data = build_header([
+            0b0011_1101,
+            0b0011_0100,
+            0x00,
+            0x08,
+            0x00,
+            0xFF,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        let header = Header::new(&data).unwrap();
+        assert_eq!(header.prg_rom_size, 0x18000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+
+        let data = build_header([0xFF, 0, 0x00, 0x08, 0x00, 0x0F, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::new(&data),
+            Err(CartridgeError::UnsupportedNes2Feature(_))
+        ));
+
+        // 2^63 bytes each fit alone, but not together
+        let data = build_header([0xFC, 0xFC, 0x00, 0x08, 0x00, 0xFF, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::new(&data),
+            Err(CartridgeError::UnsupportedNes2Feature(_))
+        ));
     }
 }
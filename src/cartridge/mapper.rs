@@ -1,8 +1,37 @@
+use super::pager::Page;
+use super::Data;
 use super::Mirroring;
+use crate::state::SaveState;
+
+// Mappers save their bank registers along with the cartridge RAM
+pub trait Mapper: SaveState {
+    fn data(&self) -> &Data;
+    fn data_mut(&mut self) -> &mut Data;
 
-pub trait Mapper {
     fn signal_scanline(&mut self) {}
 
+    // The PRG ROM page mapped at a CPU address, and the offset into it
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)>;
+
+    // Offset into the PRG ROM of the byte mapped at a CPU address
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let (page, offset) = self.prg_rom_page(address)?;
+        Some(self.data().prg_rom.index(page, offset))
+    }
+
+    // The CHR page mapped at a PPU address, and the offset into it
+    fn chr_page(&self, address: u16) -> (Page, u16);
+
+    // Offset into the CHR ROM of the byte mapped at a PPU address, None for
+    // boards with CHR RAM
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        if self.data().header.chr_rom_size == 0 {
+            return None;
+        }
+        let (page, offset) = self.chr_page(address);
+        Some(self.data().chr_rom.index(page, offset))
+    }
+
     fn read_prg_byte(&self, address: u16) -> u8;
     fn write_prg_byte(&mut self, address: u16, value: u8);
     fn read_chr_byte(&self, address: u16) -> u8;
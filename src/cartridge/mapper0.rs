@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::Data;
 use super::Mapper;
 use super::Mirroring;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper0 {
     data: Data,
@@ -13,54 +14,62 @@ pub struct Mapper0 {
 
 impl Mapper0 {
     pub fn new(data: Data) -> Self {
-        Mapper0 { data: data }
+        Mapper0 { data }
     }
 }
 
 impl Mapper for Mapper0 {
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut Data {
+        &mut self.data
+    }
+
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)> {
+        match address {
+            0x8000..=0xBFFF => Some((Page::First(PageSize::SixteenKB), address - 0x8000)),
+            0xC000..=0xFFFF => Some((Page::Last(PageSize::SixteenKB), address - 0xC000)),
+            _ => None,
+        }
+    }
+
     fn read_prg_byte(&self, address: u16) -> u8 {
         match address {
             0x6000..=0x7FFF => self
                 .data
                 .prg_ram
                 .read(Page::First(PageSize::EightKB), address - 0x6000),
-            0x8000..=0xBFFF => self
-                .data
-                .prg_rom
-                .read(Page::First(PageSize::SixteenKB), address - 0x8000),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKB), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            _ => self
+                .prg_rom_page(address)
+                .map_or(0, |(page, offset)| self.data.prg_rom.read(page, offset)),
         }
     }
 
     fn write_prg_byte(&mut self, address: u16, value: u8) {
-        match address {
-            0x6000..=0x7FFF => {
-                self.data
-                    .prg_ram
-                    .write(Page::First(PageSize::EightKB), address - 0x6000, value)
-            }
-            _ => panic!("bad address"),
+        if let 0x6000..=0x7FFF = address {
+            self.data
+                .prg_ram
+                .write(Page::First(PageSize::EightKB), address - 0x6000, value)
         }
     }
 
+    fn chr_page(&self, address: u16) -> (Page, u16) {
+        (Page::First(PageSize::EightKB), address)
+    }
+
     fn read_chr_byte(&self, address: u16) -> u8 {
-        if self.data.header.chr_rom_pages == 0 {
-            self.data
-                .chr_ram
-                .read(Page::First(PageSize::EightKB), address)
+        let (page, offset) = self.chr_page(address);
+        if self.data.header.chr_rom_size == 0 {
+            self.data.chr_ram.read(page, offset)
         } else {
-            self.data
-                .chr_rom
-                .read(Page::First(PageSize::EightKB), address)
+            self.data.chr_rom.read(page, offset)
         }
     }
 
     fn write_chr_byte(&mut self, address: u16, value: u8) {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_size == 0 {
             self.data
                 .chr_ram
                 .write(Page::First(PageSize::EightKB), address, value)
@@ -71,3 +80,14 @@ impl Mapper for Mapper0 {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper0 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.data.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(r)?;
+        Ok(())
+    }
+}
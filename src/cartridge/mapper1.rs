@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::Data;
 use super::Mapper;
 use super::Mirroring;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum AddressRange {
@@ -38,9 +39,10 @@ bitfield! {
 impl ControlRegister {
     fn mirroring(&self) -> Mirroring {
         match self.nt_mode_id() {
+            0 => Mirroring::SingleScreenLow,
+            1 => Mirroring::SingleScreenHigh,
             2 => Mirroring::Vertical,
-            3 => Mirroring::Horizontal,
-            _ => panic!("Unsupported Mirroring"), // TODO: Implement additional modes
+            _ => Mirroring::Horizontal,
         }
     }
 
@@ -154,8 +156,8 @@ impl Mapper1 {
         self.data.chr_ram.write(page, offset, value)
     }
 
-    fn read_paged_prg_rom(&self, address_range: AddressRange, offset: u16) -> u8 {
-        let page = match self.control.prg_mode() {
+    fn paged_prg_rom(&self, address_range: AddressRange) -> Page {
+        match self.control.prg_mode() {
             PrgMode::FixFirst => match address_range {
                 AddressRange::Low => Page::First(PageSize::SixteenKB),
                 AddressRange::High => Page::Number(self.prg_0, PageSize::SixteenKB),
@@ -168,12 +170,11 @@ impl Mapper1 {
                 AddressRange::Low => Page::Number(self.prg_0 & !1, PageSize::SixteenKB),
                 AddressRange::High => Page::Number(self.prg_0 | 1, PageSize::SixteenKB),
             },
-        };
-        self.data.prg_rom.read(page, offset)
+        }
     }
 
-    fn read_paged_chr_rom(&self, address_range: AddressRange, offset: u16) -> u8 {
-        let page = match self.control.chr_mode() {
+    fn paged_chr(&self, address_range: AddressRange) -> Page {
+        match self.control.chr_mode() {
             ChrMode::Consecutive => match address_range {
                 AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKB),
                 AddressRange::High => Page::Number(self.chr_0 + 1, PageSize::FourKB),
@@ -182,23 +183,33 @@ impl Mapper1 {
                 AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKB), // TODO !? Low bit??
                 AddressRange::High => Page::Number(self.chr_1, PageSize::FourKB),
             },
-        };
-
-        if self.data.header.chr_rom_pages == 0 {
-            self.data.chr_ram.read(page, offset)
-        } else {
-            self.data.chr_rom.read(page, offset)
         }
     }
 }
 
 impl Mapper for Mapper1 {
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut Data {
+        &mut self.data
+    }
+
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)> {
+        match address {
+            0x8000..=0xBFFF => Some((self.paged_prg_rom(AddressRange::Low), address - 0x8000)),
+            0xC000..=0xFFFF => Some((self.paged_prg_rom(AddressRange::High), address - 0xC000)),
+            _ => None,
+        }
+    }
+
     fn read_prg_byte(&self, address: u16) -> u8 {
         match address {
             0x6000..=0x7FFF => self.read_paged_prg_ram(address - 0x6000),
-            0x8000..=0xBFFF => self.read_paged_prg_rom(AddressRange::Low, address - 0x8000),
-            0xC000..=0xFFFF => self.read_paged_prg_rom(AddressRange::High, address - 0xC000),
-            _ => panic!("bad address"),
+            _ => self
+                .prg_rom_page(address)
+                .map_or(0, |(page, offset)| self.data.prg_rom.read(page, offset)),
         }
     }
 
@@ -206,19 +217,31 @@ impl Mapper for Mapper1 {
         match address {
             0x6000..=0x7FFF => self.write_paged_prg_ram(address - 0x6000, value),
             0x8000..=0xFFFF => self.write_shift(address, value),
-            _ => panic!("bad address"),
+            _ => (),
         }
     }
 
-    fn read_chr_byte(&self, address: u16) -> u8 {
+    fn chr_page(&self, address: u16) -> (Page, u16) {
         match address {
-            0x0000..=0x0FFF => self.read_paged_chr_rom(AddressRange::Low, address),
-            0x1000..=0x1FFF => self.read_paged_chr_rom(AddressRange::High, address - 0x1000),
+            0x0000..=0x0FFF => (self.paged_chr(AddressRange::Low), address),
+            0x1000..=0x1FFF => (self.paged_chr(AddressRange::High), address - 0x1000),
             _ => panic!("bad address"),
         }
     }
 
+    fn read_chr_byte(&self, address: u16) -> u8 {
+        let (page, offset) = self.chr_page(address);
+        if self.data.header.chr_rom_size == 0 {
+            self.data.chr_ram.read(page, offset)
+        } else {
+            self.data.chr_rom.read(page, offset)
+        }
+    }
+
     fn write_chr_byte(&mut self, address: u16, value: u8) {
+        if self.data.header.chr_rom_size != 0 {
+            return;
+        }
         match address {
             0x0000..=0x0FFF => self.write_paged_chr_ram(AddressRange::Low, address, value),
             0x1000..=0x1FFF => {
@@ -234,6 +257,29 @@ impl Mapper for Mapper1 {
     }
 }
 
+impl SaveState for Mapper1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.data.save_state(w);
+        w.write_u8(self.shift.value);
+        w.write_u8(self.shift.bit_index);
+        w.write_u8(self.control.0);
+        w.write_usize(self.prg_0);
+        w.write_usize(self.chr_0);
+        w.write_usize(self.chr_1);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(r)?;
+        self.shift.value = r.read_u8()?;
+        self.shift.bit_index = r.read_u8()?;
+        self.control.0 = r.read_u8()?;
+        self.prg_0 = r.read_usize()?;
+        self.chr_0 = r.read_usize()?;
+        self.chr_1 = r.read_usize()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -279,7 +325,7 @@ mod test {
             data.push(i as u8);
         }
 
-        Data::new(&data)
+        Data::new(&data).unwrap()
     }
 
     fn configure_mapper(mapper: &mut Mapper1, address: u16, value: u8) {
@@ -303,6 +349,19 @@ mod test {
         assert_eq!(mapper.control.mirroring(), Mirroring::Vertical);
         assert_eq!(mapper.control.prg_mode(), PrgMode::Consecutive);
         assert_eq!(mapper.control.chr_mode(), ChrMode::NonConsecutive);
+
+        configure_mapper(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.control.mirroring(), Mirroring::SingleScreenLow);
+        configure_mapper(&mut mapper, 0x8000, 0b00001);
+        assert_eq!(mapper.control.mirroring(), Mirroring::SingleScreenHigh);
+    }
+
+    #[test]
+    fn test_ignore_chr_rom_writes() {
+        let mut mapper = Mapper1::new(build_cartridge_data());
+        let value = mapper.read_chr_byte(0x1234);
+        mapper.write_chr_byte(0x1234, !value);
+        assert_eq!(mapper.read_chr_byte(0x1234), value);
     }
 
     #[test]
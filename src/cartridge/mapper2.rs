@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::Data;
 use super::Mapper;
 use super::Mirroring;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper2 {
     data: Data,
@@ -14,51 +15,57 @@ pub struct Mapper2 {
 
 impl Mapper2 {
     pub fn new(data: Data) -> Self {
-        Mapper2 {
-            data: data,
-            prg_0: 0,
-        }
+        Mapper2 { data, prg_0: 0 }
     }
 }
 
 impl Mapper for Mapper2 {
-    fn read_prg_byte(&self, address: u16) -> u8 {
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut Data {
+        &mut self.data
+    }
+
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)> {
         match address {
-            0x8000..=0xBFFF => self.data.prg_rom.read(
+            0x8000..=0xBFFF => Some((
                 Page::Number(self.prg_0, PageSize::SixteenKB),
                 address - 0x8000,
-            ),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKB), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            )),
+            0xC000..=0xFFFF => Some((Page::Last(PageSize::SixteenKB), address - 0xC000)),
+            _ => None,
         }
     }
 
+    fn read_prg_byte(&self, address: u16) -> u8 {
+        self.prg_rom_page(address)
+            .map_or(0, |(page, offset)| self.data.prg_rom.read(page, offset))
+    }
+
     fn write_prg_byte(&mut self, address: u16, value: u8) {
-        match address {
-            0x8000..=0xFFFF => {
-                self.prg_0 = value as usize & 0x0F;
-            }
-            _ => panic!("bad address"),
+        if let 0x8000..=0xFFFF = address {
+            let count = self.data.prg_rom.page_count(PageSize::SixteenKB);
+            self.prg_0 = (value as usize & 0x0F) % count;
         }
     }
 
+    fn chr_page(&self, address: u16) -> (Page, u16) {
+        (Page::First(PageSize::EightKB), address)
+    }
+
     fn read_chr_byte(&self, address: u16) -> u8 {
-        if self.data.header.chr_rom_pages == 0 {
-            self.data
-                .chr_ram
-                .read(Page::First(PageSize::EightKB), address)
+        let (page, offset) = self.chr_page(address);
+        if self.data.header.chr_rom_size == 0 {
+            self.data.chr_ram.read(page, offset)
         } else {
-            self.data
-                .chr_rom
-                .read(Page::First(PageSize::EightKB), address)
+            self.data.chr_rom.read(page, offset)
         }
     }
 
     fn write_chr_byte(&mut self, address: u16, value: u8) {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_size == 0 {
             self.data
                 .chr_ram
                 .write(Page::First(PageSize::EightKB), address, value)
@@ -69,3 +76,19 @@ impl Mapper for Mapper2 {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.data.save_state(w);
+        w.write_usize(self.prg_0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(r)?;
+        self.prg_0 = r.read_usize()?;
+        if self.prg_0 >= self.data.prg_rom.page_count(PageSize::SixteenKB) {
+            return Err(StateError::Invalid("PRG bank beyond the ROM"));
+        }
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::Data;
 use super::Mapper;
 use super::Mirroring;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper3 {
     data: Data,
@@ -14,41 +15,45 @@ pub struct Mapper3 {
 
 impl Mapper3 {
     pub fn new(data: Data) -> Self {
-        Mapper3 {
-            data: data,
-            chr_0: 0,
-        }
+        Mapper3 { data, chr_0: 0 }
     }
 }
 
 impl Mapper for Mapper3 {
-    fn read_prg_byte(&self, address: u16) -> u8 {
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut Data {
+        &mut self.data
+    }
+
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)> {
         match address {
-            0x8000..=0xBFFF => self
-                .data
-                .prg_rom
-                .read(Page::First(PageSize::SixteenKB), address - 0x8000),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKB), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            0x8000..=0xBFFF => Some((Page::First(PageSize::SixteenKB), address - 0x8000)),
+            0xC000..=0xFFFF => Some((Page::Last(PageSize::SixteenKB), address - 0xC000)),
+            _ => None,
         }
     }
 
+    fn read_prg_byte(&self, address: u16) -> u8 {
+        self.prg_rom_page(address)
+            .map_or(0, |(page, offset)| self.data.prg_rom.read(page, offset))
+    }
+
     fn write_prg_byte(&mut self, address: u16, value: u8) {
-        match address {
-            0x8000..=0xFFFF => {
-                self.chr_0 = value as usize;
-            }
-            _ => (),
+        if let 0x8000..=0xFFFF = address {
+            self.chr_0 = value as usize % self.data.chr_rom.page_count(PageSize::EightKB);
         }
     }
 
+    fn chr_page(&self, address: u16) -> (Page, u16) {
+        (Page::Number(self.chr_0, PageSize::EightKB), address)
+    }
+
     fn read_chr_byte(&self, address: u16) -> u8 {
-        self.data
-            .chr_rom
-            .read(Page::Number(self.chr_0, PageSize::EightKB), address)
+        let (page, offset) = self.chr_page(address);
+        self.data.chr_rom.read(page, offset)
     }
 
     fn write_chr_byte(&mut self, _: u16, _: u8) {}
@@ -57,3 +62,19 @@ impl Mapper for Mapper3 {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.data.save_state(w);
+        w.write_usize(self.chr_0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(r)?;
+        self.chr_0 = r.read_usize()?;
+        if self.chr_0 >= self.data.chr_rom.page_count(PageSize::EightKB) {
+            return Err(StateError::Invalid("CHR bank beyond the ROM"));
+        }
+        Ok(())
+    }
+}
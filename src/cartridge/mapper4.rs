@@ -3,9 +3,11 @@
 
 use super::pager::Page;
 use super::pager::PageSize;
+use super::pager::Pager;
 use super::Data;
 use super::Mapper;
 use super::Mirroring;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper4 {
     data: Data,
@@ -24,7 +26,7 @@ pub struct Mapper4 {
 impl Mapper4 {
     pub fn new(data: Data) -> Self {
         Mapper4 {
-            data: data,
+            data,
             registers: [0; 8],
             index: 0,
             prg_mode: false,
@@ -37,40 +39,56 @@ impl Mapper4 {
             irq_flag: false,
         }
     }
+
+    fn chr(&self) -> &Pager {
+        if self.data.header.chr_rom_size == 0 {
+            &self.data.chr_ram
+        } else {
+            &self.data.chr_rom
+        }
+    }
+
+    // Banks a register selects from, R0-R5 switch CHR and R6-R7 PRG
+    fn bank_count(&self, register: usize) -> usize {
+        if register < 6 {
+            self.chr().page_count(PageSize::OneKB)
+        } else {
+            self.data.prg_rom.page_count(PageSize::EightKB)
+        }
+    }
 }
 
 impl Mapper for Mapper4 {
+    fn data(&self) -> &Data {
+        &self.data
+    }
+
+    fn data_mut(&mut self) -> &mut Data {
+        &mut self.data
+    }
+
+    fn prg_rom_page(&self, address: u16) -> Option<(Page, u16)> {
+        let page = match (address, self.prg_mode) {
+            (0x8000..=0x9FFF, false) => Page::Number(self.registers[6], PageSize::EightKB),
+            (0x8000..=0x9FFF, true) => Page::FromEnd(1, PageSize::EightKB),
+            (0xA000..=0xBFFF, _) => Page::Number(self.registers[7], PageSize::EightKB),
+            (0xC000..=0xDFFF, false) => Page::FromEnd(1, PageSize::EightKB),
+            (0xC000..=0xDFFF, true) => Page::Number(self.registers[6], PageSize::EightKB),
+            (0xE000..=0xFFFF, _) => Page::FromEnd(0, PageSize::EightKB),
+            _ => return None,
+        };
+        Some((page, address & 0x1FFF))
+    }
+
     fn read_prg_byte(&self, address: u16) -> u8 {
-        match (address, self.prg_mode) {
-            (0x6000..=0x7FFF, _) => self
+        match address {
+            0x6000..=0x7FFF => self
                 .data
                 .prg_ram
                 .read(Page::First(PageSize::EightKB), address - 0x6000),
-            (0x8000..=0x9FFF, false) => self.data.prg_rom.read(
-                Page::Number(self.registers[6], PageSize::EightKB),
-                address - 0x8000,
-            ),
-            (0x8000..=0x9FFF, true) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(1, PageSize::EightKB), address - 0x8000),
-            (0xA000..=0xBFFF, _) => self.data.prg_rom.read(
-                Page::Number(self.registers[7], PageSize::EightKB),
-                address - 0xA000,
-            ),
-            (0xC000..=0xDFFF, false) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(1, PageSize::EightKB), address - 0xC000),
-            (0xC000..=0xDFFF, true) => self.data.prg_rom.read(
-                Page::Number(self.registers[6], PageSize::EightKB),
-                address - 0xC000,
-            ),
-            (0xE000..=0xFFFF, _) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(0, PageSize::EightKB), address - 0xE000),
-            (a, _m) => panic!("bad address: {:04X}", a),
+            _ => self
+                .prg_rom_page(address)
+                .map_or(0, |(page, offset)| self.data.prg_rom.read(page, offset)),
         }
     }
 
@@ -87,7 +105,7 @@ impl Mapper for Mapper4 {
                 self.chr_mode = value & 0b1000_0000 != 0;
             }
             (0x8000..=0x9FFF, 1) => {
-                self.registers[self.index] = value as usize;
+                self.registers[self.index] = value as usize % self.bank_count(self.index);
             }
             (0xA000..=0xBFFF, 0) => {
                 self.mirroring = if value & 1 == 0 {
@@ -115,7 +133,7 @@ impl Mapper for Mapper4 {
     // $1400-$17FF 	R3 	R0 OR 1
     // $1800-$1BFF 	R4 	R1 AND $FE
     // $1C00-$1FFF 	R5 	R1 OR 1
-    fn read_chr_byte(&self, address: u16) -> u8 {
+    fn chr_page(&self, address: u16) -> (Page, u16) {
         let bank = match (address, self.chr_mode) {
             (0x0000..=0x03FF, false) => self.registers[0] & !1,
             (0x0000..=0x03FF, true) => self.registers[2],
@@ -136,13 +154,20 @@ impl Mapper for Mapper4 {
             (0x1C00..=0x1FFF, true) => self.registers[1] | 1,
             _ => panic!(),
         };
+        (Page::Number(bank, PageSize::OneKB), address % 0x0400)
+    }
 
-        self.data
-            .chr_rom
-            .read(Page::Number(bank, PageSize::OneKB), address % 0x0400)
+    fn read_chr_byte(&self, address: u16) -> u8 {
+        let (page, offset) = self.chr_page(address);
+        self.chr().read(page, offset)
     }
 
-    fn write_chr_byte(&mut self, _: u16, _: u8) {}
+    fn write_chr_byte(&mut self, address: u16, value: u8) {
+        if self.data.header.chr_rom_size == 0 {
+            let (page, offset) = self.chr_page(address);
+            self.data.chr_ram.write(page, offset, value)
+        }
+    }
 
     fn mirroring(&self) -> Mirroring {
         self.mirroring
@@ -162,3 +187,51 @@ impl Mapper for Mapper4 {
         }
     }
 }
+
+impl SaveState for Mapper4 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.data.save_state(w);
+        for register in self.registers {
+            w.write_usize(register);
+        }
+        w.write_usize(self.index);
+        w.write_bool(self.prg_mode);
+        w.write_bool(self.chr_mode);
+        w.write_u8(match self.mirroring {
+            Mirroring::Vertical => 0,
+            _ => 1,
+        });
+        w.write_u8(self.irq_counter);
+        w.write_u8(self.irq_period);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_reset);
+        w.write_bool(self.irq_flag);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(r)?;
+        for i in 0..self.registers.len() {
+            self.registers[i] = r.read_usize()?;
+            if self.registers[i] >= self.bank_count(i) {
+                return Err(StateError::Invalid("bank beyond the ROM"));
+            }
+        }
+        self.index = r.read_usize()?;
+        if self.index >= self.registers.len() {
+            return Err(StateError::Invalid("bank register out of range"));
+        }
+        self.prg_mode = r.read_bool()?;
+        self.chr_mode = r.read_bool()?;
+        self.mirroring = match r.read_u8()? {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            _ => return Err(StateError::Invalid("unknown mirroring")),
+        };
+        self.irq_counter = r.read_u8()?;
+        self.irq_period = r.read_u8()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_reset = r.read_bool()?;
+        self.irq_flag = r.read_bool()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 mod data;
+mod error;
 mod headers;
 mod mapper;
 mod mapper0;
@@ -8,35 +9,117 @@ mod mapper3;
 mod mapper4;
 mod pager;
 
+pub use self::error::{CartridgeError, SaveRamError};
+pub use self::headers::{ConsoleType, Format, Header, Timing};
 use self::{
     data::Data, mapper::Mapper, mapper0::Mapper0, mapper1::Mapper1, mapper2::Mapper2,
     mapper3::Mapper3, mapper4::Mapper4,
 };
+use crate::cdl::CodeDataLogger;
+use crate::cheat::Cheat;
+use crate::debugger::{AddressingMode, Instruction};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
     None,
-    //TODO: 4 screen
+    // The cartridge provides 2 KB of extra VRAM for four unique nametables
+    FourScreen,
+    // Every nametable shows the first or second 1 KB of VRAM
+    SingleScreenLow,
+    SingleScreenHigh,
 }
 
 pub struct Cartridge {
+    header: Header,
     mapper: Box<dyn Mapper>,
+    // Set by writes to battery backed RAM since the host last saved it
+    save_ram_dirty: bool,
+    cdl: Option<Box<CodeDataLogger>>,
+    // Enabled substitution cheats
+    cheats: Vec<Cheat>,
 }
 
 impl Cartridge {
-    pub fn new(data: &[u8]) -> Self {
-        let data = Data::new(data);
+    pub fn new(data: &[u8]) -> Result<Self, CartridgeError> {
+        let data = Data::new(data)?;
+        let header = data.header;
+        Cartridge::check_console(&header)?;
+        Cartridge::check_board(&header)?;
         let mapper: Box<dyn Mapper> = match data.header.mapper_number {
             0 => Box::new(Mapper0::new(data)),
             1 => Box::new(Mapper1::new(data)),
             2 => Box::new(Mapper2::new(data)),
             3 => Box::new(Mapper3::new(data)),
             4 => Box::new(Mapper4::new(data)),
-            n => panic!("Mapper {} not implemented yet", n),
+            n => return Err(CartridgeError::UnsupportedMapper(n)),
         };
-        Cartridge { mapper: mapper }
+        Ok(Cartridge {
+            header,
+            mapper,
+            save_ram_dirty: false,
+            cdl: None,
+            cheats: Vec::new(),
+        })
+    }
+
+    // Rejects NES 2.0 images made for anything but an NTSC NES with standard
+    // controllers. iNES 1.0 headers are too often wrong about these to trust
+    fn check_console(header: &Header) -> Result<(), CartridgeError> {
+        if header.format != Format::Nes2 {
+            return Ok(());
+        }
+        let feature = match (header.timing, header.console_type) {
+            (Timing::Pal, _) => "PAL timing",
+            (Timing::Dendy, _) => "Dendy timing",
+            (_, ConsoleType::VsSystem { .. }) => "Vs. System console",
+            (_, ConsoleType::Playchoice10) => "PlayChoice-10 console",
+            (_, ConsoleType::Extended(_)) => "extended console type",
+            // 0 is unspecified, 1 standard controllers
+            _ if header.expansion_device > 1 => "expansion device",
+            _ => return Ok(()),
+        };
+        Err(CartridgeError::UnsupportedNes2Feature(feature))
+    }
+
+    // Rejects ROM sizes no board of the mapper has. Sizes within the limits
+    // can still be smaller than what the mapper addresses, in which case
+    // bank numbers wrap around
+    fn check_board(header: &Header) -> Result<(), CartridgeError> {
+        const KB: usize = 0x400;
+        let (prg, chr) = (header.prg_rom_size, header.chr_rom_size);
+        let reason = match header.mapper_number {
+            0 | 3 if prg > 32 * KB => "NROM and CNROM have at most 32 KiB of PRG ROM",
+            0 | 2 if chr > 8 * KB => "NROM and UxROM have at most 8 KiB of CHR ROM",
+            3 if chr == 0 => "CNROM has no CHR RAM",
+            1 if prg > 512 * KB => "MMC1 has at most 512 KiB of PRG ROM",
+            1 if chr > 128 * KB => "MMC1 has at most 128 KiB of CHR ROM",
+            4 if prg > 512 * KB => "MMC3 has at most 512 KiB of PRG ROM",
+            4 if chr > 256 * KB => "MMC3 has at most 256 KiB of CHR ROM",
+            _ => {
+                // ROMs smaller than a bank are mirrored to fill it, larger
+                // ones must split into whole banks
+                let (prg_bank, chr_bank) = match header.mapper_number {
+                    1 => (16 * KB, 4 * KB),
+                    4 => (8 * KB, KB),
+                    _ => (16 * KB, 8 * KB),
+                };
+                if prg > prg_bank && !prg.is_multiple_of(prg_bank) {
+                    "PRG ROM is not a multiple of the mapper's banks"
+                } else if chr > chr_bank && !chr.is_multiple_of(chr_bank) {
+                    "CHR ROM is not a multiple of the mapper's banks"
+                } else {
+                    return Ok(());
+                }
+            }
+        };
+        Err(CartridgeError::InconsistentSizes(reason))
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
     }
 
     pub fn signal_scanline(&mut self) {
@@ -47,7 +130,31 @@ impl Cartridge {
         self.mapper.read_prg_byte(address)
     }
 
+    // What the CPU sees instead of `value` read at `address`. Only CPU reads
+    // go through the cheats, everything else sees the ROM as it is
+    pub(crate) fn apply_cheats(&self, address: u16, value: u8) -> u8 {
+        if self.cheats.is_empty() {
+            return value;
+        }
+        self.cheats
+            .iter()
+            .find_map(|cheat| cheat.substitute(address, value))
+            .unwrap_or(value)
+    }
+
+    pub(crate) fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        self.cheats = cheats;
+    }
+
+    // Offset into the PRG ROM of the byte currently mapped at a CPU address
+    pub fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        self.mapper.prg_rom_offset(address)
+    }
+
     pub fn write_prg_byte(&mut self, address: u16, value: u8) {
+        if self.header.battery && (0x6000..=0x7FFF).contains(&address) {
+            self.save_ram_dirty = true;
+        }
         self.mapper.write_prg_byte(address, value);
     }
 
@@ -59,11 +166,446 @@ impl Cartridge {
         self.mapper.write_chr_byte(address, value)
     }
 
+    // Four-screen boards ignore the mapper's own mirroring control
     pub fn mirroring(&self) -> Mirroring {
-        self.mapper.mirroring()
+        if self.header.mirroring == Mirroring::FourScreen {
+            Mirroring::FourScreen
+        } else {
+            self.mapper.mirroring()
+        }
+    }
+
+    pub fn battery_backed(&self) -> bool {
+        self.header.battery
+    }
+
+    // Contents of the battery backed PRG RAM, None for carts without one
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        if self.header.battery {
+            Some(&self.mapper.data().prg_ram.data)
+        } else {
+            None
+        }
+    }
+
+    pub fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.header.battery {
+            Some(&mut self.mapper.data_mut().prg_ram.data)
+        } else {
+            None
+        }
+    }
+
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), SaveRamError> {
+        if !self.header.battery {
+            return Err(SaveRamError::NotBatteryBacked);
+        }
+        let prg_ram = &mut self.mapper.data_mut().prg_ram.data;
+        if prg_ram.len() != data.len() {
+            return Err(SaveRamError::SizeMismatch {
+                expected: prg_ram.len(),
+                actual: data.len(),
+            });
+        }
+        prg_ram.copy_from_slice(data);
+        self.save_ram_dirty = false;
+        Ok(())
+    }
+
+    pub fn save_ram_dirty(&self) -> bool {
+        self.save_ram_dirty
+    }
+
+    pub fn set_save_ram_dirty(&mut self, dirty: bool) {
+        self.save_ram_dirty = dirty;
     }
 
     pub fn irq_flag(&self) -> bool {
         self.mapper.irq_flag()
     }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.mapper.data().prg_rom.data
+    }
+
+    pub fn prg_rom_mut(&mut self) -> &mut [u8] {
+        &mut self.mapper.data_mut().prg_rom.data
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.mapper.data().prg_ram.data
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.mapper.data_mut().prg_ram.data
+    }
+
+    // CHR ROM, or CHR RAM for boards without ROM
+    pub fn chr(&self) -> &[u8] {
+        let data = self.mapper.data();
+        match self.header.chr_rom_size {
+            0 => &data.chr_ram.data,
+            _ => &data.chr_rom.data,
+        }
+    }
+
+    pub fn chr_mut(&mut self) -> &mut [u8] {
+        let data = self.mapper.data_mut();
+        match self.header.chr_rom_size {
+            0 => &mut data.chr_ram.data,
+            _ => &mut data.chr_rom.data,
+        }
+    }
+
+    // Offset into chr() of the byte mapped at a PPU address
+    pub fn chr_offset(&self, address: u16) -> usize {
+        let (page, offset) = self.mapper.chr_page(address);
+        let data = self.mapper.data();
+        match self.header.chr_rom_size {
+            0 => data.chr_ram.index(page, offset),
+            _ => data.chr_rom.index(page, offset),
+        }
+    }
+
+    // Starts logging ROM accesses, keeping the log if one is running
+    pub fn enable_code_data_logger(&mut self) {
+        if self.cdl.is_none() {
+            let data = self.mapper.data();
+            let chr_rom_size = match self.header.chr_rom_size {
+                0 => 0,
+                _ => data.chr_rom.data.len(),
+            };
+            let cdl = CodeDataLogger::new(data.prg_rom.data.len(), chr_rom_size);
+            self.cdl = Some(Box::new(cdl));
+        }
+    }
+
+    pub fn take_code_data_logger(&mut self) -> Option<Box<CodeDataLogger>> {
+        self.cdl.take()
+    }
+
+    pub fn set_code_data_logger(&mut self, cdl: Option<Box<CodeDataLogger>>) {
+        self.cdl = cdl;
+    }
+
+    pub fn code_data_logger(&self) -> Option<&CodeDataLogger> {
+        self.cdl.as_deref()
+    }
+
+    pub fn code_data_logger_mut(&mut self) -> Option<&mut CodeDataLogger> {
+        self.cdl.as_deref_mut()
+    }
+
+    // Logs the bytes of an instruction about to run as code, along with
+    // the target of an indirect JMP
+    pub(crate) fn log_instruction(&mut self, instruction: &Instruction) {
+        let Some(cdl) = &mut self.cdl else {
+            return;
+        };
+        let length = instruction.bytes.len() as u16;
+        for address in (0..length).map(|i| instruction.address.wrapping_add(i)) {
+            if let Some(offset) = self.mapper.prg_rom_offset(address) {
+                cdl.log_prg(address, offset, CodeDataLogger::CODE);
+            }
+        }
+        let mut indirect_address = None;
+        match (instruction.mode, instruction.effective_address) {
+            (AddressingMode::Indirect, Some(target)) => {
+                if let Some(offset) = self.mapper.prg_rom_offset(target) {
+                    cdl.log_prg(target, offset, CodeDataLogger::INDIRECT_CODE);
+                }
+            }
+            (AddressingMode::IndirectX | AddressingMode::IndirectY, address) => {
+                indirect_address = address;
+            }
+            _ => (),
+        }
+        cdl.begin_instruction(instruction.address, length, indirect_address);
+    }
+
+    pub(crate) fn log_prg_access(&mut self, address: u16, flags: u8) {
+        if let Some(cdl) = &mut self.cdl {
+            if let Some(offset) = self.mapper.prg_rom_offset(address) {
+                cdl.log_prg(address, offset, flags);
+            }
+        }
+    }
+
+    pub(crate) fn log_chr_access(&mut self, address: u16, flags: u8) {
+        if let Some(cdl) = &mut self.cdl {
+            if let Some(offset) = self.mapper.chr_rom_offset(address) {
+                cdl.log_chr(offset, flags);
+            }
+        }
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.mapper.save_state(w);
+    }
+
+    // The loaded state replaces the save RAM, so it needs writing out again
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.load_state(r)?;
+        self.save_ram_dirty = self.header.battery;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ines;
+
+    #[test]
+    fn test_load_errors() {
+        assert!(Cartridge::new(&ines(0, 2, 1)).is_ok());
+        assert_eq!(Cartridge::new(b"NES").err(), Some(CartridgeError::BadMagic));
+        assert_eq!(
+            Cartridge::new(b"NES\x1a\x01").err(),
+            Some(CartridgeError::Truncated {
+                expected: 16,
+                actual: 5
+            })
+        );
+
+        let mut rom = ines(0, 2, 1);
+        rom.truncate(0x6000);
+        assert_eq!(
+            Cartridge::new(&rom).err(),
+            Some(CartridgeError::Truncated {
+                expected: 0xA010,
+                actual: 0x6000
+            })
+        );
+
+        assert_eq!(
+            Cartridge::new(&ines(9, 2, 1)).err(),
+            Some(CartridgeError::UnsupportedMapper(9))
+        );
+        assert_eq!(
+            Cartridge::new(&ines(0, 0, 1)).err(),
+            Some(CartridgeError::InconsistentSizes("no PRG ROM"))
+        );
+
+        let mut rom = ines(0, 2, 1);
+        rom[6] |= 0x04;
+        assert_eq!(
+            Cartridge::new(&rom).err(),
+            Some(CartridgeError::Truncated {
+                expected: 0xA210,
+                actual: 0xA010
+            })
+        );
+
+        assert_eq!(
+            Cartridge::new(&ines(3, 2, 0)).err(),
+            Some(CartridgeError::InconsistentSizes("CNROM has no CHR RAM"))
+        );
+        assert_eq!(
+            Cartridge::new(&ines(0, 4, 1)).err(),
+            Some(CartridgeError::InconsistentSizes(
+                "NROM and CNROM have at most 32 KiB of PRG ROM"
+            ))
+        );
+
+        // 2^13 * 3 bytes of PRG ROM
+        let mut rom = ines(2, 2, 0);
+        rom[4] = 0b0011_0101;
+        rom[7] |= 0x08;
+        rom[9] = 0x0F;
+        assert_eq!(
+            Cartridge::new(&rom).err(),
+            Some(CartridgeError::InconsistentSizes(
+                "PRG ROM is not a multiple of the mapper's banks"
+            ))
+        );
+
+        let mut rom = ines(0, 2, 1);
+        rom[7] |= 0x08;
+        rom[8] = 0x01;
+        assert_eq!(
+            Cartridge::new(&rom).err(),
+            Some(CartridgeError::UnsupportedMapper(0x100))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_consoles() {
+        let nes2 = || {
+            let mut rom = ines(0, 2, 1);
+            rom[7] |= 0x08;
+            rom
+        };
+        let unsupported = |rom: Vec<u8>| match Cartridge::new(&rom) {
+            Err(CartridgeError::UnsupportedNes2Feature(feature)) => feature,
+            other => panic!("expected an unsupported feature, got {:?}", other.err()),
+        };
+
+        // Multi-region boards run as NTSC, standard controllers are fine
+        let mut rom = nes2();
+        rom[12] = 2;
+        rom[15] = 1;
+        assert!(Cartridge::new(&rom).is_ok());
+
+        let mut rom = nes2();
+        rom[12] = 1;
+        assert_eq!(unsupported(rom), "PAL timing");
+        let mut rom = nes2();
+        rom[12] = 3;
+        assert_eq!(unsupported(rom), "Dendy timing");
+
+        let mut rom = nes2();
+        rom[7] |= 0x01;
+        assert_eq!(unsupported(rom), "Vs. System console");
+        let mut rom = nes2();
+        rom[7] |= 0x02;
+        assert_eq!(unsupported(rom), "PlayChoice-10 console");
+        let mut rom = nes2();
+        rom[7] |= 0x03;
+        assert_eq!(unsupported(rom), "extended console type");
+
+        // Zapper
+        let mut rom = nes2();
+        rom[15] = 8;
+        assert_eq!(unsupported(rom), "expansion device");
+
+        // iNES 1.0 flags for the same aren't trusted
+        let mut rom = ines(0, 2, 1);
+        rom[7] |= 0x01;
+        rom[9] = 0x01;
+        assert!(Cartridge::new(&rom).is_ok());
+    }
+
+    // Bank numbers beyond the ROM wrap around instead of panicking
+    #[test]
+    fn test_bank_wrapping() {
+        let mut rom = ines(3, 2, 2);
+        rom[16 + 0x8000 + 0x2000] = 0x42;
+        let mut cartridge = Cartridge::new(&rom).unwrap();
+        cartridge.write_prg_byte(0x8000, 0xFF);
+        assert_eq!(cartridge.read_chr_byte(0x0000), 0x42);
+
+        let mut rom = ines(2, 2, 1);
+        rom[16 + 0x4000] = 0x43;
+        let mut cartridge = Cartridge::new(&rom).unwrap();
+        cartridge.write_prg_byte(0x8000, 5);
+        assert_eq!(cartridge.read_prg_byte(0x8000), 0x43);
+
+        let mut rom = ines(4, 2, 1);
+        rom[16 + 0x6000] = 0x44;
+        let mut cartridge = Cartridge::new(&rom).unwrap();
+        cartridge.write_prg_byte(0x8000, 6);
+        cartridge.write_prg_byte(0x8001, 0x3F);
+        assert_eq!(cartridge.read_prg_byte(0x8000), 0x44);
+
+        // MMC3 boards without CHR ROM have CHR RAM
+        let mut cartridge = Cartridge::new(&ines(4, 2, 0)).unwrap();
+        cartridge.write_prg_byte(0x8000, 2);
+        cartridge.write_prg_byte(0x8001, 0x7F);
+        cartridge.write_prg_byte(0x8000, 5);
+        cartridge.write_prg_byte(0x8001, 7);
+        cartridge.write_chr_byte(0x1000, 0x45);
+        assert_eq!(cartridge.read_chr_byte(0x1C00), 0x45);
+    }
+
+    // NES 2.0 sizes can be smaller than the mapper's pages
+    #[test]
+    fn test_reject_banks() {
+        let mut cartridge = Cartridge::new(&ines(3, 2, 2)).unwrap();
+        cartridge.write_prg_byte(0x8000, 0xFF);
+        let mut w = StateWriter::new();
+        cartridge.save_state(&mut w);
+        let mut state = w.into_inner();
+        cartridge.load_state(&mut StateReader::new(&state)).unwrap();
+
+        // The last field is the CHR bank
+        let end = state.len();
+        state[end - 8] = 2;
+        assert_eq!(
+            cartridge.load_state(&mut StateReader::new(&state)),
+            Err(StateError::Invalid("CHR bank beyond the ROM"))
+        );
+    }
+
+    #[test]
+    fn test_small_roms() {
+        // 2^13 bytes of PRG ROM, 2^10 bytes of CHR ROM
+        let mut rom = ines(0, 0, 0);
+        rom[4] = 0b0011_0100;
+        rom[5] = 0b0010_1000;
+        rom[7] |= 0x08;
+        rom[9] = 0xFF;
+        rom.extend(vec![0; 0x2400]);
+        rom[16 + 0x1FFC] = 0x42;
+        rom[16 + 0x2000 + 0x3FF] = 0x43;
+        let cartridge = Cartridge::new(&rom).unwrap();
+        assert_eq!(cartridge.read_prg_byte(0x9FFC), 0x42);
+        assert_eq!(cartridge.read_prg_byte(0xFFFC), 0x42);
+        assert_eq!(cartridge.read_chr_byte(0x03FF), 0x43);
+        assert_eq!(cartridge.read_chr_byte(0x1FFF), 0x43);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut rom = ines(0, 1, 1);
+        rom[6] |= 0x04;
+        let mut trainer = vec![0xAB; 0x200];
+        trainer[0x1FF] = 0xCD;
+        rom.splice(16..16, trainer);
+        rom[16 + 0x200] = 0xEF;
+
+        let cartridge = Cartridge::new(&rom).unwrap();
+        assert_eq!(cartridge.read_prg_byte(0x6FFF), 0x00);
+        assert_eq!(cartridge.read_prg_byte(0x7000), 0xAB);
+        assert_eq!(cartridge.read_prg_byte(0x71FF), 0xCD);
+        assert_eq!(cartridge.read_prg_byte(0x8000), 0xEF);
+    }
+
+    #[test]
+    fn test_header_flags() {
+        let mut rom = ines(4, 2, 1);
+        rom[6] |= 0x0A;
+        let cartridge = Cartridge::new(&rom).unwrap();
+        assert!(cartridge.battery_backed());
+        assert_eq!(cartridge.mirroring(), Mirroring::FourScreen);
+        assert_eq!(cartridge.header().prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.header().prg_ram_size, 0);
+    }
+
+    #[test]
+    fn test_save_ram() {
+        let mut cartridge = Cartridge::new(&ines(0, 1, 1)).unwrap();
+        assert_eq!(cartridge.save_ram(), None);
+        assert_eq!(
+            cartridge.load_save_ram(&[0; 0x2000]),
+            Err(SaveRamError::NotBatteryBacked)
+        );
+        cartridge.write_prg_byte(0x6000, 1);
+        assert!(!cartridge.save_ram_dirty());
+
+        let mut rom = ines(0, 1, 1);
+        rom[6] |= 0x02;
+        let mut cartridge = Cartridge::new(&rom).unwrap();
+        cartridge.write_prg_byte(0x8000, 1);
+        assert!(!cartridge.save_ram_dirty());
+        cartridge.write_prg_byte(0x6001, 0x42);
+        assert!(cartridge.save_ram_dirty());
+        assert_eq!(cartridge.save_ram().unwrap()[1], 0x42);
+        cartridge.set_save_ram_dirty(false);
+        assert!(!cartridge.save_ram_dirty());
+
+        assert_eq!(
+            cartridge.load_save_ram(&[0; 0x100]),
+            Err(SaveRamError::SizeMismatch {
+                expected: 0x2000,
+                actual: 0x100
+            })
+        );
+        let mut save = vec![0; 0x2000];
+        save[0x1FFF] = 0x99;
+        cartridge.load_save_ram(&save).unwrap();
+        assert_eq!(cartridge.read_prg_byte(0x7FFF), 0x99);
+    }
 }
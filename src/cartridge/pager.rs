@@ -30,34 +30,53 @@ impl Pager {
         let i = self.index(page, offset);
         self.data[i] = value;
     }
-    fn page_count(&self, size: PageSize) -> usize {
-        if self.data.len() % (size as usize) != 0 {
+    pub fn page_count(&self, size: PageSize) -> usize {
+        if !self.data.len().is_multiple_of(size as usize) {
             panic!("Page size must divide evenly into data length")
         }
+        if self.data.is_empty() {
+            panic!("No pages in empty data")
+        }
 
         self.data.len() / (size as usize)
     }
-    fn index(&self, page: Page, offset: u16) -> usize {
-        match page {
-            Page::First(size) => self.index(Page::Number(0, size), offset),
-            Page::Last(size) => {
-                let last_page = self.page_count(size) - 1;
-                self.index(Page::Number(last_page, size), offset)
-            }
-            Page::Number(n, size) => {
-                let last_page = self.page_count(size) - 1;
-                if (offset as usize) > (size as usize) {
-                    panic!("Offset cannot exceed page bounds")
-                }
-                if n > last_page {
-                    panic!("Page out of bounds")
-                }
-                n * (size as usize) + (offset as usize)
-            }
-            Page::FromEnd(n, size) => {
-                let last_page = self.page_count(size) - 1;
-                self.index(Page::Number(last_page - n, size), offset)
-            }
+    // Bank numbers wrap around the pages there are, like the unconnected
+    // high bank lines of a board with less ROM than its mapper can address
+    pub fn index(&self, page: Page, offset: u16) -> usize {
+        if (offset as usize) >= (size_of(page) as usize) {
+            panic!("Offset cannot exceed page bounds")
+        }
+        let count = self.page_count(size_of(page));
+        let n = match page {
+            Page::First(_) => 0,
+            Page::Last(_) => count - 1,
+            Page::Number(n, _) => n % count,
+            Page::FromEnd(n, _) => count - 1 - n % count,
+        };
+        n * (size_of(page) as usize) + (offset as usize)
+    }
+}
+
+fn size_of(page: Page) -> PageSize {
+    match page {
+        Page::First(size) | Page::Last(size) | Page::Number(_, size) | Page::FromEnd(_, size) => {
+            size
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrapping() {
+        let pager = Pager::new(vec![0; 3 * PageSize::EightKB as usize]);
+        let page = |n| pager.index(Page::Number(n, PageSize::EightKB), 1);
+        assert_eq!(page(1), 0x2001);
+        assert_eq!(page(4), 0x2001);
+        assert_eq!(page(0xFF), 0x0001);
+        assert_eq!(pager.index(Page::Last(PageSize::EightKB), 2), 0x4002);
+        assert_eq!(pager.index(Page::FromEnd(4, PageSize::EightKB), 0), 0x2000);
+    }
+}
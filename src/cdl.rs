@@ -0,0 +1,231 @@
+// Code/Data Logger, recording how every byte of PRG and CHR ROM was used
+// http://fceux.com/web/help/CodeDataLogger.html
+//
+// Bytes are tagged by their offset in the ROM image rather than by CPU
+// address, so code in switched banks is told apart. The .cdl file is the PRG
+// ROM flags followed by the CHR ROM flags, one byte per ROM byte:
+//
+// PRG  xPdcAADC  C code, D data, AA the 8KB window of $8000-$FFFF it was last
+//                mapped at, c indirect code, d indirect data, P PCM audio
+// CHR  xxxxxxRD  D rendered, R read through PPUDATA
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CdlError {
+    // The file doesn't match the PRG and CHR ROM sizes of the cartridge
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CdlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CdlError::SizeMismatch { expected, actual } => write!(
+                f,
+                "CDL file is {} bytes but the ROM needs {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CdlError {}
+
+#[derive(Debug, Clone)]
+pub struct CodeDataLogger {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    // Address and length of the instruction being executed. Its operands
+    // are fetched with ordinary reads, which aren't data accesses
+    instruction: (u16, u16),
+    // Where the instruction being executed reads through a pointer
+    indirect_address: Option<u16>,
+}
+
+impl CodeDataLogger {
+    pub const CODE: u8 = 0x01;
+    pub const DATA: u8 = 0x02;
+    pub const INDIRECT_CODE: u8 = 0x10;
+    pub const INDIRECT_DATA: u8 = 0x20;
+    pub const PCM: u8 = 0x40;
+    pub const CHR_RENDERED: u8 = 0x01;
+    pub const CHR_READ: u8 = 0x02;
+
+    const BANK_MASK: u8 = 0x0C;
+
+    pub fn new(prg_rom_size: usize, chr_rom_size: usize) -> Self {
+        CodeDataLogger {
+            prg_rom: vec![0; prg_rom_size],
+            chr_rom: vec![0; chr_rom_size],
+            instruction: (0, 0),
+            indirect_address: None,
+        }
+    }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+
+    pub fn clear(&mut self) {
+        self.prg_rom.fill(0);
+        self.chr_rom.fill(0);
+    }
+
+    // Contents of a .cdl file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg_rom[..], &self.chr_rom[..]].concat()
+    }
+
+    // Replaces the log with the contents of a .cdl file
+    pub fn load(&mut self, data: &[u8]) -> Result<(), CdlError> {
+        let expected = self.prg_rom.len() + self.chr_rom.len();
+        if data.len() != expected {
+            return Err(CdlError::SizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let (prg_rom, chr_rom) = data.split_at(self.prg_rom.len());
+        self.prg_rom.copy_from_slice(prg_rom);
+        self.chr_rom.copy_from_slice(chr_rom);
+        Ok(())
+    }
+
+    // Starts a new instruction at `pc`, `length` bytes long. Reads at
+    // `indirect_address` while it runs are logged as indirect data
+    pub(crate) fn begin_instruction(
+        &mut self,
+        pc: u16,
+        length: u16,
+        indirect_address: Option<u16>,
+    ) {
+        self.instruction = (pc, length);
+        self.indirect_address = indirect_address;
+    }
+
+    // Logs an access to the PRG ROM byte at `offset`, mapped at `address`
+    pub(crate) fn log_prg(&mut self, address: u16, offset: usize, mut flags: u8) {
+        let (pc, length) = self.instruction;
+        if flags == Self::DATA {
+            if address.wrapping_sub(pc) < length {
+                return;
+            }
+            if self.indirect_address == Some(address) {
+                flags |= Self::INDIRECT_DATA;
+            }
+        }
+        let byte = &mut self.prg_rom[offset];
+        if flags & (Self::CODE | Self::DATA) != 0 {
+            let bank = ((address >> 13) & 3) as u8;
+            *byte = (*byte & !Self::BANK_MASK) | (bank << 2);
+        }
+        *byte |= flags;
+    }
+
+    pub(crate) fn log_chr(&mut self, offset: usize, flags: u8) {
+        self.chr_rom[offset] |= flags;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    #[test]
+    fn test_log_prg() {
+        let mut cdl = CodeDataLogger::new(0x8000, 0x2000);
+        cdl.begin_instruction(0xC000, 3, None);
+        cdl.log_prg(0xC000, 0x4000, CodeDataLogger::CODE);
+        // Operand fetches aren't data reads
+        cdl.log_prg(0xC001, 0x4001, CodeDataLogger::DATA);
+        assert_eq!(cdl.prg_rom()[0x4000], 0x09);
+        assert_eq!(cdl.prg_rom()[0x4001], 0x00);
+
+        cdl.begin_instruction(0xC003, 2, Some(0x8010));
+        cdl.log_prg(0x8010, 0x0010, CodeDataLogger::DATA);
+        cdl.log_prg(0xE020, 0x4020, CodeDataLogger::DATA);
+        cdl.log_prg(0xE020, 0x4020, CodeDataLogger::PCM);
+        assert_eq!(cdl.prg_rom()[0x0010], 0x22);
+        assert_eq!(cdl.prg_rom()[0x4020], 0x4E);
+    }
+
+    #[test]
+    fn test_file() {
+        let mut cdl = CodeDataLogger::new(4, 2);
+        cdl.log_prg(0x8001, 1, CodeDataLogger::CODE);
+        cdl.log_chr(1, CodeDataLogger::CHR_RENDERED);
+        let bytes = cdl.to_bytes();
+        assert_eq!(bytes, [0, 1, 0, 0, 0, 1]);
+
+        let mut loaded = CodeDataLogger::new(4, 2);
+        loaded.load(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(
+            loaded.load(&bytes[1..]),
+            Err(CdlError::SizeMismatch {
+                expected: 6,
+                actual: 5
+            })
+        );
+        loaded.clear();
+        assert_eq!(loaded.to_bytes(), [0; 6]);
+    }
+
+    #[test]
+    fn test_code_data_logger() {
+        let mut program = vec![
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x06, 0x20, // STA $2006
+            0x8D, 0x06, 0x20, // STA $2006
+            0xAD, 0x07, 0x20, // LDA $2007
+            0xAD, 0x07, 0x20, // LDA $2007
+            0xAD, 0x00, 0x81, // LDA $8100
+            0xA9, 0x00, // LDA #$00
+            0x85, 0x00, // STA $00
+            0xA9, 0x82, // LDA #$82
+            0x85, 0x01, // STA $01
+            0xA0, 0x05, // LDY #$05
+            0xB1, 0x00, // LDA ($00),Y
+            0x6C, 0x00, 0x83, // JMP ($8300)
+        ];
+        program.resize(0x40, 0xEA);
+        program.extend_from_slice(&[0x4C, 0x40, 0x80]); // JMP $8040
+        program.resize(0x300, 0xEA);
+        program.extend_from_slice(&[0x40, 0x80]);
+        let mut nes = Nes::from_rom(&nrom(&program)).unwrap();
+        nes.enable_code_data_logger();
+        assert!(nes.cpu.bus.instrumented());
+        nes.run_frame();
+
+        let cdl = nes.code_data_logger().unwrap();
+        let prg = cdl.prg_rom();
+        assert_eq!(prg[0x00], CodeDataLogger::CODE);
+        assert_eq!(prg[0x0F], CodeDataLogger::CODE);
+        assert_eq!(prg[0x100], CodeDataLogger::DATA);
+        let indirect_data = CodeDataLogger::DATA | CodeDataLogger::INDIRECT_DATA;
+        assert_eq!(prg[0x205], indirect_data);
+        assert_eq!(prg[0x300], CodeDataLogger::DATA);
+        assert_eq!(
+            prg[0x40],
+            CodeDataLogger::CODE | CodeDataLogger::INDIRECT_CODE
+        );
+        assert_eq!(prg[0x20], 0);
+        assert_eq!(cdl.chr_rom()[..3], [2, 2, 0]);
+        assert_eq!(cdl.to_bytes().len(), 0x6000);
+        drop(cdl);
+
+        nes.power_cycle();
+        assert_eq!(nes.code_data_logger().unwrap().prg_rom()[0x100], 2);
+        nes.code_data_logger_mut().unwrap().clear();
+        let cdl = nes.disable_code_data_logger().unwrap();
+        assert!(!nes.cpu.bus.instrumented());
+        assert!(cdl.prg_rom().iter().all(|&flags| flags == 0));
+        assert!(nes.code_data_logger().is_none());
+    }
+}
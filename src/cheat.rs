@@ -0,0 +1,323 @@
+// Cheats: Game Genie codes, raw codes and FCEUX .cht cheat lists
+// https://wiki.nesdev.com/w/index.php/Game_Genie
+//
+// A cheat either substitutes the value the CPU reads from the cartridge,
+// like a Game Genie does, optionally only when the byte there equals a
+// compare value, or freezes a byte of RAM by writing it before every frame,
+// like a Pro Action Replay does.
+//
+// Raw codes are written AAAA:VV, or AAAA?CC:VV with a compare value. Raw
+// codes for ROM addresses substitute, others freeze. .cht lines are
+//
+//   [:][S][C]AAAA:VV[:CC]:Name
+//
+// where ':' marks a disabled cheat, 'S' a substitution and 'C' a compare
+// value. Hex numbers are lower case so an address never reads as a flag.
+
+use std::fmt;
+use std::fmt::Write;
+
+// Game Genie letters, in the order of the nibbles they encode
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheatError {
+    InvalidCode(String),
+    Parse { line: usize, reason: &'static str },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::InvalidCode(code) => write!(f, "invalid cheat code: {}", code),
+            CheatError::Parse { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CheatKind {
+    // Replaces reads from the cartridge
+    Substitute,
+    // Writes the value to RAM before every frame
+    Freeze,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub name: String,
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+    pub kind: CheatKind,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    // Decodes a 6 or 8 letter Game Genie code or a raw code
+    pub fn from_code(code: &str) -> Result<Cheat, CheatError> {
+        let invalid = || CheatError::InvalidCode(code.to_string());
+        let code = code.trim();
+        let (address, value, compare) = match code.split_once(':') {
+            Some((address, value)) => {
+                let (address, compare) = match address.split_once('?') {
+                    Some((address, compare)) => (address, Some(compare)),
+                    None => (address, None),
+                };
+                let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+                let value = u8::from_str_radix(value, 16).map_err(|_| invalid())?;
+                let compare = compare
+                    .map(|c| u8::from_str_radix(c, 16))
+                    .transpose()
+                    .map_err(|_| invalid())?;
+                (address, value, compare)
+            }
+            None => decode_game_genie(code).ok_or_else(invalid)?,
+        };
+        let kind = if address >= 0x8000 || compare.is_some() {
+            CheatKind::Substitute
+        } else {
+            CheatKind::Freeze
+        };
+        Ok(Cheat {
+            name: String::new(),
+            address,
+            value,
+            compare,
+            kind,
+            enabled: true,
+        })
+    }
+
+    // The Game Genie code for a substitution in ROM, 8 letters when there
+    // is a compare value
+    pub fn game_genie_code(&self) -> Option<String> {
+        if self.kind != CheatKind::Substitute || self.address < 0x8000 {
+            return None;
+        }
+        let (address, value) = (self.address, self.value);
+        let mut nibbles = vec![
+            (value & 7) | ((value >> 4) & 8),
+            ((value >> 4) & 7) | ((address >> 4) & 8) as u8,
+            ((address >> 4) & 7) as u8,
+            ((address >> 12) & 7) as u8 | (address & 8) as u8,
+            (address & 7) as u8 | ((address >> 8) & 8) as u8,
+            ((address >> 8) & 7) as u8 | (value & 8),
+        ];
+        if let Some(compare) = self.compare {
+            nibbles[2] |= 8;
+            nibbles[5] = (nibbles[5] & 7) | (compare & 8);
+            nibbles.push((compare & 7) | ((compare >> 4) & 8));
+            nibbles.push(((compare >> 4) & 7) | (value & 8));
+        }
+        let letters = nibbles.iter().map(|&n| GAME_GENIE_LETTERS[n as usize]);
+        Some(letters.map(char::from).collect())
+    }
+
+    // The value the CPU reads at `address` where the cartridge has `value`
+    pub(crate) fn substitute(&self, address: u16, value: u8) -> Option<u8> {
+        let matches = self.address == address && self.compare.is_none_or(|c| c == value);
+        matches.then_some(self.value)
+    }
+}
+
+fn decode_game_genie(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let n = code
+        .bytes()
+        .map(|c| {
+            let c = c.to_ascii_uppercase();
+            GAME_GENIE_LETTERS
+                .iter()
+                .position(|&l| l == c)
+                .map(|n| n as u16)
+        })
+        .collect::<Option<Vec<u16>>>()?;
+    if n.len() != 6 && n.len() != 8 {
+        return None;
+    }
+    let address = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8)
+        | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4)
+        | ((n[1] & 8) << 4)
+        | (n[4] & 7)
+        | (n[3] & 8);
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+    if n.len() == 6 {
+        return Some((address, (value | (n[5] & 8)) as u8, None));
+    }
+    let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+    Some((address, (value | (n[7] & 8)) as u8, Some(compare as u8)))
+}
+
+pub fn parse_cht(text: &str) -> Result<Vec<Cheat>, CheatError> {
+    let mut cheats = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let error = |reason| CheatError::Parse {
+            line: i + 1,
+            reason,
+        };
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (enabled, line) = match line.strip_prefix(':') {
+            Some(line) => (false, line),
+            None => (true, line),
+        };
+        let (kind, line) = match line.strip_prefix('S') {
+            Some(line) => (CheatKind::Substitute, line),
+            None => (CheatKind::Freeze, line),
+        };
+        let (has_compare, line) = match line.strip_prefix('C') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let fields = if has_compare { 4 } else { 3 };
+        let parts: Vec<&str> = line.splitn(fields, ':').collect();
+        if parts.len() != fields {
+            return Err(error("missing field"));
+        }
+        let address = u16::from_str_radix(parts[0], 16).map_err(|_| error("invalid address"))?;
+        let value = u8::from_str_radix(parts[1], 16).map_err(|_| error("invalid value"))?;
+        let compare = match has_compare {
+            true => Some(u8::from_str_radix(parts[2], 16).map_err(|_| error("invalid compare"))?),
+            false => None,
+        };
+        cheats.push(Cheat {
+            name: parts[fields - 1].to_string(),
+            address,
+            value,
+            compare,
+            kind,
+            enabled,
+        });
+    }
+    Ok(cheats)
+}
+
+pub fn to_cht(cheats: &[Cheat]) -> String {
+    let mut text = String::new();
+    for cheat in cheats {
+        if !cheat.enabled {
+            text.push(':');
+        }
+        if cheat.kind == CheatKind::Substitute {
+            text.push('S');
+        }
+        match cheat.compare {
+            Some(compare) => write!(
+                text,
+                "C{:04x}:{:02x}:{:02x}",
+                cheat.address, cheat.value, compare
+            ),
+            None => write!(text, "{:04x}:{:02x}", cheat.address, cheat.value),
+        }
+        .unwrap();
+        writeln!(text, ":{}", cheat.name).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemorySpace;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    #[test]
+    fn test_game_genie() {
+        // Super Mario Bros. infinite lives
+        let cheat = Cheat::from_code("SXIOPO").unwrap();
+        assert_eq!(
+            (cheat.address, cheat.value, cheat.compare),
+            (0x91D9, 0xAD, None)
+        );
+        assert_eq!(cheat.kind, CheatKind::Substitute);
+        assert_eq!(cheat.game_genie_code().as_deref(), Some("SXIOPO"));
+
+        let cheat = Cheat {
+            compare: Some(0x5A),
+            ..cheat
+        };
+        let code = cheat.game_genie_code().unwrap();
+        assert_eq!(code.len(), 8);
+        let decoded = Cheat::from_code(&code.to_lowercase()).unwrap();
+        assert_eq!((decoded.address, decoded.value), (0x91D9, 0xAD));
+        assert_eq!(decoded.compare, Some(0x5A));
+
+        assert!(Cheat::from_code("SXIOP").is_err());
+        assert!(Cheat::from_code("SXIOPB").is_err());
+    }
+
+    #[test]
+    fn test_raw_codes() {
+        let cheat = Cheat::from_code("075A:09").unwrap();
+        assert_eq!((cheat.address, cheat.value), (0x075A, 0x09));
+        assert_eq!(cheat.kind, CheatKind::Freeze);
+        assert_eq!(cheat.game_genie_code(), None);
+
+        let cheat = Cheat::from_code("C000?A9:EA").unwrap();
+        assert_eq!(cheat.compare, Some(0xA9));
+        assert_eq!(cheat.kind, CheatKind::Substitute);
+        assert_eq!(cheat.substitute(0xC000, 0xA9), Some(0xEA));
+        assert_eq!(cheat.substitute(0xC000, 0xA8), None);
+        assert!(Cheat::from_code("C000:1FF").is_err());
+    }
+
+    #[test]
+    fn test_cht() {
+        let text = "075a:09:Lives\n:SC91d9:ad:de:Skip\nS8000:ea:Name: with colon\n";
+        let cheats = parse_cht(text).unwrap();
+        assert_eq!(cheats.len(), 3);
+        assert_eq!(cheats[0].kind, CheatKind::Freeze);
+        assert!(!cheats[1].enabled);
+        assert_eq!(cheats[1].compare, Some(0xDE));
+        assert_eq!(cheats[2].name, "Name: with colon");
+        assert_eq!(to_cht(&cheats), text);
+        let cheats = parse_cht("c000:ea:Nop").unwrap();
+        assert_eq!((cheats[0].address, cheats[0].compare), (0xC000, None));
+        assert_eq!(
+            parse_cht("075a").unwrap_err(),
+            CheatError::Parse {
+                line: 1,
+                reason: "missing field"
+            }
+        );
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut program = vec![
+            0xAD, 0x10, 0x80, // LDA $8010
+            0x85, 0x00, // STA $00
+            0x4C, 0x05, 0x80, // JMP $8005
+        ];
+        program.resize(0x10, 0xEA);
+        program.push(0x42);
+        let mut nes = Nes::from_rom(&nrom(&program)).unwrap();
+        nes.add_cheat(Cheat::from_code("8010?41:55").unwrap());
+        nes.add_cheat(Cheat::from_code("0001:77").unwrap());
+        nes.run_frame();
+        assert_eq!(nes.ram()[..2], [0x42, 0x77]);
+
+        nes.add_cheat(Cheat::from_code("8010:99").unwrap());
+        nes.set_cheat_enabled(1, false);
+        nes.memory().poke(MemorySpace::InternalRam, 1, 0);
+        nes.power_cycle();
+        nes.run_frame();
+        assert_eq!(nes.ram()[..2], [0x99, 0x00]);
+        assert_eq!(nes.cheats().len(), 3);
+        // Side effect free reads see the ROM as it is
+        assert_eq!(nes.cpu.bus.peek(0x8010), 0x42);
+
+        nes.clear_cheats();
+        nes.power_cycle();
+        nes.run_frame();
+        assert_eq!(nes.ram()[0], 0x42);
+    }
+}
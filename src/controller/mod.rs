@@ -1,4 +1,7 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
 #[derive(Debug, Copy, Clone, PartialEq)]
+
 pub enum Button {
     A = 0b0000_0001,
     B = 0b0000_0010,
@@ -51,3 +54,18 @@ impl Controller {
         }
     }
 }
+
+impl SaveState for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons);
+        w.write_bool(self.strobe);
+        w.write_u8(self.index);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        self.index = r.read_u8()?;
+        Ok(())
+    }
+}
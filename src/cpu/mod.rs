@@ -1,9 +1,11 @@
-use log::debug;
-
 use self::utils::high_byte;
 use super::bus::SystemBus;
+use crate::debugger::{Interrupt, Registers};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+#[cfg(feature = "debug")]
+use crate::trace::{format_line, TraceFormat};
 
-mod debug;
+pub(crate) mod debug;
 mod utils;
 
 // 7  bit  0
@@ -41,9 +43,9 @@ enum Mode {
     Indirect,
     IndirectX,
     IndirectY,
-    NoMode,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum InterruptType {
     NMI,
@@ -52,6 +54,7 @@ enum InterruptType {
     BRK,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub bus: SystemBus,
     pc: u16,
@@ -60,6 +63,18 @@ pub struct CPU {
     x: u8,
     y: u8,
     p: u8,
+    // Interrupt polling state. The CPU polls its interrupt inputs at the end
+    // of every cycle but acts on what it saw during the second to last cycle
+    // of an instruction, which is why the previous values are kept around
+    // https://wiki.nesdev.com/w/index.php/CPU_interrupts
+    nmi_pending: bool,
+    prev_nmi_pending: bool,
+    run_irq: bool,
+    prev_run_irq: bool,
+    // Set by the JAM opcodes. The CPU stops fetching instructions and
+    // ignores interrupts until it's reset, while the rest of the system
+    // keeps running
+    jammed: bool,
 }
 
 impl CPU {
@@ -72,27 +87,96 @@ impl CPU {
             x: 0,
             y: 0,
             p: 0,
+            nmi_pending: false,
+            prev_nmi_pending: false,
+            run_irq: false,
+            prev_run_irq: false,
+            jammed: false,
         }
     }
 
+    // Power on state. The reset sequence decrements SP by 3, leaving it at 0xFD
     pub fn reset_registers(&mut self) {
-        self.sp = 0xFF;
-        self.p = 0x34;
-        // TODO: set reset interrupt
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0x00;
+        self.p = FlagBit::IrqDisable as u8;
+        self.reset();
+    }
+
+    // Warm reset, only SP and the I flag are affected
+    pub fn reset(&mut self) {
+        self.nmi_pending = false;
+        self.prev_nmi_pending = false;
+        self.run_irq = false;
+        self.prev_run_irq = false;
+        self.jammed = false;
+        self.interrupt(InterruptType::RESET);
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            p: self.p | FlagBit::Push as u8,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.bus.tick();
+        self.poll_interrupts();
+    }
+
+    fn poll_interrupts(&mut self) {
+        self.prev_nmi_pending = self.nmi_pending;
+        if self.bus.poll_nmi() {
+            self.nmi_pending = true;
+        }
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = self.bus.irq() && !self.check_flag(FlagBit::IrqDisable);
+    }
+
+    // Every memory access takes one CPU cycle
+    pub fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.bus.read(address)
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.bus.write(address, value);
+    }
+
+    fn read_2bytes(&mut self, address: u16) -> u16 {
+        let low = self.read_byte(address) as u16;
+        let high = self.read_byte(address.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    // Reads a little-endian word. The high byte wraps within the same page
+    // to emulate the 6502 JMP ($xxFF) and zero page pointer behaviour
+    fn read_2bytes_wrapped(&mut self, address: u16) -> u16 {
+        let high_address = utils::high_byte(address) | utils::low_byte(address.wrapping_add(1));
+        let low = self.read_byte(address) as u16;
+        let high = self.read_byte(high_address) as u16;
+        (high << 8) | low
     }
 
     pub fn pop_byte(&mut self) -> u8 {
         // Address range 0x0100-0x01FF
         self.sp = self.sp.wrapping_add(1);
         let address = 0x0100 + (self.sp as u16);
-        // TODO: read from bus
-        0
+        self.read_byte(address)
     }
 
     pub fn push_byte(&mut self, data: u8) {
         // Address range 0x0100-0x01FF
         let address = 0x0100 + (self.sp as u16);
-        // TODO: write to bus
+        self.write_byte(address, data);
         self.sp = self.sp.wrapping_sub(1);
     }
 
@@ -113,16 +197,13 @@ impl CPU {
     pub fn next_byte(&mut self) -> u8 {
         let address = self.pc;
         self.incrase_pc();
-        // TODO: read from bus
-        0
+        self.read_byte(address)
     }
 
     pub fn next_2bytes(&mut self) -> u16 {
-        let address = self.pc;
-        self.incrase_pc();
-        self.incrase_pc();
-        // TODO: read from bus
-        0
+        let low = self.next_byte() as u16;
+        let high = self.next_byte() as u16;
+        (high << 8) | low
     }
 
     fn check_flag(&self, flag: FlagBit) -> bool {
@@ -153,6 +234,26 @@ impl CPU {
     }
 
     fn get_operand_address(&mut self, mode: Mode) -> u16 {
+        self.operand_address(mode, false)
+    }
+
+    // Stores and read-modify-write instructions can't skip the cycle the CPU
+    // spends fixing the high byte of an indexed address, so they always take it
+    fn get_write_operand_address(&mut self, mode: Mode) -> u16 {
+        self.operand_address(mode, true)
+    }
+
+    // The cycle used to fix the high byte reads from the address the CPU has
+    // computed so far, i.e. without the carry into the high byte
+    fn index_fixup(&mut self, base: u16, index: u8, always: bool) -> u16 {
+        let address = utils::offset(base, index);
+        if always || utils::check_cross_page(base, index) {
+            self.read_byte(utils::high_byte(base) | utils::low_byte(address));
+        }
+        address
+    }
+
+    fn operand_address(&mut self, mode: Mode, write: bool) -> u16 {
         match mode {
             Mode::Immediate => {
                 let temp = self.pc;
@@ -161,57 +262,43 @@ impl CPU {
             }
             Mode::ZeroPage => self.next_byte() as u16,
             Mode::ZeroPageX => {
-                self.bus.tick();
+                self.tick();
                 utils::low_byte(utils::offset(self.next_byte(), self.x))
             }
             Mode::ZeroPageY => {
-                self.bus.tick();
+                self.tick();
                 utils::low_byte(utils::offset(self.next_byte(), self.y))
             }
             Mode::Absolute => self.next_2bytes(),
             Mode::AbsoluteX => {
                 let temp = self.next_2bytes();
-                if utils::check_cross_page(temp, self.x) {
-                    self.bus.tick();
-                }
-                utils::offset(temp, self.x)
+                self.index_fixup(temp, self.x, write)
             }
             Mode::AbsoluteY => {
                 let temp = self.next_2bytes();
-                if utils::check_cross_page(temp, self.y) {
-                    self.bus.tick();
-                }
-                utils::offset(temp, self.y)
+                self.index_fixup(temp, self.y, write)
             }
             Mode::Indirect => {
                 let temp = self.next_2bytes();
-                // TODO: Read from bus
-                0
+                self.read_2bytes_wrapped(temp)
             }
             Mode::IndirectX => {
-                self.bus.tick();
+                self.tick();
                 let temp = self.next_byte();
-                let address = utils::offset(temp, self.x);
-                // TODO: Read from bus
-                0
+                let address = utils::low_byte(utils::offset(temp, self.x));
+                self.read_2bytes_wrapped(address)
             }
             Mode::IndirectY => {
                 let temp = self.next_byte();
-                // TODO: Read from bus
-                let base = 0 as u16;
-                if utils::check_cross_page(base, self.y) {
-                    self.bus.tick();
-                }
-                utils::offset(base, self.y)
+                let base = self.read_2bytes_wrapped(temp as u16);
+                self.index_fixup(base, self.y, write)
             }
-            Mode::NoMode => panic!("No mode"),
         }
     }
 
     fn fetch_operand(&mut self, mode: Mode) -> u8 {
         let address = self.get_operand_address(mode);
-        // TODO: Read from bus
-        0
+        self.read_byte(address)
     }
 
     fn interrupt(&mut self, kind: InterruptType) {
@@ -219,19 +306,16 @@ impl CPU {
             InterruptType::NMI => (2, true, 0xFFFAu16, vec![FlagBit::IrqDisable]),
             InterruptType::RESET => (5, false, 0xFFFCu16, vec![]),
             InterruptType::IRQ => (2, true, 0xFFFEu16, vec![FlagBit::IrqDisable]),
-            InterruptType::BRK => (
-                1,
-                true,
-                0xFFFEu16,
-                vec![FlagBit::IrqDisable, FlagBit::Break],
-            ),
+            // The break flag only exists in the copy of P pushed to the stack
+            InterruptType::BRK => (1, true, 0xFFFEu16, vec![FlagBit::IrqDisable]),
         };
         // Interrupts need couple of ticks to complete
         // The baseline is the BRK instruction, which takes 6 ticks
         // The rest of the interrupts take from 7-10 ticks
         for _ in 0..ticks {
-            self.bus.tick();
+            self.tick();
         }
+        let mut address = address;
         // Push PC to stack
         if push {
             let pc = self.pc;
@@ -242,26 +326,35 @@ impl CPU {
             }
             self.push_2bytes(pc);
             self.push_byte(p);
+        } else {
+            // RESET performs the stack accesses as reads
+            self.sp = self.sp.wrapping_sub(3);
+        }
+        // An NMI asserted while BRK or IRQ pushes its state hijacks the vector
+        if address == 0xFFFE && self.nmi_pending {
+            self.nmi_pending = false;
+            address = 0xFFFA;
         }
         for f in flags {
             self.update_flag(f, true);
         }
-        // TODO: read the new address
-        self.pc = 0;
+        self.pc = self.read_2bytes(address);
+
+        if let Some(debugger) = &mut self.bus.debugger {
+            let interrupt = match kind {
+                InterruptType::NMI => Interrupt::Nmi,
+                InterruptType::IRQ => Interrupt::Irq,
+                InterruptType::BRK => Interrupt::Brk,
+                InterruptType::RESET => return,
+            };
+            debugger.check_interrupt(interrupt);
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn log_instruction(&mut self) {
-        let pc = self.pc;
-        let rom_offset = 15 + (self.pc % 0x4000);
-        // TODO: read from bus
-        let opcode = 0 as usize;
-        let mut args = String::new();
-        for i in 1..debug::INSTRUCTION_SIZES[opcode] {
-
-            // write!(args,"{:02X} ",self.fetch_operand(Mode::Immediate)).unwrap();
-        }
-        debug!("{}",format!("OFFSET: {:06x}\tPC: {:04x}\tOPCODE: {:02x}\tPC: {:04x}\tA: {:02x}\tX: {:02x}\tY: {:02x}\tP: {:08b}\t opcode: [{:02x}] {}\t{}",rom_offset,pc,opcode,pc,self.a,self.x,self.y,self.p,opcode,debug::INSTRUCTION_NAMES[opcode],args));
+    #[cfg(feature = "debug")]
+    fn log_instruction(&self) {
+        let line = format_line(TraceFormat::Nestest, &self.bus, self.registers());
+        log::debug!("{}", line);
     }
 
     fn execute_instruction_opcode(&mut self, opcode: u8) {
@@ -536,7 +629,7 @@ impl CPU {
             0x8B => self.xaa(),
             0xAB => self.lxa(),
             0xCB => self.axs(),
-            0xEB => self.sbc_nop(),
+            0xEB => self.sbc(Mode::Immediate),
             0x93 => self.ahx(Mode::IndirectY),
             0x9F => self.ahx(Mode::AbsoluteY),
             0x9c => self.shy(),
@@ -544,22 +637,71 @@ impl CPU {
             0x9b => self.tas(Mode::AbsoluteY),
             0xbb => self.las(Mode::AbsoluteY),
 
-            0x02 => println!("----------------PING----------------------"),
-
-            _ => panic!("unimplemented or illegal instruction: 0x{:X}", opcode),
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+                self.jam()
+            }
         }
     }
 
     pub fn execute_next_instruction(&mut self) {
-        //TODO: Check if interrupts are enabled
+        // Cycles stolen by DMC sample fetches
+        while self.bus.stall_cycles > 0 {
+            self.bus.stall_cycles -= 1;
+            self.tick();
+        }
+
+        if self.jammed {
+            self.tick();
+            return;
+        }
+
+        if self.prev_nmi_pending {
+            self.nmi_pending = false;
+            self.prev_nmi_pending = false;
+            self.interrupt(InterruptType::NMI);
+            return;
+        }
+        if self.prev_run_irq {
+            self.prev_run_irq = false;
+            self.interrupt(InterruptType::IRQ);
+            return;
+        }
+
+        if self.bus.instrumented() {
+            let registers = self.registers();
+            let debugger = self.bus.debugger.as_mut();
+            if debugger.is_some_and(|d| d.check_instruction(registers)) {
+                return;
+            }
+            if self.bus.code_data_logging() {
+                self.bus.log_instruction(registers);
+            }
+            if let Some(mut tracer) = self.bus.tracer.take() {
+                tracer.trace(&self.bus, registers);
+                self.bus.tracer = Some(tracer);
+            }
+        }
+
         #[cfg(feature = "debug")]
         self.log_instruction();
 
         let instruction = self.next_byte();
+        self.execute_instruction_opcode(instruction);
     }
 }
 
 impl CPU {
+    // Whether a JAM opcode halted the CPU
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
+
+    fn jam(&mut self) {
+        // The operand fetch the CPU gets stuck on
+        self.read_byte(self.pc);
+        self.jammed = true;
+    }
+
     //Loads famaily
     fn lda(&mut self, mode: Mode) {
         let operand = self.fetch_operand(mode);
@@ -581,21 +723,21 @@ impl CPU {
 
     // Stores family
     fn sta(&mut self, mode: Mode) {
-        let operand = self.fetch_operand(mode);
+        let address = self.get_write_operand_address(mode);
         let value = self.a;
-        //TODO: Save the value to memory
+        self.write_byte(address, value);
     }
 
     fn stx(&mut self, mode: Mode) {
-        let operand = self.fetch_operand(mode);
+        let address = self.get_write_operand_address(mode);
         let value = self.x;
-        // TODO: Save the value to memory
+        self.write_byte(address, value);
     }
 
     fn sty(&mut self, mode: Mode) {
-        let operand = self.fetch_operand(mode);
+        let address = self.get_write_operand_address(mode);
         let value = self.y;
-        // TODO: Save the value to memory
+        self.write_byte(address, value);
     }
 
     // Math
@@ -610,12 +752,13 @@ impl CPU {
     }
 
     fn sbc(&mut self, mode: Mode) {
+        // A - M - (1 - C) == A + !M + C
         let operand = !self.fetch_operand(mode);
         let a = self.a;
-        let result = a.wrapping_sub(operand);
-        self.update_zero_and_negative(result);
-        self.update_flow_carry_overflow(a, operand, result as u16);
-        self.a = result;
+        let result = a as u16 + operand as u16 + self.get_carry() as u16;
+        self.update_flow_carry_overflow(a, operand, result);
+        self.update_zero_and_negative(result as u8);
+        self.a = result as u8;
     }
 
     // Compare family
@@ -679,14 +822,13 @@ impl CPU {
     }
 
     fn _rol(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: Read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = (operand << 1) | self.get_carry();
         self.update_flag(FlagBit::Carry, (operand & 0b10000000) != 0);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: Write to memory
+        self.write_byte(address, result);
         result
     }
 
@@ -696,7 +838,7 @@ impl CPU {
         self.update_flag(FlagBit::Carry, (a & 0b10000000) != 0);
         self.update_zero_and_negative(result);
         self.a = result;
-        self.bus.tick();
+        self.tick();
     }
 
     fn ror(&mut self, mode: Mode) {
@@ -704,14 +846,13 @@ impl CPU {
     }
 
     fn _ror(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: Read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = (operand >> 1) | (self.get_carry() << 7);
         self.update_flag(FlagBit::Carry, (operand & 0b00000001) != 0);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: Write to memory
+        self.write_byte(address, result);
         result
     }
 
@@ -721,7 +862,7 @@ impl CPU {
         self.update_flag(FlagBit::Carry, (operand & 0b00000001) != 0);
         self.update_zero_and_negative(result);
         self.a = result;
-        self.bus.tick();
+        self.tick();
     }
 
     fn asl(&mut self, mode: Mode) {
@@ -729,14 +870,13 @@ impl CPU {
     }
 
     fn _asl(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: Read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = operand << 1;
         self.update_flag(FlagBit::Carry, (operand & 0b10000000) != 0);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: Write to memory
+        self.write_byte(address, result);
         result
     }
     fn asl_a(&mut self) {
@@ -745,7 +885,7 @@ impl CPU {
         self.update_flag(FlagBit::Carry, operand & 0b10000000 != 0);
         self.update_zero_and_negative(result);
         self.a = result;
-        self.bus.tick();
+        self.tick();
     }
 
     fn lsr(&mut self, mode: Mode) {
@@ -753,14 +893,13 @@ impl CPU {
     }
 
     fn _lsr(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = operand >> 1;
         self.update_flag(FlagBit::Carry, operand & 0b00000001 != 0);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: write to memory
+        self.write_byte(address, result);
         result
     }
 
@@ -770,7 +909,7 @@ impl CPU {
         self.update_flag(FlagBit::Carry, operand & 1 != 0);
         self.update_zero_and_negative(result);
         self.a = result;
-        self.bus.tick();
+        self.tick();
     }
 
     fn inc(&mut self, mode: Mode) {
@@ -778,13 +917,12 @@ impl CPU {
     }
 
     fn _inc(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = operand.wrapping_add(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: write to memory
+        self.write_byte(address, result);
         result
     }
 
@@ -793,127 +931,126 @@ impl CPU {
     }
 
     fn _dec(&mut self, mode: Mode) -> u8 {
-        let address = self.get_operand_address(mode);
-        // TODO: read operand from memory
-        let operand = 0 as u8;
+        let address = self.get_write_operand_address(mode);
+        let operand = self.read_byte(address);
         let result = operand.wrapping_sub(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
-        // TODO: write to memory
+        self.write_byte(address, result);
         result
     }
 
     fn inx(&mut self) {
         let result = self.x.wrapping_add(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.x = result;
     }
 
     fn dex(&mut self) {
         let result = self.x.wrapping_sub(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.x = result;
     }
 
     fn iny(&mut self) {
         let result = self.y.wrapping_add(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.y = result;
     }
 
     fn dey(&mut self) {
         let result = self.y.wrapping_sub(1);
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.y = result;
     }
 
     fn tax(&mut self) {
         let result = self.a;
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.x = result;
     }
 
     fn tay(&mut self) {
         let result = self.a;
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.y = result;
     }
 
     fn txa(&mut self) {
         let result = self.x;
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.a = result;
     }
 
     fn tya(&mut self) {
         let result = self.y;
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.a = result;
     }
 
     fn txs(&mut self) {
         let result = self.x;
-        self.bus.tick();
+        self.tick();
         self.sp = result;
     }
 
     fn tsx(&mut self) {
         let result = self.sp;
-        self.bus.tick();
+        self.tick();
         self.update_zero_and_negative(result);
         self.x = result;
     }
 
     fn clc(&mut self) {
         self.update_flag(FlagBit::Carry, false);
-        self.bus.tick();
+        self.tick();
     }
 
     fn sec(&mut self) {
         self.update_flag(FlagBit::Carry, true);
-        self.bus.tick();
+        self.tick();
     }
 
     fn cli(&mut self) {
         self.update_flag(FlagBit::IrqDisable, false);
-        self.bus.tick();
+        self.tick();
     }
 
     fn sei(&mut self) {
         self.update_flag(FlagBit::IrqDisable, true);
-        self.bus.tick();
+        self.tick();
     }
 
     fn clv(&mut self) {
         self.update_flag(FlagBit::Overflow, false);
-        self.bus.tick();
+        self.tick();
     }
 
     fn cld(&mut self) {
         self.update_flag(FlagBit::Decimal, false);
-        self.bus.tick();
+        self.tick();
     }
 
     fn sed(&mut self) {
         self.update_flag(FlagBit::Decimal, true);
-        self.bus.tick();
+        self.tick();
     }
     fn branch(&mut self, condition: bool) {
         // branch operand is signed. So we need `as i8 as u16` to cast it as twos-compliment representation
         let offset = self.fetch_operand(Mode::Immediate) as i8 as u16;
         if condition {
-            self.bus.tick();
+            self.tick();
             let next_step = self.pc.wrapping_add(offset);
             if utils::high_byte(self.pc) != high_byte(next_step) {
-                self.bus.tick();
+                self.tick();
             }
             self.pc = next_step;
         }
@@ -964,59 +1101,62 @@ impl CPU {
 
     fn jsr(&mut self) {
         let target_address = self.get_operand_address(Mode::Absolute);
-        let return_address = self.pc - 1;
-        self.bus.tick();
+        let return_address = self.pc.wrapping_sub(1);
+        self.tick();
         self.push_2bytes(return_address);
         self.pc = target_address;
     }
 
     fn rts(&mut self) {
-        self.bus.tick();
-        self.bus.tick();
-        self.pc = self.pop_2bytes() + 1;
-        self.bus.tick();
+        self.tick();
+        self.tick();
+        self.pc = self.pop_2bytes().wrapping_add(1);
+        self.tick();
     }
 
     fn brk(&mut self) {
-        self.pc += 1;
+        self.incrase_pc();
         self.interrupt(InterruptType::BRK);
     }
 
     fn rti(&mut self) {
-        self.p = self.pop_byte();
+        self.tick();
+        self.tick();
+        // Like PLP, the push and break flags are dropped
+        self.p = self.pop_byte() & !(FlagBit::Push as u8 | FlagBit::Break as u8);
         self.pc = self.pop_2bytes();
     }
 
     fn pha(&mut self) {
-        self.bus.tick();
+        self.tick();
         let a = self.a;
         self.push_byte(a);
     }
 
     fn pla(&mut self) {
-        self.bus.tick();
-        self.bus.tick();
+        self.tick();
+        self.tick();
         let result = self.pop_byte();
         self.update_zero_and_negative(result);
         self.a = result;
     }
 
     fn php(&mut self) {
-        self.bus.tick();
+        self.tick();
         // See http://wiki.nesdev.com/w/index.php/CPU_status_flag_behavior
         let p = self.p | FlagBit::Push as u8 | FlagBit::Break as u8;
         self.push_byte(p);
     }
 
     fn plp(&mut self) {
-        self.bus.tick();
-        self.bus.tick();
+        self.tick();
+        self.tick();
         // Push and break flags are never set in the actual P register.
         self.p = self.pop_byte() & !(FlagBit::Push as u8 | FlagBit::Break as u8);
     }
 
     fn nop(&mut self) {
-        self.bus.tick();
+        self.tick();
     }
 
     fn slo(&mut self, mode: Mode) {
@@ -1047,9 +1187,9 @@ impl CPU {
     }
 
     fn sax(&mut self, mode: Mode) {
-        let address = self.get_operand_address(mode);
+        let address = self.get_write_operand_address(mode);
         let result = self.a & self.x;
-        // TODO: write to memory
+        self.write_byte(address, result);
     }
 
     fn lax(&mut self, mode: Mode) {
@@ -1103,14 +1243,18 @@ impl CPU {
         self.a = result;
     }
 
+    // XAA and LXA are unstable, the "magic" constant is taken as 0xFF
     fn xaa(&mut self) {
-        self.txa();
-        self.and(Mode::Immediate);
+        let result = self.x & self.fetch_operand(Mode::Immediate);
+        self.update_zero_and_negative(result);
+        self.a = result;
     }
 
     fn lxa(&mut self) {
-        self.lda(Mode::Immediate);
-        self.tax();
+        let result = self.fetch_operand(Mode::Immediate);
+        self.update_zero_and_negative(result);
+        self.a = result;
+        self.x = result;
     }
 
     fn axs(&mut self) {
@@ -1120,44 +1264,42 @@ impl CPU {
         let result = (a & x).wrapping_sub(operand);
         self.update_flag(FlagBit::Carry, (a & x) >= operand);
         self.update_zero_and_negative(result);
-        self.x = result as u8;
+        self.x = result;
     }
 
-    fn sbc_nop(&mut self) {
-        self.sbc(Mode::Immediate);
-        self.nop();
+    // AHX, SHX, SHY and TAS store a register ANDed with the high byte of the
+    // base address plus one. When the index crosses a page that value also
+    // replaces the high byte of the target address
+    fn unstable_store(&mut self, mode: Mode, index: u8, register: u8) {
+        let address = self.get_write_operand_address(mode);
+        let base = address.wrapping_sub(index as u16);
+        let value = register & ((base >> 8) as u8).wrapping_add(1);
+        let address = if utils::check_cross_page(base, index) {
+            ((value as u16) << 8) | utils::low_byte(address)
+        } else {
+            address
+        };
+        self.write_byte(address, value);
     }
 
     fn ahx(&mut self, mode: Mode) {
-        let address = self.get_operand_address(mode);
-        let result = self.a & self.x & (address >> 8) as u8;
-        // TODO: write to memory
+        let register = self.a & self.x;
+        self.unstable_store(mode, self.y, register);
     }
 
     fn shx(&mut self) {
-        let mut address = self.get_operand_address(Mode::AbsoluteY);
-        if utils::check_cross_page(address - self.y as u16, self.y) {
-            address &= (self.x as u16) << 8;
-        }
-        let result = self.x & ((address >> 8) as u8 + 1);
-        // TODO: write to memory
+        self.unstable_store(Mode::AbsoluteY, self.y, self.x);
     }
 
     fn shy(&mut self) {
-        let mut address = self.get_operand_address(Mode::AbsoluteX);
-        if utils::check_cross_page(address - self.x as u16, self.x) {
-            address &= (self.y as u16) << 8;
-        }
-        let result = self.y & ((address >> 8) as u8 + 1);
-        // TODO: write to memory
+        self.unstable_store(Mode::AbsoluteX, self.x, self.y);
     }
 
     fn tas(&mut self, mode: Mode) {
-        let address = self.get_operand_address(mode);
         self.sp = self.x & self.a;
-        let result = self.sp & ((address >> 8) as u8 + 1);
-        // TODO: write to memory
+        self.unstable_store(mode, self.y, self.sp);
     }
+
     fn las(&mut self, mode: Mode) {
         let result = self.fetch_operand(mode) & self.sp;
         self.a = result;
@@ -1170,3 +1312,250 @@ impl CPU {
         self.fetch_operand(mode);
     }
 }
+
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.pc);
+        w.write_u8(self.sp);
+        w.write_u8(self.a);
+        w.write_u8(self.x);
+        w.write_u8(self.y);
+        w.write_u8(self.p);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.prev_nmi_pending);
+        w.write_bool(self.run_irq);
+        w.write_bool(self.prev_run_irq);
+        w.write_bool(self.jammed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u8()?;
+        self.a = r.read_u8()?;
+        self.x = r.read_u8()?;
+        self.y = r.read_u8()?;
+        self.p = r.read_u8()?;
+        self.nmi_pending = r.read_bool()?;
+        self.prev_nmi_pending = r.read_bool()?;
+        self.run_irq = r.read_bool()?;
+        self.prev_run_irq = r.read_bool()?;
+        self.jammed = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::test_util::nrom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Builds a CPU wired to an NROM cartridge with 16KB of PRG-ROM mapped at
+    // both 0x8000 and 0xC000. `program` is placed at 0x8000
+    fn build_cpu(program: &[u8], nmi: u16, reset: u16, irq: u16) -> CPU {
+        let mut rom = nrom(program);
+        for (i, vector) in [nmi, reset, irq].into_iter().enumerate() {
+            let offset = 16 + 0x3FFA + i * 2;
+            rom[offset..offset + 2].copy_from_slice(&vector.to_le_bytes());
+        }
+
+        let mut bus = SystemBus::new();
+        bus.set_cartridge(Rc::new(RefCell::new(Cartridge::new(&rom).unwrap())));
+        let mut cpu = CPU::new(bus);
+        cpu.reset_registers();
+        cpu
+    }
+
+    #[test]
+    fn test_reset_vector() {
+        let cpu = build_cpu(&[], 0x0000, 0x8123, 0x0000);
+        assert_eq!(cpu.pc, 0x8123);
+        assert_eq!(cpu.sp, 0xFD);
+        assert!(cpu.check_flag(FlagBit::IrqDisable));
+        assert_eq!(cpu.bus.cycles, 7);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let program = [
+            0xA9, 0x80, // LDA #$80
+            0x8D, 0x00, 0x20, // STA $2000
+            0x4C, 0x05, 0x80, // JMP $8005
+            0xE8, // NMI: INX
+            0x40, // RTI
+        ];
+        let mut cpu = build_cpu(&program, 0x8008, 0x8000, 0x0000);
+        while cpu.bus.ppu.frame < 2 {
+            cpu.execute_next_instruction();
+        }
+        assert_eq!(cpu.x, 2);
+        assert_eq!(cpu.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_irq_after_cli() {
+        let program = [
+            0x58, // CLI
+            0x4C, 0x01, 0x80, // JMP $8001
+            0xE8, // IRQ: INX
+            0x78, // SEI
+            0x4C, 0x05, 0x80, // JMP $8005
+        ];
+        // The APU frame counter raises its IRQ 29829 cycles after power on
+        let mut cpu = build_cpu(&program, 0x0000, 0x8000, 0x8004);
+        while cpu.bus.cycles < 40000 {
+            cpu.execute_next_instruction();
+        }
+        assert_eq!(cpu.x, 1);
+        assert!(cpu.check_flag(FlagBit::IrqDisable));
+    }
+
+    // Base cycle counts, without page crossing or taken branches
+    #[rustfmt::skip]
+    const CYCLES: [u64; 256] = [
+        7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+        2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+        2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+        2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    ];
+
+    // Extra cycle taken by reads when indexing crosses a page
+    #[rustfmt::skip]
+    const PAGE_CROSS_CYCLES: [u64; 256] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0,
+    ];
+
+    const KIL: [u8; 12] = [
+        0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+    ];
+
+    // Runs a single instruction from RAM and returns the cycles it took
+    fn count_cycles(address: u16, program: &[u8], index: u8, p: u8) -> u64 {
+        let mut cpu = CPU::new(SystemBus::new());
+        for (i, byte) in program.iter().enumerate() {
+            cpu.bus.write(address + i as u16, *byte);
+        }
+        // Zero page pointer used by the indirect modes
+        cpu.bus.write(0x0003, 0x03);
+        cpu.bus.write(0x0004, 0x03);
+        cpu.pc = address;
+        cpu.sp = 0xFD;
+        cpu.p = p;
+        cpu.x = index;
+        cpu.y = index;
+        cpu.execute_next_instruction();
+        cpu.bus.cycles
+    }
+
+    fn is_branch(opcode: u8) -> bool {
+        opcode & 0x1F == 0x10
+    }
+
+    #[test]
+    fn test_opcode_cycles() {
+        for opcode in 0..=255u8 {
+            // With every flag cleared BPL, BVC, BCC and BNE are taken
+            let taken = is_branch(opcode) && opcode & 0x20 == 0;
+            let expected = CYCLES[opcode as usize] + taken as u64;
+            let actual = count_cycles(0x0200, &[opcode, 0x00, 0x03], 0, 0);
+            assert_eq!(actual, expected, "opcode {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_opcode_page_cross_cycles() {
+        for opcode in (0..=255u8).filter(|op| !is_branch(*op)) {
+            let expected = CYCLES[opcode as usize] + PAGE_CROSS_CYCLES[opcode as usize];
+            let actual = count_cycles(0x0200, &[opcode, 0x03, 0x03], 0xFF, 0);
+            assert_eq!(actual, expected, "opcode {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_rts_wraps() {
+        let program = [
+            0xA9, 0xFF, // LDA #$FF
+            0x48, // PHA
+            0x48, // PHA
+            0x60, // RTS
+        ];
+        let mut cpu = build_cpu(&program, 0x0000, 0x8000, 0x0000);
+        for _ in 0..4 {
+            cpu.execute_next_instruction();
+        }
+        assert_eq!(cpu.pc, 0x0000);
+    }
+
+    #[test]
+    fn test_jsr_wraps() {
+        // The high byte of the reset vector is a JSR to the IRQ vector, whose
+        // operand ends the address space
+        let mut cpu = build_cpu(&[], 0x0000, 0x2000, 0x8000);
+        cpu.pc = 0xFFFD;
+        cpu.execute_next_instruction();
+        assert_eq!(cpu.pc, 0x8000);
+        assert_eq!(cpu.pop_2bytes(), 0xFFFF);
+    }
+
+    #[test]
+    fn test_jam() {
+        for opcode in KIL {
+            let mut cpu = CPU::new(SystemBus::new());
+            cpu.bus.write(0x0200, opcode);
+            cpu.pc = 0x0200;
+            cpu.execute_next_instruction();
+            assert!(cpu.jammed(), "opcode {:02X}", opcode);
+
+            // Interrupts are ignored and the clock keeps running
+            cpu.nmi_pending = true;
+            cpu.prev_nmi_pending = true;
+            let cycles = cpu.bus.cycles;
+            for _ in 0..10 {
+                cpu.execute_next_instruction();
+            }
+            assert_eq!(cpu.bus.cycles, cycles + 10);
+            assert_eq!(cpu.pc, 0x0201);
+
+            cpu.reset();
+            assert!(!cpu.jammed());
+        }
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        let not_taken = FlagBit::Zero as u8;
+        assert_eq!(count_cycles(0x02F0, &[0xD0, 0x7F], 0, not_taken), 2);
+        assert_eq!(count_cycles(0x02F0, &[0xD0, 0x02], 0, 0), 3);
+        assert_eq!(count_cycles(0x02F0, &[0xD0, 0x7F], 0, 0), 4);
+        assert_eq!(count_cycles(0x0200, &[0xD0, 0xF0], 0, 0), 4);
+    }
+}
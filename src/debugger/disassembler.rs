@@ -0,0 +1,277 @@
+// Disassembler producing ca65 syntax, e.g. `LDA ($20),Y` or `JMP ($FFFC)`
+//
+// Memory is read with SystemBus::peek, so disassembling never disturbs the
+// machine. Unofficial opcodes use the mnemonics ca65 accepts with
+// `.setcpu "6502X"`. Absolute operands below $100 get an `a:` prefix so ca65
+// doesn't assemble them as zero page.
+
+use super::labels::Labels;
+use super::Registers;
+use crate::bus::SystemBus;
+use crate::cpu::debug::{INSTRUCTION_NAMES, INSTRUCTION_SIZES};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub unofficial: bool,
+    // Memory the instruction accesses or jumps to, computed from the
+    // registers given to disassemble. Only meaningful for the instruction
+    // at PC
+    pub effective_address: Option<u16>,
+    // Label of the instruction's own address
+    pub label: Option<String>,
+    // Label of the address in the operand
+    pub operand_label: Option<String>,
+}
+
+impl Instruction {
+    // The operand as written, or the target address of a branch
+    pub fn operand(&self) -> u16 {
+        match (self.mode, self.bytes.len()) {
+            (AddressingMode::Relative, _) => {
+                self.next_address().wrapping_add(self.bytes[1] as i8 as u16)
+            }
+            (_, 2) => self.bytes[1] as u16,
+            (_, 3) => u16::from_le_bytes([self.bytes[1], self.bytes[2]]),
+            _ => 0,
+        }
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = self.operand();
+        let address = match (&self.operand_label, self.mode) {
+            (Some(label), _) => label.clone(),
+            (None, AddressingMode::ZeroPage)
+            | (None, AddressingMode::ZeroPageX)
+            | (None, AddressingMode::ZeroPageY)
+            | (None, AddressingMode::IndirectX)
+            | (None, AddressingMode::IndirectY) => format!("${:02X}", operand),
+            (None, _) => format!("${:04X}", operand),
+        };
+        // Keeps ca65 from picking zero page addressing for absolute operands
+        let absolute = if operand < 0x100 { "a:" } else { "" };
+        match self.mode {
+            AddressingMode::Implied => write!(f, "{}", self.mnemonic),
+            AddressingMode::Accumulator => write!(f, "{} A", self.mnemonic),
+            AddressingMode::Immediate => write!(f, "{} #${:02X}", self.mnemonic, operand),
+            AddressingMode::ZeroPage | AddressingMode::Relative => {
+                write!(f, "{} {}", self.mnemonic, address)
+            }
+            AddressingMode::ZeroPageX => write!(f, "{} {},X", self.mnemonic, address),
+            AddressingMode::ZeroPageY => write!(f, "{} {},Y", self.mnemonic, address),
+            AddressingMode::Absolute => write!(f, "{} {}{}", self.mnemonic, absolute, address),
+            AddressingMode::AbsoluteX => {
+                write!(f, "{} {}{},X", self.mnemonic, absolute, address)
+            }
+            AddressingMode::AbsoluteY => {
+                write!(f, "{} {}{},Y", self.mnemonic, absolute, address)
+            }
+            AddressingMode::Indirect => write!(f, "{} ({})", self.mnemonic, address),
+            AddressingMode::IndirectX => write!(f, "{} ({},X)", self.mnemonic, address),
+            AddressingMode::IndirectY => write!(f, "{} ({}),Y", self.mnemonic, address),
+        }
+    }
+}
+
+// Decodes the instruction at `address`. Labels come from `labels` when given
+pub(crate) fn disassemble(
+    bus: &SystemBus,
+    address: u16,
+    registers: &Registers,
+    labels: Option<&Labels>,
+) -> Instruction {
+    let opcode = bus.peek(address) as usize;
+    let bytes = (0..INSTRUCTION_SIZES[opcode])
+        .map(|i| bus.peek(address.wrapping_add(i)))
+        .collect();
+    let (name, unofficial) = match INSTRUCTION_NAMES[opcode].strip_prefix('*') {
+        Some(name) => (name, true),
+        None => (INSTRUCTION_NAMES[opcode], false),
+    };
+    let (mnemonic, mode) = name.split_once(' ').unwrap_or((name, ""));
+    let mnemonic = match mnemonic {
+        "KIL" => "JAM",
+        "AHX" => "SHA",
+        "XAA" => "ANE",
+        mnemonic => mnemonic,
+    };
+    let mode = match mode {
+        "imm" => AddressingMode::Immediate,
+        "zp" => AddressingMode::ZeroPage,
+        "zpx" => AddressingMode::ZeroPageX,
+        "zpy" => AddressingMode::ZeroPageY,
+        "abs" => AddressingMode::Absolute,
+        "abx" => AddressingMode::AbsoluteX,
+        "aby" => AddressingMode::AbsoluteY,
+        "ind" => AddressingMode::Indirect,
+        "izx" => AddressingMode::IndirectX,
+        "izy" => AddressingMode::IndirectY,
+        "rel" => AddressingMode::Relative,
+        _ if matches!(mnemonic, "ASL" | "LSR" | "ROL" | "ROR") => AddressingMode::Accumulator,
+        _ => AddressingMode::Implied,
+    };
+
+    let mut instruction = Instruction {
+        address,
+        bytes,
+        mnemonic,
+        mode,
+        unofficial,
+        effective_address: None,
+        label: None,
+        operand_label: None,
+    };
+    instruction.effective_address = effective_address(bus, &instruction, registers);
+    if let Some(labels) = labels {
+        let label = |address| labels.get(address, bus.prg_rom_offset(address));
+        instruction.label = label(address).map(str::to_string);
+        let has_address = !matches!(
+            mode,
+            AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate
+        );
+        if has_address {
+            instruction.operand_label = label(instruction.operand()).map(str::to_string);
+        }
+    }
+    instruction
+}
+
+fn effective_address(
+    bus: &SystemBus,
+    instruction: &Instruction,
+    registers: &Registers,
+) -> Option<u16> {
+    let operand = instruction.operand();
+    // Pointers wrap within their page, in the zero page and for JMP ($xxFF)
+    let pointer = |address: u16| {
+        let high = (address & 0xFF00) | (address.wrapping_add(1) & 0x00FF);
+        u16::from_le_bytes([bus.peek(address), bus.peek(high)])
+    };
+    let (x, y) = (registers.x as u16, registers.y as u16);
+    match instruction.mode {
+        AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate => None,
+        AddressingMode::ZeroPage | AddressingMode::Absolute | AddressingMode::Relative => {
+            Some(operand)
+        }
+        AddressingMode::ZeroPageX => Some((operand + x) & 0xFF),
+        AddressingMode::ZeroPageY => Some((operand + y) & 0xFF),
+        AddressingMode::AbsoluteX => Some(operand.wrapping_add(x)),
+        AddressingMode::AbsoluteY => Some(operand.wrapping_add(y)),
+        AddressingMode::Indirect => Some(pointer(operand)),
+        AddressingMode::IndirectX => Some(pointer((operand + x) & 0xFF)),
+        AddressingMode::IndirectY => Some(pointer(operand).wrapping_add(y)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bus_with(program: &[u8]) -> SystemBus {
+        let mut bus = SystemBus::new();
+        for (i, &byte) in program.iter().enumerate() {
+            bus.write(i as u16, byte);
+        }
+        bus
+    }
+
+    fn text(bus: &SystemBus, address: u16) -> String {
+        disassemble(bus, address, &Registers::default(), None).to_string()
+    }
+
+    #[test]
+    fn test_syntax() {
+        let bus = bus_with(&[
+            0xB1, 0x20, // LDA ($20),Y
+            0x6C, 0xFC, 0xFF, // JMP ($FFFC)
+            0xD0, 0xFB, // BNE $0002
+            0xA9, 0x10, // LDA #$10
+            0x0A, // ASL A
+            0x9D, 0x20, 0x00, // STA a:$0020,X
+            0xA1, 0x80, // LDA ($80,X)
+            0xB6, 0x10, // LDX $10,Y
+            0x02, // JAM
+            0xA7, 0x00, // LAX $00
+            0x60, // RTS
+        ]);
+        let listing: Vec<String> = [0, 2, 5, 7, 9, 10, 13, 15, 17, 18, 20]
+            .iter()
+            .map(|&address| text(&bus, address))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "LDA ($20),Y",
+                "JMP ($FFFC)",
+                "BNE $0002",
+                "LDA #$10",
+                "ASL A",
+                "STA a:$0020,X",
+                "LDA ($80,X)",
+                "LDX $10,Y",
+                "JAM",
+                "LAX $00",
+                "RTS"
+            ]
+        );
+        let lax = disassemble(&bus, 18, &Registers::default(), None);
+        assert!(lax.unofficial);
+        assert_eq!(lax.next_address(), 20);
+    }
+
+    #[test]
+    fn test_effective_address() {
+        let mut bus = bus_with(&[
+            0xB1, 0x10, // LDA ($10),Y
+            0xA1, 0x0E, // LDA ($0E,X)
+            0xBD, 0xF0, 0x02, // LDA $02F0,X
+            0x96, 0xFF, // STX $FF,Y
+        ]);
+        bus.write(0x10, 0x00);
+        bus.write(0x11, 0x03);
+        let registers = Registers {
+            x: 2,
+            y: 0x20,
+            ..Registers::default()
+        };
+        let effective = |address| disassemble(&bus, address, &registers, None).effective_address;
+        assert_eq!(effective(0), Some(0x0320));
+        assert_eq!(effective(2), Some(0x0300));
+        assert_eq!(effective(4), Some(0x02F2));
+        assert_eq!(effective(7), Some(0x001F));
+
+        let mut labels = Labels::new();
+        labels.insert_cpu(0x0010, "pointer");
+        labels.insert_cpu(0x0000, "start");
+        let instruction = disassemble(&bus, 0, &registers, Some(&labels));
+        assert_eq!(instruction.to_string(), "LDA (pointer),Y");
+        assert_eq!(instruction.label.as_deref(), Some("start"));
+    }
+}
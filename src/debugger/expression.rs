@@ -0,0 +1,367 @@
+// Breakpoint conditions, e.g. `A == #$10 && X > 3`
+//
+// Operands are the registers A, X, Y, SP, P and PC, the ADDRESS and VALUE of
+// the access that hit a watchpoint, and numbers written in decimal, as $hex
+// or %binary, optionally prefixed with # like an immediate operand. The
+// operators are, from loosest to tightest binding, ||, &&, the comparisons
+// == != < <= > >=, the bitwise & | ^ and the unary ! and ~. Comparisons and
+// logical operators yield 1 or 0, and any non-zero result counts as true.
+
+use super::Registers;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpressionError {
+    // Byte offset into the expression where parsing failed
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.reason, self.position)
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+// What a condition is evaluated against
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Context {
+    pub registers: Registers,
+    pub address: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Operand {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+    Address,
+    Value,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum BinaryOperator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum UnaryOperator {
+    Not,
+    Complement,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(u32),
+    Operand(Operand),
+    Unary(UnaryOperator, Box<Node>),
+    Binary(BinaryOperator, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            end: text.len(),
+        };
+        let root = parser.or()?;
+        match parser.peek() {
+            None => Ok(Expression { root }),
+            Some(_) => Err(parser.error("unexpected token")),
+        }
+    }
+
+    pub fn evaluate(&self, context: &Context) -> bool {
+        evaluate(&self.root, context) != 0
+    }
+}
+
+fn evaluate(node: &Node, context: &Context) -> u32 {
+    match node {
+        Node::Number(n) => *n,
+        Node::Operand(operand) => {
+            let r = &context.registers;
+            match operand {
+                Operand::A => r.a as u32,
+                Operand::X => r.x as u32,
+                Operand::Y => r.y as u32,
+                Operand::Sp => r.sp as u32,
+                Operand::P => r.p as u32,
+                Operand::Pc => r.pc as u32,
+                Operand::Address => context.address as u32,
+                Operand::Value => context.value as u32,
+            }
+        }
+        Node::Unary(operator, operand) => {
+            let value = evaluate(operand, context);
+            match operator {
+                UnaryOperator::Not => (value == 0) as u32,
+                UnaryOperator::Complement => !value,
+            }
+        }
+        Node::Binary(BinaryOperator::Or, left, right) => {
+            (evaluate(left, context) != 0 || evaluate(right, context) != 0) as u32
+        }
+        Node::Binary(BinaryOperator::And, left, right) => {
+            (evaluate(left, context) != 0 && evaluate(right, context) != 0) as u32
+        }
+        Node::Binary(operator, left, right) => {
+            let (l, r) = (evaluate(left, context), evaluate(right, context));
+            match operator {
+                BinaryOperator::Equal => (l == r) as u32,
+                BinaryOperator::NotEqual => (l != r) as u32,
+                BinaryOperator::Less => (l < r) as u32,
+                BinaryOperator::LessEqual => (l <= r) as u32,
+                BinaryOperator::Greater => (l > r) as u32,
+                BinaryOperator::GreaterEqual => (l >= r) as u32,
+                BinaryOperator::BitAnd => l & r,
+                BinaryOperator::BitOr => l | r,
+                BinaryOperator::BitXor => l ^ r,
+                BinaryOperator::Or | BinaryOperator::And => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u32),
+    Operand(Operand),
+    Binary(BinaryOperator),
+    Unary(UnaryOperator),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let error = |reason| ExpressionError {
+            position: start,
+            reason,
+        };
+        let rest = &text[i..];
+        let two = rest.get(..2).unwrap_or("");
+        let token = match two {
+            "||" => Some(Token::Binary(BinaryOperator::Or)),
+            "&&" => Some(Token::Binary(BinaryOperator::And)),
+            "==" => Some(Token::Binary(BinaryOperator::Equal)),
+            "!=" => Some(Token::Binary(BinaryOperator::NotEqual)),
+            "<=" => Some(Token::Binary(BinaryOperator::LessEqual)),
+            ">=" => Some(Token::Binary(BinaryOperator::GreaterEqual)),
+            _ => None,
+        };
+        if let Some(token) = token {
+            tokens.push((start, token));
+            i += 2;
+            continue;
+        }
+
+        let c = bytes[i];
+        let token = match c {
+            b' ' | b'\t' => {
+                i += 1;
+                continue;
+            }
+            b'<' => Token::Binary(BinaryOperator::Less),
+            b'>' => Token::Binary(BinaryOperator::Greater),
+            b'&' => Token::Binary(BinaryOperator::BitAnd),
+            b'|' => Token::Binary(BinaryOperator::BitOr),
+            b'^' => Token::Binary(BinaryOperator::BitXor),
+            b'!' => Token::Unary(UnaryOperator::Not),
+            b'~' => Token::Unary(UnaryOperator::Complement),
+            b'(' => Token::Open,
+            b')' => Token::Close,
+            b'#' | b'$' | b'%' | b'0'..=b'9' => {
+                let rest = rest.strip_prefix('#').unwrap_or(rest);
+                let (radix, digits) = match rest.as_bytes().first() {
+                    Some(b'$') => (16, &rest[1..]),
+                    Some(b'%') => (2, &rest[1..]),
+                    _ => (10, rest),
+                };
+                let len = digits
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(digits.len());
+                let number = u32::from_str_radix(&digits[..len], radix)
+                    .map_err(|_| error("invalid number"))?;
+                i = text.len() - digits.len() + len;
+                tokens.push((start, Token::Number(number)));
+                continue;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                let operand = match rest[..len].to_ascii_uppercase().as_str() {
+                    "A" => Operand::A,
+                    "X" => Operand::X,
+                    "Y" => Operand::Y,
+                    "SP" | "S" => Operand::Sp,
+                    "P" => Operand::P,
+                    "PC" => Operand::Pc,
+                    "ADDRESS" => Operand::Address,
+                    "VALUE" => Operand::Value,
+                    _ => return Err(error("unknown register")),
+                };
+                i += len;
+                tokens.push((start, Token::Operand(operand)));
+                continue;
+            }
+            _ => return Err(error("unexpected character")),
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+// Recursive descent, one function per precedence level
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn error(&self, reason: &'static str) -> ExpressionError {
+        let position = self
+            .tokens
+            .get(self.position)
+            .map_or(self.end, |&(offset, _)| offset);
+        ExpressionError { position, reason }
+    }
+
+    // Parses a left associative chain of the operators `operators`
+    fn binary(
+        &mut self,
+        operators: &[BinaryOperator],
+        next: fn(&mut Parser) -> Result<Node, ExpressionError>,
+    ) -> Result<Node, ExpressionError> {
+        let mut node = next(self)?;
+        while let Some(&Token::Binary(operator)) = self.peek() {
+            if !operators.contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            node = Node::Binary(operator, Box::new(node), Box::new(next(self)?));
+        }
+        Ok(node)
+    }
+
+    fn or(&mut self) -> Result<Node, ExpressionError> {
+        self.binary(&[BinaryOperator::Or], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Node, ExpressionError> {
+        self.binary(&[BinaryOperator::And], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExpressionError> {
+        use BinaryOperator::*;
+        self.binary(
+            &[Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual],
+            Parser::bitwise,
+        )
+    }
+
+    fn bitwise(&mut self) -> Result<Node, ExpressionError> {
+        use BinaryOperator::*;
+        self.binary(&[BitAnd, BitOr, BitXor], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Unary(operator)) => Ok(Node::Unary(operator, Box::new(self.unary()?))),
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Operand(operand)) => Ok(Node::Operand(operand)),
+            Some(Token::Open) => {
+                let node = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(self.error("expected )"));
+                }
+                self.position += 1;
+                Ok(node)
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(text: &str) -> bool {
+        let context = Context {
+            registers: Registers {
+                pc: 0x8000,
+                a: 0x10,
+                x: 5,
+                y: 0,
+                sp: 0xFD,
+                p: 0x25,
+            },
+            address: 0x2007,
+            value: 0x42,
+        };
+        Expression::parse(text).unwrap().evaluate(&context)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert!(eval("A == #$10 && X > 3"));
+        assert!(!eval("A == #$10 && X > 5"));
+        assert!(eval("a != 16 || y == 0"));
+        assert!(eval("(P & %00000001) == 1"));
+        assert!(eval("!(Y)"));
+        assert!(eval("PC >= $8000 && SP == $FD"));
+        assert!(eval("address == $2007 && value == $42"));
+        assert!(eval("X"));
+        assert!(eval("~A & $FF == $EF"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| Expression::parse(text).unwrap_err();
+        assert_eq!(error("A == ").position, 5);
+        assert_eq!(error("A == $1G").reason, "invalid number");
+        assert_eq!(error("Q == 1").reason, "unknown register");
+        assert_eq!(error("(A == 1").reason, "expected )");
+        assert_eq!(error("A == 1 2").position, 7);
+        assert_eq!(error("A @ 1").position, 2);
+    }
+}
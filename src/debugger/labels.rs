@@ -0,0 +1,284 @@
+// Labels loaded from symbol files, for the disassembler
+//
+// Labels either name a CPU address, like RAM variables and registers, or a
+// byte of PRG ROM, so code in a switched bank is only labeled while that bank
+// is mapped. Supported files are FCEUX .nl files (rom.nes.ram.nl for CPU
+// addresses and rom.nes.N.nl for 16KB PRG bank N), Mesen .mlb files and ca65
+// .dbg files written by ld65 --dbgfile.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Size of the banks FCEUX numbers its .nl files by
+const NL_BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for LabelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {}", self.reason, self.line)
+    }
+}
+
+impl std::error::Error for LabelError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    cpu: HashMap<u16, String>,
+    prg_rom: HashMap<usize, String>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels::default()
+    }
+
+    pub fn insert_cpu(&mut self, address: u16, name: &str) {
+        self.cpu.insert(address, name.to_string());
+    }
+
+    pub fn insert_prg_rom(&mut self, offset: usize, name: &str) {
+        self.prg_rom.insert(offset, name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg_rom.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The label of a CPU address, preferring the label of the PRG ROM byte
+    // mapped there
+    pub fn get(&self, address: u16, prg_rom_offset: Option<usize>) -> Option<&str> {
+        prg_rom_offset
+            .and_then(|offset| self.prg_rom.get(&offset))
+            .or_else(|| self.cpu.get(&address))
+            .map(String::as_str)
+    }
+
+    // Loads a symbol file, telling the format from its name
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let result = if let Some(stem) = name.strip_suffix(".nl") {
+            let bank = stem.rsplit('.').next().and_then(|b| b.parse().ok());
+            self.parse_nl(&text, bank)
+        } else if name.ends_with(".mlb") {
+            self.parse_mlb(&text)
+        } else if name.ends_with(".dbg") {
+            self.parse_dbg(&text)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown symbol file format",
+            ));
+        };
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // FCEUX lines look like `$C000#Reset#comment`, or `$0300/10#Buffer#` for
+    // arrays. Addresses in a bank file are mapped into `bank`
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), LabelError> {
+        for (i, line) in text.lines().enumerate() {
+            let error = |reason| LabelError {
+                line: i + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, '#');
+            let address = fields.next().unwrap_or("");
+            let name = fields.next().ok_or(error("missing name"))?;
+            let address = address.strip_prefix('$').ok_or(error("missing address"))?;
+            let address = address.split('/').next().unwrap_or("");
+            let address = u16::from_str_radix(address, 16).map_err(|_| error("invalid address"))?;
+            if name.is_empty() {
+                continue;
+            }
+            match bank {
+                Some(bank) => self.insert_prg_rom(
+                    bank * NL_BANK_SIZE + (address as usize % NL_BANK_SIZE),
+                    name,
+                ),
+                None => self.insert_cpu(address, name),
+            }
+        }
+        Ok(())
+    }
+
+    // Mesen lines look like `P:0123:Reset:comment` or `R:0010-0011:Pointer`.
+    // Mesen 2 spells the memory types out, like `NesPrgRom:0123:Reset`
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), LabelError> {
+        for (i, line) in text.lines().enumerate() {
+            let error = |reason| LabelError {
+                line: i + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or("");
+            let range = fields.next().ok_or(error("missing address"))?;
+            let name = fields.next().ok_or(error("missing name"))?;
+            let start = range.split('-').next().unwrap_or("");
+            let start = usize::from_str_radix(start, 16).map_err(|_| error("invalid address"))?;
+            if name.is_empty() {
+                continue;
+            }
+            match kind {
+                "P" | "NesPrgRom" => self.insert_prg_rom(start, name),
+                "R" | "NesInternalRam" => self.insert_cpu(start as u16 & 0x7FF, name),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    self.insert_cpu(0x6000 + (start as u16 & 0x1FFF), name)
+                }
+                "G" | "NesMemory" => self.insert_cpu(start as u16, name),
+                // CHR, palette and other PPU memory never show up in code
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // ld65 debug files list segments and symbols as tab separated records of
+    // key=value pairs. Labels in segments written to the ROM are placed by
+    // their offset in the output file
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), LabelError> {
+        // Segment id to its start address and offset into PRG ROM
+        let mut segments = HashMap::new();
+        let mut symbols = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |reason| LabelError {
+                line: i + 1,
+                reason,
+            };
+            let Some((record, fields)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields: HashMap<&str, &str> = fields
+                .split(',')
+                .filter_map(|field| field.trim().split_once('='))
+                .collect();
+            let number = |key| -> Result<Option<usize>, LabelError> {
+                fields
+                    .get(key)
+                    .map(|value| parse_number(value))
+                    .transpose()
+                    .map_err(|_| error("invalid number"))
+            };
+            match record {
+                "seg" => {
+                    let id = number("id")?.ok_or(error("missing id"))?;
+                    let start = number("start")?.ok_or(error("missing start"))?;
+                    let offset = number("ooffs")?
+                        .filter(|&o| o >= INES_HEADER_SIZE)
+                        .map(|o| o - INES_HEADER_SIZE);
+                    if fields.get("type") == Some(&"ro") {
+                        if let Some(offset) = offset {
+                            segments.insert(id, (start, offset));
+                        }
+                    }
+                }
+                "sym" if fields.get("type") == Some(&"lab") => {
+                    let name = fields.get("name").ok_or(error("missing name"))?;
+                    let value = number("val")?.ok_or(error("missing value"))?;
+                    symbols.push((name.trim_matches('"'), value, number("seg")?));
+                }
+                _ => (),
+            }
+        }
+        for (name, value, segment) in symbols {
+            match segment.and_then(|id| segments.get(&id)) {
+                Some(&(start, offset)) if value >= start => {
+                    self.insert_prg_rom(offset + value - start, name)
+                }
+                _ => self.insert_cpu(value as u16, name),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, std::num::ParseIntError> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_nl() {
+        let mut labels = Labels::new();
+        labels
+            .parse_nl("$0010#pointer#zero page\n$0300/10#buffer#\n", None)
+            .unwrap();
+        labels
+            .parse_nl("$C004#Reset#\r\n$C010##\n", Some(1))
+            .unwrap();
+        assert_eq!(labels.get(0x0010, None), Some("pointer"));
+        assert_eq!(labels.get(0x0300, None), Some("buffer"));
+        assert_eq!(labels.get(0xC004, Some(0x4004)), Some("Reset"));
+        assert_eq!(labels.get(0xC004, Some(0x0004)), None);
+        assert_eq!(labels.len(), 3);
+        assert_eq!(
+            labels.parse_nl("C000#Reset#", None).unwrap_err(),
+            LabelError {
+                line: 1,
+                reason: "missing address"
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_mlb() {
+        let mut labels = Labels::new();
+        labels
+            .parse_mlb("P:0004:Reset:entry point\nR:0010-0011:pointer\nG:2002:PPUSTATUS\nW:0000:save\nNesPrgRom:4000:bank1\n")
+            .unwrap();
+        assert_eq!(labels.get(0x8004, Some(0x0004)), Some("Reset"));
+        assert_eq!(labels.get(0x0010, None), Some("pointer"));
+        assert_eq!(labels.get(0x2002, None), Some("PPUSTATUS"));
+        assert_eq!(labels.get(0x6000, None), Some("save"));
+        assert_eq!(labels.get(0x8000, Some(0x4000)), Some("bank1"));
+        assert_eq!(
+            labels.parse_mlb("P:zz:Reset").unwrap_err().reason,
+            "invalid address"
+        );
+    }
+
+    #[test]
+    fn test_parse_dbg() {
+        let text = "version\tmajor=2,minor=0\n\
+            seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0\n\
+            seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+            seg\tid=2,name=\"ZEROPAGE\",start=0x000000,size=0x0002,addrsize=zeropage,type=rw\n\
+            sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0xC004,seg=1,type=lab\n\
+            sym\tid=1,name=\"pointer\",addrsize=zeropage,scope=0,def=2,val=0x10,seg=2,type=lab\n\
+            sym\tid=2,name=\"SIZE\",addrsize=zeropage,scope=0,def=3,val=0x20,type=equ\n";
+        let mut labels = Labels::new();
+        labels.parse_dbg(text).unwrap();
+        assert_eq!(labels.get(0xC004, Some(0x4004)), Some("reset"));
+        assert_eq!(labels.get(0x0010, None), Some("pointer"));
+        assert_eq!(labels.len(), 2);
+    }
+}
@@ -0,0 +1,439 @@
+// Debugger keeps breakpoints, watchpoints and stepping state for a running
+// machine.
+//
+// It lives on the system bus behind an Option, so a machine without one only
+// pays for a null check per instruction and memory access. The CPU asks it
+// before every instruction whether to stop, the bus reports every CPU access
+// and every PPU access made through PPUDATA, and the CPU reports interrupts.
+// Accesses can't stop an instruction halfway, so watchpoints stop the machine
+// once the instruction that hit them has finished.
+
+mod disassembler;
+mod expression;
+mod labels;
+
+pub(crate) use self::disassembler::disassemble;
+pub use self::disassembler::{AddressingMode, Instruction};
+use self::expression::Context;
+pub use self::expression::{Expression, ExpressionError};
+pub use self::labels::{LabelError, Labels};
+use std::ops::RangeInclusive;
+
+const JSR: u8 = 0x20;
+
+// CPU registers, with P as it would be pushed by PHP minus the B flag
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BreakpointId(u32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AddressSpace {
+    // $0000-$FFFF as seen by the CPU
+    Cpu,
+    // $0000-$3FFF as seen by the PPU, through PPUDATA
+    Ppu,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BreakReason {
+    Breakpoint {
+        id: BreakpointId,
+        pc: u16,
+    },
+    Watchpoint {
+        id: BreakpointId,
+        space: AddressSpace,
+        access: Access,
+        address: u16,
+        value: u8,
+    },
+    // Stops at the first instruction of the handler
+    Interrupt(Interrupt),
+    Step,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Execute(u16),
+    Watch {
+        space: AddressSpace,
+        range: RangeInclusive<u16>,
+        access: Access,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Breakpoint {
+    id: BreakpointId,
+    kind: Kind,
+    condition: Option<Expression>,
+    enabled: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Step {
+    // Stops before the next instruction
+    Into,
+    // Stops once PC and SP are back at the instruction after a JSR
+    Over { pc: u16, sp: u8 },
+    // Stops once the stack unwinds above the given SP
+    Out { sp: u8 },
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: u32,
+    break_on_nmi: bool,
+    break_on_irq: bool,
+    break_on_brk: bool,
+    step: Option<Step>,
+    break_reason: Option<BreakReason>,
+    // Execution resumes here without stopping on the breakpoint it left from
+    resume_pc: Option<u16>,
+    // Registers at the start of the current instruction, for conditions of
+    // watchpoints hit while it runs
+    registers: Registers,
+    // Symbols for disassembly
+    labels: Labels,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    // Stops before executing the instruction at `pc`, when `condition` holds
+    pub fn add_breakpoint(
+        &mut self,
+        pc: u16,
+        condition: Option<&str>,
+    ) -> Result<BreakpointId, ExpressionError> {
+        self.add(Kind::Execute(pc), condition)
+    }
+
+    // Stops after an instruction accessed `range` in `space`, when
+    // `condition` holds. Conditions see the registers from before the
+    // instruction started
+    pub fn add_watchpoint(
+        &mut self,
+        space: AddressSpace,
+        range: RangeInclusive<u16>,
+        access: Access,
+        condition: Option<&str>,
+    ) -> Result<BreakpointId, ExpressionError> {
+        self.add(
+            Kind::Watch {
+                space,
+                range,
+                access,
+            },
+            condition,
+        )
+    }
+
+    fn add(
+        &mut self,
+        kind: Kind,
+        condition: Option<&str>,
+    ) -> Result<BreakpointId, ExpressionError> {
+        let condition = condition.map(Expression::parse).transpose()?;
+        let id = BreakpointId(self.next_id);
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            kind,
+            condition,
+            enabled: true,
+        });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn set_enabled(&mut self, id: BreakpointId, enabled: bool) -> bool {
+        match self.breakpoints.iter_mut().find(|b| b.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn break_on_interrupt(&mut self, interrupt: Interrupt, enabled: bool) {
+        match interrupt {
+            Interrupt::Nmi => self.break_on_nmi = enabled,
+            Interrupt::Irq => self.break_on_irq = enabled,
+            Interrupt::Brk => self.break_on_brk = enabled,
+        }
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    pub fn labels_mut(&mut self) -> &mut Labels {
+        &mut self.labels
+    }
+
+    // Why the machine last stopped, cleared when it runs again
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.break_reason
+    }
+
+    pub fn step_into(&mut self) {
+        self.step = Some(Step::Into);
+    }
+
+    // Like step_into, except that a JSR runs until its subroutine returns
+    pub fn step_over(&mut self, registers: Registers, opcode: u8) {
+        self.step = Some(if opcode == JSR {
+            Step::Over {
+                pc: registers.pc.wrapping_add(3),
+                sp: registers.sp,
+            }
+        } else {
+            Step::Into
+        });
+    }
+
+    // Runs until the current subroutine returns
+    pub fn step_out(&mut self, registers: Registers) {
+        self.step = Some(Step::Out { sp: registers.sp });
+    }
+
+    // Clears the last break before the machine runs again from `pc`
+    pub(crate) fn resume(&mut self, pc: u16) {
+        self.break_reason = None;
+        self.resume_pc = Some(pc);
+    }
+
+    // Called before every instruction, returns whether to stop before it
+    pub(crate) fn check_instruction(&mut self, registers: Registers) -> bool {
+        self.registers = registers;
+        let pc = registers.pc;
+
+        let step = match self.step {
+            Some(Step::Into) => self.resume_pc != Some(pc),
+            Some(Step::Over { pc: target, sp }) => pc == target && registers.sp == sp,
+            Some(Step::Out { sp }) => registers.sp > sp,
+            None => false,
+        };
+        if step {
+            self.step = None;
+            return self.stop(BreakReason::Step, pc);
+        }
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+
+        let context = Context {
+            registers,
+            ..Context::default()
+        };
+        let hit = self.breakpoints.iter().find(|b| {
+            b.enabled
+                && matches!(b.kind, Kind::Execute(address) if address == pc)
+                && b.condition.as_ref().is_none_or(|c| c.evaluate(&context))
+        });
+        match hit {
+            Some(breakpoint) => {
+                let reason = BreakReason::Breakpoint {
+                    id: breakpoint.id,
+                    pc,
+                };
+                self.stop(reason, pc)
+            }
+            None => false,
+        }
+    }
+
+    fn stop(&mut self, reason: BreakReason, pc: u16) -> bool {
+        self.break_reason = Some(reason);
+        self.resume_pc = Some(pc);
+        true
+    }
+
+    pub(crate) fn check_access(
+        &mut self,
+        space: AddressSpace,
+        access: Access,
+        address: u16,
+        value: u8,
+    ) {
+        if self.break_reason.is_some() {
+            return;
+        }
+        let context = Context {
+            registers: self.registers,
+            address,
+            value,
+        };
+        let hit = self.breakpoints.iter().find(|b| {
+            b.enabled
+                && matches!(&b.kind, Kind::Watch { space: s, range, access: a }
+                    if *s == space && range.contains(&address) && a.matches(access))
+                && b.condition.as_ref().is_none_or(|c| c.evaluate(&context))
+        });
+        if let Some(breakpoint) = hit {
+            self.break_reason = Some(BreakReason::Watchpoint {
+                id: breakpoint.id,
+                space,
+                access,
+                address,
+                value,
+            });
+        }
+    }
+
+    pub(crate) fn check_interrupt(&mut self, interrupt: Interrupt) {
+        let enabled = match interrupt {
+            Interrupt::Nmi => self.break_on_nmi,
+            Interrupt::Irq => self.break_on_irq,
+            Interrupt::Brk => self.break_on_brk,
+        };
+        if enabled && self.break_reason.is_none() {
+            self.break_reason = Some(BreakReason::Interrupt(interrupt));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    #[test]
+    fn test_debugger() {
+        let mut program = vec![
+            0xA2, 0x00, // LDX #$00
+            0x20, 0x10, 0x80, // JSR $8010
+            0xE8, // INX
+            0x8E, 0x00, 0x02, // STX $0200
+            0x4C, 0x02, 0x80, // JMP $8002
+        ];
+        program.resize(0x10, 0xEA);
+        program.extend_from_slice(&[
+            0xA9, 0x10, // LDA #$10
+            0x85, 0x00, // STA $00
+            0x60, // RTS
+        ]);
+        let mut nes = Nes::from_rom(&nrom(&program)).unwrap();
+        nes.enable_debugger();
+        let debugger = nes.debugger_mut().unwrap();
+        let id = debugger.add_breakpoint(0x8010, Some("X >= 2")).unwrap();
+        assert!(debugger.add_breakpoint(0x8010, Some("X >=")).is_err());
+
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), 0);
+        let reason = nes.debugger().unwrap().break_reason();
+        assert_eq!(reason, Some(BreakReason::Breakpoint { id, pc: 0x8010 }));
+        assert_eq!(nes.cpu_registers().x, 2);
+
+        nes.step_instruction();
+        assert_eq!(nes.cpu_registers().pc, 0x8012);
+        nes.step_out();
+        assert_eq!(
+            nes.debugger().unwrap().break_reason(),
+            Some(BreakReason::Step)
+        );
+        assert_eq!(nes.cpu_registers().pc, 0x8005);
+        nes.step_over();
+        assert_eq!(nes.cpu_registers().pc, 0x8006);
+
+        let debugger = nes.debugger_mut().unwrap();
+        debugger.remove(id);
+        let id = debugger
+            .add_watchpoint(AddressSpace::Cpu, 0x0200..=0x0200, Access::Write, None)
+            .unwrap();
+        nes.run_frame();
+        assert_eq!(nes.cpu_registers().pc, 0x8009);
+        assert_eq!(
+            nes.debugger().unwrap().break_reason(),
+            Some(BreakReason::Watchpoint {
+                id,
+                space: AddressSpace::Cpu,
+                access: Access::Write,
+                address: 0x0200,
+                value: 3,
+            })
+        );
+
+        nes.debugger_mut().unwrap().clear();
+        nes.step_instruction();
+        nes.cpu.bus.write(0x0000, 0);
+        nes.step_over();
+        assert_eq!(nes.cpu_registers().pc, 0x8005);
+        assert_eq!(nes.cpu.bus.read(0x0000), 0x10);
+
+        let debugger = nes.debugger_mut().unwrap();
+        let id = debugger
+            .add_watchpoint(AddressSpace::Ppu, 0x2000..=0x23FF, Access::ReadWrite, None)
+            .unwrap();
+        debugger.break_on_interrupt(Interrupt::Nmi, true);
+        debugger.resume(0x8005);
+        nes.cpu.bus.write(0x2006, 0x21);
+        nes.cpu.bus.write(0x2006, 0x00);
+        nes.cpu.bus.write(0x2007, 0x55);
+        assert_eq!(
+            nes.debugger().unwrap().break_reason(),
+            Some(BreakReason::Watchpoint {
+                id,
+                space: AddressSpace::Ppu,
+                access: Access::Write,
+                address: 0x2100,
+                value: 0x55,
+            })
+        );
+
+        nes.cpu.bus.write(0x2000, 0x80);
+        nes.run_frame();
+        assert_eq!(
+            nes.debugger().unwrap().break_reason(),
+            Some(BreakReason::Interrupt(Interrupt::Nmi))
+        );
+        assert_eq!(nes.cpu_registers().pc, 0x8000);
+        nes.disable_debugger();
+        nes.run_frame();
+        assert!(nes.frame_count() >= 1);
+    }
+}
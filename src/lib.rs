@@ -2,7 +2,45 @@
 extern crate bitfield;
 mod apu;
 mod bus;
+mod capi;
 mod cartridge;
+mod cdl;
+mod cheat;
 mod controller;
 mod cpu;
+mod crc32;
+mod debugger;
+mod libretro;
+mod md5;
+mod memory;
+mod movie;
+mod nes;
 mod ppu;
+mod ram_search;
+mod rewind;
+pub mod save_file;
+mod state;
+mod test_rom;
+#[cfg(test)]
+mod test_util;
+mod trace;
+
+pub use crate::cartridge::{
+    CartridgeError, ConsoleType, Format, Header, Mirroring, SaveRamError, Timing,
+};
+pub use crate::cdl::{CdlError, CodeDataLogger};
+pub use crate::cheat::{parse_cht, to_cht, Cheat, CheatError, CheatKind};
+pub use crate::controller::Button;
+pub use crate::crc32::crc32;
+pub use crate::debugger::{
+    Access, AddressSpace, AddressingMode, BreakReason, BreakpointId, Debugger, ExpressionError,
+    Instruction, Interrupt, LabelError, Labels, Registers,
+};
+pub use crate::memory::{MemorySpace, MemoryView};
+pub use crate::movie::{FrameInput, Movie, MovieError, MovieMode, COMMAND_POWER, COMMAND_RESET};
+pub use crate::nes::{LoadError, Nes};
+pub use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::ram_search::{Candidate, Comparison, Operand, RamSearch, ValueSize};
+pub use crate::state::{StateError, STATE_VERSION};
+pub use crate::test_rom::{run_test_rom, TestOutcome, TestResult};
+pub use crate::trace::{TraceFormat, Tracer};
@@ -0,0 +1,622 @@
+// libretro core, so frontends like RetroArch can load the cdylib
+// https://github.com/libretro/libretro-common/blob/master/include/libretro.h
+//
+// libretro cores are singletons driven from the frontend's main thread, so
+// the core lives in a thread local. Video is the PPU frame buffer as
+// XRGB8888, audio the APU's mono samples copied to both channels, and the
+// save RAM the cartridge's battery-backed PRG RAM, which the frontend reads
+// and writes in place.
+//
+// Panics never unwind into the frontend: every entry point doing more than
+// returning a constant catches them, logs them and unloads the game, since
+// the machine may have been left half updated. Frontend callbacks are only
+// called while the core isn't borrowed, so they can call back into it.
+
+use crate::cheat::Cheat;
+use crate::controller::Button;
+use crate::nes::Nes;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use log::{error, warn};
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+const RETRO_REGION_NTSC: c_uint = 0;
+
+const SAMPLE_RATE: u32 = 44_100;
+// CPU clock over CPU cycles per frame
+const FRAME_RATE: f64 = 1_789_773.0 / 29_780.5;
+
+// RETRO_DEVICE_ID_JOYPAD_* to controller buttons
+const JOYPAD_BUTTONS: [(c_uint, Button); 8] = [
+    (0, Button::B),
+    (2, Button::Select),
+    (3, Button::Start),
+    (4, Button::Up),
+    (5, Button::Down),
+    (6, Button::Left),
+    (7, Button::Right),
+    (8, Button::A),
+];
+
+type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = extern "C" fn();
+type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[derive(Default)]
+struct Core {
+    nes: Option<Nes>,
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+thread_local! {
+    static CORE: RefCell<Core> = RefCell::new(Core::default());
+}
+
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> T {
+    CORE.with(|core| f(&mut core.borrow_mut()))
+}
+
+// Runs an entry point, returning `default` and unloading the game if it
+// panics
+fn guard<T>(default: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            error!("the core panicked, unloading the game");
+            // The borrow, if any, was released while unwinding
+            CORE.with(|core| {
+                if let Ok(mut core) = core.try_borrow_mut() {
+                    core.nes = None;
+                }
+            });
+            default
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    guard((), || with_core(|core| core.environment = Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    guard((), || with_core(|core| core.video_refresh = Some(callback)));
+}
+
+// Audio is only sent in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    guard((), || {
+        with_core(|core| core.audio_sample_batch = Some(callback))
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    guard((), || with_core(|core| core.input_poll = Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    guard((), || with_core(|core| core.input_state = Some(callback)));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    guard((), || with_core(|core| *core = Core::default()));
+}
+
+/// # Safety
+/// `info` must point to a writable retro_system_info.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    guard((), || {
+        if let Some(info) = info.as_mut() {
+            *info = RetroSystemInfo {
+                library_name: c"saka-nes-simulator".as_ptr(),
+                library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+                valid_extensions: c"nes".as_ptr(),
+                need_fullpath: false,
+                block_extract: false,
+            };
+        }
+    });
+}
+
+/// # Safety
+/// `info` must point to a writable retro_system_av_info.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    guard((), || {
+        if let Some(info) = info.as_mut() {
+            *info = RetroSystemAvInfo {
+                geometry: RetroGameGeometry {
+                    base_width: SCREEN_WIDTH as c_uint,
+                    base_height: SCREEN_HEIGHT as c_uint,
+                    max_width: SCREEN_WIDTH as c_uint,
+                    max_height: SCREEN_HEIGHT as c_uint,
+                    aspect_ratio: 4.0 / 3.0,
+                },
+                timing: RetroSystemTiming {
+                    fps: FRAME_RATE,
+                    sample_rate: SAMPLE_RATE as f64,
+                },
+            };
+        }
+    });
+}
+
+// Only standard controllers are emulated
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+/// # Safety
+/// `game` must point to a retro_game_info whose data holds `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+    let rom = slice::from_raw_parts(game.data as *const u8, game.size);
+    guard(false, || {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        let format = &mut format as *mut c_uint as *mut c_void;
+        match with_core(|core| core.environment) {
+            Some(environment) if environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, format) => (),
+            _ => {
+                error!("frontend doesn't support XRGB8888");
+                return false;
+            }
+        }
+        match Nes::from_rom(rom) {
+            Ok(mut nes) => {
+                nes.set_sample_rate(SAMPLE_RATE);
+                with_core(|core| core.nes = Some(nes));
+                true
+            }
+            Err(e) => {
+                error!("failed to load ROM: {}", e);
+                false
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const RetroGameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    guard((), || with_core(|core| core.nes = None));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    guard((), || {
+        with_core(|core| {
+            if let Some(nes) = &mut core.nes {
+                nes.reset();
+            }
+        })
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    guard((), run);
+}
+
+fn run() {
+    let (loaded, input_poll, input_state) =
+        with_core(|core| (core.nes.is_some(), core.input_poll, core.input_state));
+    if !loaded {
+        return;
+    }
+    if let Some(input_poll) = input_poll {
+        input_poll();
+    }
+    let buttons = input_state.map(|input_state| {
+        [0, 1].map(|port| {
+            JOYPAD_BUTTONS
+                .iter()
+                .filter(|&&(id, _)| input_state(port, RETRO_DEVICE_JOYPAD, 0, id) != 0)
+                .fold(0, |buttons, &(_, button)| buttons | button as u8)
+        })
+    });
+
+    // The frontend may have unloaded the game from a callback
+    let Some((frame, samples)) = with_core(|core| {
+        let nes = core.nes.as_mut()?;
+        if let Some(buttons) = buttons {
+            nes.set_buttons(0, buttons[0]);
+            nes.set_buttons(1, buttons[1]);
+        }
+        nes.run_frame();
+        Some((nes.frame_buffer().to_vec(), nes.audio_samples()))
+    }) else {
+        return;
+    };
+    let (video_refresh, audio_sample_batch) =
+        with_core(|core| (core.video_refresh, core.audio_sample_batch));
+
+    if let Some(video_refresh) = video_refresh {
+        video_refresh(
+            frame.as_ptr() as *const c_void,
+            SCREEN_WIDTH as c_uint,
+            SCREEN_HEIGHT as c_uint,
+            SCREEN_WIDTH * 4,
+        );
+    }
+    // Interleaved stereo. Whatever the frontend doesn't take is dropped
+    let audio: Vec<i16> = samples
+        .into_iter()
+        .flat_map(|sample| {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            [sample, sample]
+        })
+        .collect();
+    if let Some(audio_sample_batch) = audio_sample_batch {
+        let mut sent = 0;
+        while sent < audio.len() {
+            let frames = (audio.len() - sent) / 2;
+            let taken = audio_sample_batch(audio[sent..].as_ptr(), frames);
+            if taken == 0 {
+                break;
+            }
+            sent += taken.min(frames) * 2;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    guard(0, || {
+        with_core(|core| core.nes.as_ref().map_or(0, |nes| nes.save_state().len()))
+    })
+}
+
+/// # Safety
+/// `data` must have room for `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    guard(false, || {
+        let Some(state) = with_core(|core| core.nes.as_ref().map(Nes::save_state)) else {
+            return false;
+        };
+        if data.is_null() || size < state.len() {
+            return false;
+        }
+        slice::from_raw_parts_mut(data as *mut u8, state.len()).copy_from_slice(&state);
+        true
+    })
+}
+
+/// # Safety
+/// `data` must point to `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    guard(false, || {
+        with_core(|core| match &mut core.nes {
+            Some(nes) => match nes.load_state(state) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("failed to load state: {}", e);
+                    false
+                }
+            },
+            None => false,
+        })
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    guard((), || {
+        with_core(|core| {
+            if let Some(nes) = &mut core.nes {
+                nes.clear_cheats();
+            }
+        })
+    });
+}
+
+/// # Safety
+/// `code` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    if code.is_null() {
+        return;
+    }
+    let code = CStr::from_ptr(code).to_string_lossy();
+    guard((), || {
+        with_core(|core| {
+            let Some(nes) = &mut core.nes else {
+                return;
+            };
+            // Frontends join the codes of one cheat with '+'
+            for part in code.split('+') {
+                match Cheat::from_code(part) {
+                    Ok(cheat) => nes.add_cheat(Cheat {
+                        name: index.to_string(),
+                        enabled,
+                        ..cheat
+                    }),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        })
+    });
+}
+
+// Pointers into the loaded machine, for the frontend to use between calls
+// into the core. The machine stays in place in CORE, and loading a state or
+// a reset write its memory in place, so they stay valid until the game is
+// unloaded. Nes::power_cycle would rebuild the cartridge and move the save
+// RAM, which is why nothing here calls it
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    guard(ptr::null_mut(), || {
+        memory(id).map_or(ptr::null_mut(), |(data, _)| data as *mut c_void)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    guard(0, || memory(id).map_or(0, |(_, size)| size))
+}
+
+fn memory(id: c_uint) -> Option<(*mut u8, usize)> {
+    with_core(|core| {
+        let nes = core.nes.as_mut()?;
+        match id {
+            RETRO_MEMORY_SAVE_RAM => nes
+                .save_ram_mut()
+                .map(|mut ram| (ram.as_mut_ptr(), ram.len())),
+            RETRO_MEMORY_SYSTEM_RAM => {
+                let ram = nes.ram_mut();
+                Some((ram.as_mut_ptr(), ram.len()))
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::nrom;
+    use std::cell::Cell;
+
+    // What the harness frontend saw
+    #[derive(Default)]
+    struct Frontend {
+        frames: Cell<usize>,
+        audio_frames: Cell<usize>,
+        pixel: Cell<u32>,
+        polls: Cell<usize>,
+    }
+
+    thread_local! {
+        static FRONTEND: Frontend = Frontend::default();
+    }
+
+    extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        let format = unsafe { *(data as *const c_uint) };
+        cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT && format == RETRO_PIXEL_FORMAT_XRGB8888
+    }
+
+    extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert_eq!((width, height, pitch), (256, 240, 1024));
+        // Callbacks may call back into the core
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 0x800);
+        let pixel = unsafe { *(data as *const u32) };
+        FRONTEND.with(|f| {
+            f.frames.set(f.frames.get() + 1);
+            f.pixel.set(pixel);
+        });
+    }
+
+    extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+        // Takes at most 100 frames at a time, like a small ring buffer
+        let frames = frames.min(100);
+        FRONTEND.with(|f| f.audio_frames.set(f.audio_frames.get() + frames));
+        frames
+    }
+
+    extern "C" fn input_poll() {
+        FRONTEND.with(|f| f.polls.set(f.polls.get() + 1));
+    }
+
+    extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        assert_eq!(device, RETRO_DEVICE_JOYPAD);
+        (port == 0 && id == 8) as i16
+    }
+
+    // Battery-backed NROM reading the A button into $11 and writing $42 to
+    // $10 and $6000
+    fn build_rom() -> Vec<u8> {
+        let mut rom = nrom(&[
+            0xA9, 0x01, 0x8D, 0x16, 0x40, // strobe the controllers
+            0xA9, 0x00, 0x8D, 0x16, 0x40, //
+            0xAD, 0x16, 0x40, 0x29, 0x01, 0x85, 0x11, // A button to $11
+            0xA9, 0x42, 0x8D, 0x00, 0x60, 0x85, 0x10, // $42 to $6000 and $10
+            0x4C, 0x00, 0x80, // JMP $8000
+        ]);
+        rom[6] |= 0x02;
+        rom
+    }
+
+    fn load(rom: &[u8]) -> bool {
+        let game = RetroGameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe { retro_load_game(&game) }
+    }
+
+    #[test]
+    fn test_core() {
+        assert_eq!(retro_api_version(), 1);
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+
+        let mut info = RetroSystemAvInfo {
+            geometry: RetroGameGeometry {
+                base_width: 0,
+                base_height: 0,
+                max_width: 0,
+                max_height: 0,
+                aspect_ratio: 0.0,
+            },
+            timing: RetroSystemTiming {
+                fps: 0.0,
+                sample_rate: 0.0,
+            },
+        };
+        unsafe { retro_get_system_av_info(&mut info) };
+        assert_eq!(info.geometry.base_width, 256);
+        assert_eq!(info.timing.sample_rate, 44100.0);
+
+        assert!(!load(&[0; 16]));
+        assert!(load(&build_rom()));
+        for _ in 0..3 {
+            retro_run();
+        }
+        FRONTEND.with(|f| {
+            assert_eq!((f.frames.get(), f.polls.get()), (3, 3));
+            assert!(f.audio_frames.get() > 3 * 700);
+            assert_eq!(f.pixel.get() >> 24, 0);
+        });
+
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0x2000);
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 0x800);
+        let ram = retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *mut u8;
+        let save_ram = retro_get_memory_data(RETRO_MEMORY_SAVE_RAM) as *mut u8;
+        unsafe {
+            assert_eq!((*ram.add(0x10), *ram.add(0x11)), (0x42, 1));
+            assert_eq!(*save_ram, 0x42);
+        }
+
+        let mut state = vec![0u8; retro_serialize_size()];
+        unsafe {
+            assert!(retro_serialize(
+                state.as_mut_ptr() as *mut c_void,
+                state.len()
+            ));
+            *ram.add(0x20) = 0x99;
+            assert!(retro_unserialize(
+                state.as_ptr() as *const c_void,
+                state.len()
+            ));
+            assert_eq!(*ram.add(0x20), 0);
+            assert!(!retro_unserialize(state.as_ptr() as *const c_void, 4));
+        }
+
+        // A freeze code
+        unsafe { retro_cheat_set(0, true, c"0020:77".as_ptr()) };
+        retro_run();
+        assert_eq!(unsafe { *ram.add(0x20) }, 0x77);
+
+        // A panic unloads the game instead of unwinding into the frontend
+        assert_eq!(guard(0, || panic!("test panic")), 0);
+        assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+        retro_run();
+        assert!(load(&build_rom()));
+
+        retro_unload_game();
+        assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+        retro_deinit();
+    }
+}
@@ -0,0 +1,171 @@
+// Memory access for tools like hex viewers and cheat searches
+//
+// Peeks never have side effects: PPU and APU registers, the PPUDATA read
+// buffer and the code/data logger are left alone, and the CPU bus reads its
+// I/O registers as 0. Pokes write memory directly, so they never reach mapper
+// registers either. Pokes into ROM last until the next power cycle.
+
+use crate::bus::SystemBus;
+
+const CPU_BUS_SIZE: usize = 0x10000;
+const PPU_BUS_SIZE: usize = 0x4000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemorySpace {
+    // $0000-$FFFF as seen by the CPU
+    CpuBus,
+    // $0000-$3FFF as seen by the PPU
+    PpuBus,
+    Oam,
+    Palette,
+    // Whole images, by offset rather than by mapped address
+    PrgRom,
+    // CHR ROM, or CHR RAM for boards without ROM
+    Chr,
+    PrgRam,
+    // The console's 2KB of RAM
+    InternalRam,
+}
+
+pub struct MemoryView<'a> {
+    bus: &'a mut SystemBus,
+}
+
+impl<'a> MemoryView<'a> {
+    pub(crate) fn new(bus: &'a mut SystemBus) -> Self {
+        MemoryView { bus }
+    }
+
+    pub fn size(&self, space: MemorySpace) -> usize {
+        let cartridge = self.bus.cartridge().map(|c| c.borrow());
+        match space {
+            MemorySpace::CpuBus => CPU_BUS_SIZE,
+            MemorySpace::PpuBus => PPU_BUS_SIZE,
+            MemorySpace::Oam => self.bus.ppu.oam().len(),
+            MemorySpace::Palette => self.bus.ppu.palette().len(),
+            MemorySpace::PrgRom => cartridge.map_or(0, |c| c.prg_rom().len()),
+            MemorySpace::Chr => cartridge.map_or(0, |c| c.chr().len()),
+            MemorySpace::PrgRam => cartridge.map_or(0, |c| c.prg_ram().len()),
+            MemorySpace::InternalRam => self.bus.ram().len(),
+        }
+    }
+
+    // Reads 0 outside the space
+    pub fn peek(&self, space: MemorySpace, address: usize) -> u8 {
+        if address >= self.size(space) {
+            return 0;
+        }
+        let cartridge = self.bus.cartridge().map(|c| c.borrow());
+        match space {
+            MemorySpace::CpuBus => self.bus.peek(address as u16),
+            MemorySpace::PpuBus => self.bus.ppu.peek_vram(address as u16),
+            MemorySpace::Oam => self.bus.ppu.oam()[address],
+            MemorySpace::Palette => self.bus.ppu.palette()[address],
+            MemorySpace::PrgRom => cartridge.map_or(0, |c| c.prg_rom()[address]),
+            MemorySpace::Chr => cartridge.map_or(0, |c| c.chr()[address]),
+            MemorySpace::PrgRam => cartridge.map_or(0, |c| c.prg_ram()[address]),
+            MemorySpace::InternalRam => self.bus.ram()[address],
+        }
+    }
+
+    // Ignored outside the space and for CPU bus I/O registers
+    pub fn poke(&mut self, space: MemorySpace, address: usize, value: u8) {
+        if address >= self.size(space) {
+            return;
+        }
+        let cartridge = self.bus.cartridge().cloned();
+        let mut cartridge = cartridge.as_ref().map(|c| c.borrow_mut());
+        match (space, cartridge.as_mut()) {
+            (MemorySpace::CpuBus, cartridge) => match (address as u16, cartridge) {
+                (0x0000..=0x1FFF, _) => self.bus.ram_mut()[address % 0x800] = value,
+                // No mapper has registers below $8000
+                (address @ 0x4020..=0x7FFF, Some(c)) => c.write_prg_byte(address, value),
+                (address @ 0x8000..=0xFFFF, Some(c)) => {
+                    if let Some(offset) = c.prg_rom_offset(address) {
+                        c.prg_rom_mut()[offset] = value;
+                    }
+                }
+                _ => (),
+            },
+            (MemorySpace::PpuBus, _) => {
+                drop(cartridge);
+                self.bus.ppu.poke_vram(address as u16, value);
+            }
+            (MemorySpace::Oam, _) => self.bus.ppu.oam_mut()[address] = value,
+            (MemorySpace::Palette, _) => self.bus.ppu.palette_mut()[address] = value,
+            (MemorySpace::PrgRom, Some(c)) => c.prg_rom_mut()[address] = value,
+            (MemorySpace::Chr, Some(c)) => c.chr_mut()[address] = value,
+            (MemorySpace::PrgRam, Some(c)) => c.prg_ram_mut()[address] = value,
+            (MemorySpace::InternalRam, _) => self.bus.ram_mut()[address] = value,
+            (_, None) => (),
+        }
+    }
+
+    // The whole space, for viewers and searches
+    pub fn dump(&self, space: MemorySpace) -> Vec<u8> {
+        (0..self.size(space))
+            .map(|address| self.peek(space, address))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    // NROM image with recognizable PRG and CHR contents
+    fn build_rom() -> Vec<u8> {
+        let mut rom = nrom(&[0x4C, 0x00, 0x80]);
+        for (i, byte) in rom[16 + 0x4000..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_peek_and_poke() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        let mut memory = nes.memory();
+        assert_eq!(memory.size(MemorySpace::PrgRom), 0x4000);
+        assert_eq!(memory.size(MemorySpace::Chr), 0x2000);
+        assert_eq!(memory.peek(MemorySpace::CpuBus, 0xC000), 0x4C);
+        assert_eq!(memory.peek(MemorySpace::PpuBus, 0x0123), 0x23);
+        assert_eq!(memory.peek(MemorySpace::Oam, 0x100), 0);
+
+        memory.poke(MemorySpace::CpuBus, 0x0802, 0x11);
+        assert_eq!(memory.peek(MemorySpace::InternalRam, 2), 0x11);
+        memory.poke(MemorySpace::CpuBus, 0x6001, 0x22);
+        assert_eq!(memory.peek(MemorySpace::PrgRam, 1), 0x22);
+        memory.poke(MemorySpace::CpuBus, 0xC001, 0x33);
+        assert_eq!(memory.peek(MemorySpace::PrgRom, 1), 0x33);
+        memory.poke(MemorySpace::PpuBus, 0x0004, 0x44);
+        assert_eq!(memory.peek(MemorySpace::Chr, 4), 0x44);
+        memory.poke(MemorySpace::PpuBus, 0x3F10, 0x0F);
+        assert_eq!(memory.peek(MemorySpace::Palette, 0), 0x0F);
+        memory.poke(MemorySpace::PpuBus, 0x2401, 0x55);
+        assert_eq!(memory.peek(MemorySpace::PpuBus, 0x2401), 0x55);
+        memory.poke(MemorySpace::Oam, 3, 0x66);
+
+        let dump = memory.dump(MemorySpace::Oam);
+        assert_eq!(dump.len(), 0x100);
+        assert_eq!(dump[3], 0x66);
+        assert_eq!(memory.dump(MemorySpace::CpuBus).len(), 0x10000);
+    }
+
+    #[test]
+    fn test_no_side_effects() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        nes.run_frame();
+        nes.enable_code_data_logger();
+        let state = nes.save_state();
+        let memory = nes.memory();
+        for space in [MemorySpace::CpuBus, MemorySpace::PpuBus] {
+            memory.dump(space);
+        }
+        assert_eq!(nes.save_state(), state);
+        let cdl = nes.code_data_logger().unwrap();
+        assert!(cdl.to_bytes().iter().all(|&flags| flags == 0));
+    }
+}
@@ -0,0 +1,463 @@
+// Input movies in FCEUX's FM2 text format
+// http://fceux.com/web/FM2.html
+//
+// A movie is a header of "key value" lines followed by one line per frame:
+//
+//   |commands|RLDUTSBA|RLDUTSBA||
+//
+// where a button is held unless its column is '.' or ' '. Movies start from
+// power on. On top of the standard keys, "ramHash <frame> <crc32>" lines
+// record the CRC-32 of the internal RAM every HASH_INTERVAL frames so a
+// playback that drifts from the recording is noticed; FCEUX ignores them.
+
+use crate::crc32::crc32;
+use std::fmt;
+use std::fmt::Write;
+
+pub const HASH_INTERVAL: u64 = 60;
+
+// Bits of the commands column
+pub const COMMAND_RESET: u8 = 0x01;
+pub const COMMAND_POWER: u8 = 0x02;
+
+// Column order of a gamepad in an input line, from bit 7 down to bit 0 of
+// the controller's button byte
+const BUTTON_CHARACTERS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieError {
+    Parse { line: usize, reason: String },
+    Unsupported(&'static str),
+    // The movie was recorded with a different ROM
+    RomMismatch,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, reason } => write!(f, "line {}: {}", line, reason),
+            MovieError::Unsupported(feature) => write!(f, "unsupported movie feature: {}", feature),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FrameInput {
+    // COMMAND_* bits applied before the frame runs
+    pub commands: u8,
+    pub buttons: [u8; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub rom_filename: String,
+    // MD5 of the PRG and CHR ROM
+    pub rom_checksum: [u8; 16],
+    pub rerecord_count: u32,
+    pub comments: Vec<String>,
+    pub frames: Vec<FrameInput>,
+    // (frames played, CRC-32 of the internal RAM)
+    pub ram_hashes: Vec<(u64, u32)>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom_checksum: [u8; 16]) -> Self {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            frames: Vec::new(),
+            ram_hashes: Vec::new(),
+        }
+    }
+
+    pub fn parse_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new("", [0; 16]);
+        let mut ports = [true, true];
+        let mut has_checksum = false;
+
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            let error = |reason: &str| MovieError::Parse {
+                line: number,
+                reason: reason.to_string(),
+            };
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                movie.frames.push(parse_input(line, ports).map_err(error)?);
+                continue;
+            }
+            if line.trim().is_empty() || !movie.frames.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number_value = || value.trim().parse::<u64>().map_err(|_| error("bad number"));
+            match key {
+                "version" if number_value()? != 3 => {
+                    return Err(MovieError::Unsupported("FM2 versions other than 3"))
+                }
+                "binary" if number_value()? != 0 => {
+                    return Err(MovieError::Unsupported("binary input logs"))
+                }
+                "fourscore" if number_value()? != 0 => {
+                    return Err(MovieError::Unsupported("Four Score"))
+                }
+                "FDS" if number_value()? != 0 => return Err(MovieError::Unsupported("FDS")),
+                "port0" | "port1" => {
+                    let port = (key == "port1") as usize;
+                    ports[port] = match number_value()? {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(MovieError::Unsupported("devices other than gamepads")),
+                    };
+                }
+                "port2" if number_value()? != 0 => {
+                    return Err(MovieError::Unsupported("Famicom expansion port devices"))
+                }
+                "rerecordCount" => movie.rerecord_count = number_value()? as u32,
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let encoded = value
+                        .strip_prefix("base64:")
+                        .ok_or_else(|| error("checksum is not base64"))?;
+                    movie.rom_checksum = base64_decode(encoded)
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .ok_or_else(|| error("bad checksum"))?;
+                    has_checksum = true;
+                }
+                "comment" => movie.comments.push(value.to_string()),
+                "ramHash" => {
+                    let (frame, hash) = value
+                        .split_once(' ')
+                        .and_then(|(f, h)| {
+                            Some((f.parse().ok()?, u32::from_str_radix(h, 16).ok()?))
+                        })
+                        .ok_or_else(|| error("bad RAM hash"))?;
+                    movie.ram_hashes.push((frame, hash));
+                }
+                _ => (),
+            }
+        }
+
+        if !has_checksum {
+            return Err(MovieError::Parse {
+                line: 0,
+                reason: "missing romChecksum".to_string(),
+            });
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "version 3").unwrap();
+        writeln!(text, "rerecordCount {}", self.rerecord_count).unwrap();
+        writeln!(text, "palFlag 0").unwrap();
+        writeln!(text, "romFilename {}", self.rom_filename).unwrap();
+        writeln!(
+            text,
+            "romChecksum base64:{}",
+            base64_encode(&self.rom_checksum)
+        )
+        .unwrap();
+        writeln!(text, "fourscore 0").unwrap();
+        writeln!(text, "microphone 0").unwrap();
+        writeln!(text, "port0 1").unwrap();
+        writeln!(text, "port1 1").unwrap();
+        writeln!(text, "port2 0").unwrap();
+        writeln!(text, "FDS 0").unwrap();
+        writeln!(text, "NewPPU 0").unwrap();
+        for comment in &self.comments {
+            writeln!(text, "comment {}", comment).unwrap();
+        }
+        for (frame, hash) in &self.ram_hashes {
+            writeln!(text, "ramHash {} {:08X}", frame, hash).unwrap();
+        }
+        for input in &self.frames {
+            text.push('|');
+            write!(text, "{}", input.commands).unwrap();
+            for buttons in input.buttons {
+                text.push('|');
+                for (bit, &c) in BUTTON_CHARACTERS.iter().enumerate() {
+                    let pressed = buttons & (0x80 >> bit) != 0;
+                    text.push(if pressed { c as char } else { '.' });
+                }
+            }
+            text.push_str("||\n");
+        }
+        text
+    }
+}
+
+fn parse_input(line: &str, ports: [bool; 2]) -> Result<FrameInput, &'static str> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields
+        .next()
+        .and_then(|c| c.trim().parse().ok())
+        .ok_or("bad commands column")?;
+    let mut input = FrameInput {
+        commands,
+        buttons: [0; 2],
+    };
+    for (port, connected) in ports.iter().enumerate() {
+        let field = fields.next().ok_or("missing port column")?;
+        if !connected {
+            continue;
+        }
+        if field.len() != BUTTON_CHARACTERS.len() {
+            return Err("gamepad column must be 8 characters");
+        }
+        for (bit, c) in field.bytes().enumerate() {
+            if c != b'.' && c != b' ' {
+                input.buttons[port] |= 0x80 >> bit;
+            }
+        }
+    }
+    Ok(input)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().trim_end_matches('=');
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+}
+
+// A movie attached to a running machine
+pub struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    // Frames recorded or played so far
+    pub frame: usize,
+    // Commands issued since the last recorded frame
+    pub pending_commands: u8,
+    // First frame whose RAM hash didn't match the recording
+    pub desync: Option<u64>,
+}
+
+impl MovieSession {
+    pub fn new(movie: Movie, mode: MovieMode) -> Self {
+        MovieSession {
+            movie,
+            mode,
+            frame: 0,
+            pending_commands: 0,
+            desync: None,
+        }
+    }
+
+    // Records a reset or power cycle into the next frame
+    pub fn record_command(&mut self, command: u8) {
+        if self.mode == MovieMode::Recording {
+            self.pending_commands |= command;
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.mode == MovieMode::Playing && self.frame >= self.movie.frames.len()
+    }
+
+    // Called after every frame with the internal RAM
+    pub fn check_ram_hash(&mut self, ram: &[u8]) {
+        let frame = self.frame as u64;
+        if !frame.is_multiple_of(HASH_INTERVAL) {
+            return;
+        }
+        let hash = crc32(ram);
+        match self.mode {
+            MovieMode::Recording => self.movie.ram_hashes.push((frame, hash)),
+            MovieMode::Playing => {
+                let expected = self.movie.ram_hashes.iter().find(|&&(f, _)| f == frame);
+                if self.desync.is_none() && expected.is_some_and(|&(_, h)| h != hash) {
+                    self.desync = Some(frame);
+                }
+            }
+        }
+    }
+
+    // Goes back to an earlier frame. While recording everything after it is
+    // dropped and the rerecord counter goes up
+    pub fn rewind_to(&mut self, frame: usize) {
+        self.frame = frame;
+        self.desync = self.desync.filter(|&f| f <= frame as u64);
+        if self.mode == MovieMode::Recording {
+            self.movie.frames.truncate(frame);
+            self.movie.ram_hashes.retain(|&(f, _)| f <= frame as u64);
+            self.movie.rerecord_count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Button;
+    use crate::nes::Nes;
+    use crate::test_util::{nrom, COUNT_A_PRESSES};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_encode(b"M"), "TQ==");
+        assert_eq!(base64_decode("TWE=").unwrap(), b"Ma");
+        assert_eq!(base64_decode("TQ==").unwrap(), b"M");
+        assert_eq!(base64_decode("T*=="), None);
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let mut movie = Movie::new("Super Mario Bros. (World).nes", [0xAB; 16]);
+        movie.rerecord_count = 12;
+        movie.comments.push("author someone".to_string());
+        movie.frames.push(FrameInput {
+            commands: COMMAND_POWER,
+            buttons: [0, 0],
+        });
+        movie.frames.push(FrameInput {
+            commands: 0,
+            buttons: [0b1000_0001, 0b0000_1000],
+        });
+        movie.ram_hashes.push((60, 0xDEADBEEF));
+
+        let text = movie.to_fm2();
+        assert!(text.contains("romChecksum base64:q6urq6urq6urq6urq6urqw==\n"));
+        assert!(text.ends_with("|2|........|........||\n|0|R......A|....T...||\n"));
+        assert_eq!(Movie::parse_fm2(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_parse_fceux_movie() {
+        let text = "version 3\nemuVersion 22020\nrerecordCount 3\npalFlag 0\n\
+                    romFilename smb\nromChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                    guid 5A1E7D13-3B73-4A2A-A2D2-0E4B1B5BDB72\nfourscore 0\n\
+                    microphone 0\nport0 1\nport1 0\nport2 0\nFDS 0\nNewPPU 0\n\
+                    |1|...U.S.A|||\n|0|   U    |||\n";
+        let movie = Movie::parse_fm2(text).unwrap();
+        assert_eq!(movie.rerecord_count, 3);
+        assert_eq!(movie.rom_checksum[0], 0x8E);
+        assert_eq!(
+            movie.frames,
+            vec![
+                FrameInput {
+                    commands: 1,
+                    buttons: [0b0001_0101, 0]
+                },
+                FrameInput {
+                    commands: 0,
+                    buttons: [0b0001_0000, 0]
+                },
+            ]
+        );
+
+        assert_eq!(
+            Movie::parse_fm2("version 3\nfourscore 1\n"),
+            Err(MovieError::Unsupported("Four Score"))
+        );
+        assert!(matches!(
+            Movie::parse_fm2("version 3\nromChecksum base64:AAAA\n"),
+            Err(MovieError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_movie() {
+        let rom = nrom(&COUNT_A_PRESSES);
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        nes.run_frame();
+        nes.set_buttons(0, Button::A as u8);
+        nes.enable_rewind(1, 1 << 20);
+        nes.start_recording("count.nes");
+        for frame in 0..130 {
+            nes.set_buttons(0, if frame % 4 == 0 { Button::A as u8 } else { 0 });
+            if frame == 70 {
+                nes.reset();
+            }
+            nes.run_frame();
+        }
+        assert_eq!(nes.rewind_frames(10), 10);
+        for _ in 0..10 {
+            nes.run_frame();
+        }
+        let expected = (
+            nes.cycles(),
+            nes.cpu.bus.read(0x0000),
+            nes.cpu.bus.read(0x0001),
+        );
+        let movie = nes.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 130);
+        assert_eq!(movie.frames[70].commands, COMMAND_RESET);
+        assert_eq!(movie.rerecord_count, 1);
+        assert_eq!(movie.ram_hashes.len(), 2);
+
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        let movie = Movie::parse_fm2(&movie.to_fm2()).unwrap();
+        nes.play_movie(movie.clone()).unwrap();
+        while !nes.movie_finished() {
+            nes.run_frame();
+        }
+        assert_eq!(nes.movie_desync(), None);
+        let actual = (
+            nes.cycles(),
+            nes.cpu.bus.read(0x0000),
+            nes.cpu.bus.read(0x0001),
+        );
+        assert_eq!(actual, expected);
+
+        let mut edited = movie.clone();
+        edited.frames[90].buttons[0] = Button::A as u8;
+        nes.play_movie(edited).unwrap();
+        for _ in 0..130 {
+            nes.run_frame();
+        }
+        assert_eq!(nes.movie_desync(), Some(120));
+
+        let other = Nes::from_rom(&nrom(&[])).unwrap().rom_md5();
+        let mut wrong_rom = movie;
+        wrong_rom.rom_checksum = other;
+        assert_eq!(nes.play_movie(wrong_rom), Err(MovieError::RomMismatch));
+    }
+}
@@ -0,0 +1,847 @@
+// Nes is the public entry point of the emulator. It wires a cartridge into
+// the system bus and drives the CPU, which in turn clocks the PPU and APU.
+
+use crate::bus::SystemBus;
+use crate::cartridge::{Cartridge, CartridgeError, Header, SaveRamError};
+use crate::cdl::CodeDataLogger;
+use crate::cheat::{Cheat, CheatKind};
+use crate::controller::{Button, Controller};
+use crate::cpu::CPU;
+use crate::crc32::crc32;
+use crate::debugger::{self, Debugger, Instruction, Registers};
+use crate::md5::md5;
+use crate::memory::{MemorySpace, MemoryView};
+use crate::movie::{
+    FrameInput, Movie, MovieError, MovieMode, MovieSession, COMMAND_POWER, COMMAND_RESET,
+};
+use crate::rewind::Rewind;
+use crate::save_file;
+use crate::state::{StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::trace::Tracer;
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    Cartridge(CartridgeError),
+}
+
+impl From<CartridgeError> for LoadError {
+    fn from(error: CartridgeError) -> Self {
+        LoadError::Cartridge(error)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Cartridge(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Cartridge(error) => Some(error),
+        }
+    }
+}
+
+// Magic, version and ROM CRC
+const STATE_HEADER_SIZE: usize = 10;
+
+pub struct Nes {
+    pub(crate) cpu: CPU,
+    cartridge: Rc<RefCell<Cartridge>>,
+    rom: Vec<u8>,
+    rom_crc32: u32,
+    rewind: Option<Rewind>,
+    movie: Option<MovieSession>,
+    cheats: Vec<Cheat>,
+    // The debugger stopped the machine before the frame finished
+    frame_interrupted: bool,
+}
+
+impl Nes {
+    pub fn from_rom(rom: &[u8]) -> Result<Nes, LoadError> {
+        let cartridge = Rc::new(RefCell::new(Cartridge::new(rom)?));
+        let mut bus = SystemBus::new();
+        bus.set_cartridge(cartridge.clone());
+        let mut cpu = CPU::new(bus);
+        cpu.reset_registers();
+        Ok(Nes {
+            cpu,
+            cartridge,
+            rom: rom.to_vec(),
+            rom_crc32: crc32(rom),
+            rewind: None,
+            movie: None,
+            cheats: Vec::new(),
+            frame_interrupted: false,
+        })
+    }
+
+    // CRC-32 of the whole ROM file, header included
+    pub fn rom_crc32(&self) -> u32 {
+        self.rom_crc32
+    }
+
+    // MD5 of the PRG and CHR ROM, which is how FM2 movies identify a game
+    pub fn rom_md5(&self) -> [u8; 16] {
+        let header = self.header();
+        md5(&self.rom[header.prg_rom_range().start..header.chr_rom_range().end])
+    }
+
+    pub fn header(&self) -> Header {
+        *self.cartridge.borrow().header()
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.update_cheats();
+    }
+
+    pub fn remove_cheat(&mut self, index: usize) -> Cheat {
+        let cheat = self.cheats.remove(index);
+        self.update_cheats();
+        cheat
+    }
+
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) {
+        self.cheats[index].enabled = enabled;
+        self.update_cheats();
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+        self.update_cheats();
+    }
+
+    // Hands the enabled substitutions to the cartridge's read path
+    fn update_cheats(&mut self) {
+        let substitutions = self
+            .cheats
+            .iter()
+            .filter(|c| c.enabled && c.kind == CheatKind::Substitute)
+            .cloned()
+            .collect();
+        self.cartridge.borrow_mut().set_cheats(substitutions);
+    }
+
+    fn apply_freeze_cheats(&mut self) {
+        for i in 0..self.cheats.len() {
+            let cheat = &self.cheats[i];
+            if cheat.enabled && cheat.kind == CheatKind::Freeze {
+                let (address, value) = (cheat.address as usize, cheat.value);
+                self.memory().poke(MemorySpace::CpuBus, address, value);
+            }
+        }
+    }
+
+    // Side effect free access to memory
+    pub fn memory(&mut self) -> MemoryView<'_> {
+        MemoryView::new(&mut self.cpu.bus)
+    }
+
+    pub(crate) fn cartridge(&self) -> Ref<'_, Cartridge> {
+        self.cartridge.borrow()
+    }
+
+    // Whether the cartridge keeps its PRG RAM alive with a battery
+    pub fn battery_backed(&self) -> bool {
+        self.cartridge.borrow().battery_backed()
+    }
+
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.cartridge.borrow().save_ram().map(|data| data.to_vec())
+    }
+
+    // The save RAM itself, for frontends that keep it in sync on their own
+    pub fn save_ram_mut(&mut self) -> Option<RefMut<'_, [u8]>> {
+        RefMut::filter_map(self.cartridge.borrow_mut(), |c| c.save_ram_mut()).ok()
+    }
+
+    pub fn load_save_ram(&mut self, data: &[u8]) -> Result<(), SaveRamError> {
+        self.cartridge.borrow_mut().load_save_ram(data)
+    }
+
+    // Whether the game wrote to its save RAM since it was last loaded or saved
+    pub fn save_ram_dirty(&self) -> bool {
+        self.cartridge.borrow().save_ram_dirty()
+    }
+
+    // Loads the .sav file next to the ROM, returning whether there was one
+    pub fn load_save_file(&mut self, rom_path: &Path) -> io::Result<bool> {
+        if !self.battery_backed() {
+            return Ok(false);
+        }
+        match save_file::read(rom_path)? {
+            Some(data) => {
+                self.load_save_ram(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Writes the .sav file next to the ROM if the save RAM changed,
+    // returning whether it did
+    pub fn flush_save_file(&mut self, rom_path: &Path) -> io::Result<bool> {
+        if !self.save_ram_dirty() {
+            return Ok(false);
+        }
+        let mut cartridge = self.cartridge.borrow_mut();
+        if let Some(data) = cartridge.save_ram() {
+            save_file::write(rom_path, data)?;
+        }
+        cartridge.set_save_ram_dirty(false);
+        Ok(true)
+    }
+
+    // Snapshots the whole machine, see the state module for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_u32(u32::from_le_bytes(STATE_MAGIC));
+        w.write_u16(STATE_VERSION);
+        w.write_u32(self.rom_crc32);
+        w.write_section(b"CPU ", &self.cpu);
+        w.write_section(b"BUS ", &self.cpu.bus);
+        w.write_section(b"CART", &*self.cartridge.borrow());
+        w.into_inner()
+    }
+
+    // A state that fails to load leaves the machine as it was. The rewind
+    // history is dropped since it no longer leads up to the current frame
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.restore_state(data)?;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        if r.read_u32()?.to_le_bytes() != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = r.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let rom_crc32 = r.read_u32()?;
+        if rom_crc32 != self.rom_crc32 {
+            return Err(StateError::RomMismatch {
+                expected: self.rom_crc32,
+                actual: rom_crc32,
+            });
+        }
+
+        let backup = self.save_state();
+        let result = self.load_sections(&mut r);
+        if result.is_err() {
+            self.load_sections(&mut StateReader::new(&backup[STATE_HEADER_SIZE..]))
+                .expect("a state we just saved loads");
+        }
+        result
+    }
+
+    fn load_sections(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_section(b"CPU ", &mut self.cpu)?;
+        r.read_section(b"BUS ", &mut self.cpu.bus)?;
+        r.read_section(b"CART", &mut *self.cartridge.borrow_mut())
+    }
+
+    // Snapshots the machine every `interval` frames, keeping as many
+    // snapshots as fit in `memory_limit` bytes
+    pub fn enable_rewind(&mut self, interval: u32, memory_limit: usize) {
+        self.rewind = Some(Rewind::new(interval, memory_limit));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Steps back `frames` frames, or as far as the rewind history goes, by
+    // restoring the closest snapshot and replaying the recorded input from
+    // there. Returns how many frames were actually rewound.
+    pub fn rewind_frames(&mut self, frames: u64) -> u64 {
+        let current = self.frame_count();
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return 0,
+        };
+        let target = match rewind.oldest_frame() {
+            Some(oldest) => current.saturating_sub(frames).max(oldest),
+            None => {
+                self.rewind = Some(rewind);
+                return 0;
+            }
+        };
+        let (_, state) = rewind
+            .restore(target)
+            .expect("the oldest snapshot is restorable");
+        self.restore_state(&state)
+            .expect("rewind snapshots come from this machine");
+
+        // The replayed frames are already in the movie and the rewind
+        // buffer, and were already seen by the debugger and the tracer
+        let movie = self.movie.take();
+        let debugger = self.cpu.bus.debugger.take();
+        let tracer = self.cpu.bus.tracer.take();
+        while self.frame_count() < target {
+            if let Some(buttons) = rewind.input(self.frame_count()) {
+                self.set_buttons(0, buttons[0]);
+                self.set_buttons(1, buttons[1]);
+            }
+            self.run_frame();
+        }
+        rewind.truncate(target);
+        self.rewind = Some(rewind);
+        let rewound = current - self.frame_count();
+        self.movie = movie;
+        self.cpu.bus.debugger = debugger;
+        self.cpu.bus.tracer = tracer;
+        self.cpu.bus.update_instrumentation();
+        if let Some(session) = &mut self.movie {
+            session.rewind_to(session.frame.saturating_sub(rewound as usize));
+        }
+        rewound
+    }
+
+    // Starts recording a movie from power on. Save RAM is cleared so the
+    // recording doesn't depend on earlier play sessions
+    pub fn start_recording(&mut self, rom_filename: &str) {
+        self.power_on_for_movie();
+        let movie = Movie::new(rom_filename, self.rom_md5());
+        self.movie = Some(MovieSession::new(movie, MovieMode::Recording));
+    }
+
+    // Plays `movie` from power on, driving the controllers from it each frame
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if movie.rom_checksum != self.rom_md5() {
+            return Err(MovieError::RomMismatch);
+        }
+        self.power_on_for_movie();
+        self.movie = Some(MovieSession::new(movie, MovieMode::Playing));
+        Ok(())
+    }
+
+    // Detaches the movie being recorded or played
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|session| session.movie)
+    }
+
+    pub fn movie_mode(&self) -> Option<MovieMode> {
+        self.movie.as_ref().map(|session| session.mode)
+    }
+
+    // Whether a movie being played has run out of input
+    pub fn movie_finished(&self) -> bool {
+        self.movie
+            .as_ref()
+            .is_some_and(|session| session.finished())
+    }
+
+    // First movie frame whose RAM hash differed from the recording
+    pub fn movie_desync(&self) -> Option<u64> {
+        self.movie.as_ref().and_then(|session| session.desync)
+    }
+
+    fn power_on_for_movie(&mut self) {
+        self.movie = None;
+        self.power_cycle();
+        if let Some(size) = self.save_ram().map(|data| data.len()) {
+            self.load_save_ram(&vec![0; size])
+                .expect("the save RAM has the right size");
+        }
+    }
+
+    // Equivalent to pressing the reset button
+    pub fn reset(&mut self) {
+        self.cpu.bus.ppu.reset();
+        self.cpu.bus.apu.write_register(0x4015, 0);
+        self.cpu.reset();
+        if let Some(session) = &mut self.movie {
+            session.record_command(COMMAND_RESET);
+        }
+    }
+
+    // Equivalent to switching the console off and on again. Battery backed
+    // RAM survives, everything else starts over
+    pub fn power_cycle(&mut self) {
+        let sample_rate = self.cpu.bus.apu.sample_rate();
+        let buttons = (self.controller(0).buttons, self.controller(1).buttons);
+        let save_ram = self.save_ram();
+        let save_ram_dirty = self.save_ram_dirty();
+        let rewind = self
+            .rewind
+            .as_ref()
+            .map(|r| (r.interval(), r.memory_limit()));
+        let movie = self.movie.take();
+        let debugger = self.cpu.bus.debugger.take();
+        let tracer = self.cpu.bus.tracer.take();
+        let cdl = self.cartridge.borrow_mut().take_code_data_logger();
+        let cheats = std::mem::take(&mut self.cheats);
+        *self = Nes::from_rom(&self.rom).expect("the ROM was already loaded once");
+        self.cartridge.borrow_mut().set_code_data_logger(cdl);
+        self.cheats = cheats;
+        self.update_cheats();
+        self.movie = movie;
+        self.cpu.bus.debugger = debugger;
+        self.cpu.bus.tracer = tracer;
+        self.cpu.bus.update_instrumentation();
+        if let Some(session) = &mut self.movie {
+            session.record_command(COMMAND_POWER);
+        }
+        if let Some((interval, memory_limit)) = rewind {
+            self.enable_rewind(interval, memory_limit);
+        }
+        if let Some(data) = save_ram {
+            let mut cartridge = self.cartridge.borrow_mut();
+            cartridge
+                .load_save_ram(&data)
+                .expect("the save RAM came from the same ROM");
+            cartridge.set_save_ram_dirty(save_ram_dirty);
+        }
+        self.set_sample_rate(sample_rate);
+        self.set_buttons(0, buttons.0);
+        self.set_buttons(1, buttons.1);
+    }
+
+    // Runs a single CPU instruction, or an interrupt sequence
+    pub fn step_instruction(&mut self) {
+        self.resume_debugger();
+        self.cpu.execute_next_instruction();
+    }
+
+    // Runs until the PPU has finished the current frame, or until the
+    // debugger stops the machine. The next call then finishes the frame
+    pub fn run_frame(&mut self) {
+        if !std::mem::take(&mut self.frame_interrupted) {
+            self.apply_freeze_cheats();
+            self.apply_movie_input();
+            let frame = self.frame_count();
+            let buttons = self.buttons();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.record_input(frame, buttons);
+            }
+        }
+        self.resume_debugger();
+
+        let frame = self.frame_count();
+        while self.frame_count() == frame {
+            self.cpu.execute_next_instruction();
+            if self.debugger_stopped() {
+                self.frame_interrupted = true;
+                return;
+            }
+        }
+
+        if self
+            .rewind
+            .as_ref()
+            .is_some_and(|r| r.snapshot_due(self.frame_count()))
+        {
+            let state = self.save_state();
+            let frame = self.frame_count();
+            self.rewind.as_mut().unwrap().push(frame, state);
+        }
+        self.advance_movie(self.buttons());
+    }
+
+    pub fn enable_debugger(&mut self) {
+        if self.cpu.bus.debugger.is_none() {
+            self.cpu.bus.debugger = Some(Box::new(Debugger::new()));
+            self.cpu.bus.update_instrumentation();
+        }
+    }
+
+    pub fn disable_debugger(&mut self) {
+        self.cpu.bus.debugger = None;
+        self.cpu.bus.update_instrumentation();
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.cpu.bus.debugger.as_deref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.cpu.bus.debugger.as_deref_mut()
+    }
+
+    // Traces every instruction from now on, replacing the previous tracer
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.cpu.bus.tracer = Some(Box::new(tracer));
+        self.cpu.bus.update_instrumentation();
+    }
+
+    // Returns the tracer so its output can be flushed or read
+    pub fn stop_trace(&mut self) -> Option<Tracer> {
+        let tracer = self.cpu.bus.tracer.take();
+        self.cpu.bus.update_instrumentation();
+        tracer.map(|tracer| *tracer)
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.cpu.bus.tracer.as_deref()
+    }
+
+    // Starts tagging PRG and CHR ROM bytes as code, data or graphics as the
+    // game uses them, keeping the log if one is running
+    pub fn enable_code_data_logger(&mut self) {
+        self.cartridge.borrow_mut().enable_code_data_logger();
+        self.cpu.bus.update_instrumentation();
+    }
+
+    // Stops logging and returns the log
+    pub fn disable_code_data_logger(&mut self) -> Option<CodeDataLogger> {
+        let cdl = self.cartridge.borrow_mut().take_code_data_logger();
+        self.cpu.bus.update_instrumentation();
+        cdl.map(|cdl| *cdl)
+    }
+
+    pub fn code_data_logger(&self) -> Option<Ref<'_, CodeDataLogger>> {
+        Ref::filter_map(self.cartridge.borrow(), |c| c.code_data_logger()).ok()
+    }
+
+    pub fn code_data_logger_mut(&mut self) -> Option<RefMut<'_, CodeDataLogger>> {
+        RefMut::filter_map(self.cartridge.borrow_mut(), |c| c.code_data_logger_mut()).ok()
+    }
+
+    pub fn cpu_registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    // Whether the CPU hit a JAM opcode. Only a reset or power cycle gets it
+    // running again
+    pub fn cpu_jammed(&self) -> bool {
+        self.cpu.jammed()
+    }
+
+    // Decodes the instruction at `address` without side effects, with
+    // effective addresses from the current registers and the debugger's labels
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        let bus = &self.cpu.bus;
+        let labels = bus.debugger.as_ref().map(|d| d.labels());
+        debugger::disassemble(bus, address, &self.cpu.registers(), labels)
+    }
+
+    // Runs the next instruction, or a whole subroutine if it is a JSR. Like
+    // run_frame, this stops at the end of the frame if the subroutine
+    // hasn't returned by then, and the next run_frame carries on
+    pub fn step_over(&mut self) {
+        let registers = self.cpu.registers();
+        let opcode = self.cpu.bus.peek(registers.pc);
+        match self.debugger_mut() {
+            Some(debugger) => debugger.step_over(registers, opcode),
+            None => return self.step_instruction(),
+        }
+        self.run_frame();
+    }
+
+    // Runs until the current subroutine returns, or the frame ends
+    pub fn step_out(&mut self) {
+        let registers = self.cpu.registers();
+        match self.debugger_mut() {
+            Some(debugger) => debugger.step_out(registers),
+            None => return,
+        }
+        self.run_frame();
+    }
+
+    fn resume_debugger(&mut self) {
+        let pc = self.cpu.registers().pc;
+        if let Some(debugger) = self.debugger_mut() {
+            debugger.resume(pc);
+        }
+    }
+
+    fn debugger_stopped(&self) -> bool {
+        self.debugger()
+            .is_some_and(|debugger| debugger.break_reason().is_some())
+    }
+
+    fn buttons(&self) -> [u8; 2] {
+        [self.controller(0).buttons, self.controller(1).buttons]
+    }
+
+    // Feeds the next frame of a movie being played into the machine
+    fn apply_movie_input(&mut self) {
+        let input = match &self.movie {
+            Some(session) if session.mode == MovieMode::Playing && !session.finished() => {
+                session.movie.frames[session.frame]
+            }
+            _ => return,
+        };
+        if input.commands & COMMAND_POWER != 0 {
+            self.power_cycle();
+        } else if input.commands & COMMAND_RESET != 0 {
+            self.reset();
+        }
+        self.set_buttons(0, input.buttons[0]);
+        self.set_buttons(1, input.buttons[1]);
+    }
+
+    fn advance_movie(&mut self, buttons: [u8; 2]) {
+        let session = match &mut self.movie {
+            Some(session) if !session.finished() => session,
+            _ => return,
+        };
+        if session.mode == MovieMode::Recording {
+            session.movie.frames.push(FrameInput {
+                commands: std::mem::take(&mut session.pending_commands),
+                buttons,
+            });
+        }
+        session.frame += 1;
+        session.check_ram_hash(self.cpu.bus.ram());
+    }
+
+    // 256x240 pixels in 0x00RRGGBB form
+    pub fn frame_buffer(&self) -> &[u32] {
+        self.cpu.bus.ppu.frame_buffer()
+    }
+
+    // The 2 KB of internal RAM at $0000-$07FF
+    pub fn ram(&self) -> &[u8] {
+        self.cpu.bus.ram()
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.cpu.bus.ram_mut()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus.ppu.frame
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.bus.cycles
+    }
+
+    // Mono samples generated since the last call
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.cpu.bus.apu.sample_rate()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    // Buttons are a bit mask of `Button` values for controller port 0 or 1,
+    // other ports are ignored
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        if let Some(controller) = self.controller_mut(port) {
+            controller.buttons = buttons;
+        }
+    }
+
+    pub fn set_button(&mut self, port: usize, button: Button, pressed: bool) {
+        if let Some(controller) = self.controller_mut(port) {
+            controller.set_button(button, pressed);
+        }
+    }
+
+    fn controller(&self, port: usize) -> &Controller {
+        match port {
+            0 => &self.cpu.bus.controller_0,
+            1 => &self.cpu.bus.controller_1,
+            _ => panic!("invalid controller port: {}", port),
+        }
+    }
+
+    fn controller_mut(&mut self, port: usize) -> Option<&mut Controller> {
+        match port {
+            0 => Some(&mut self.cpu.bus.controller_0),
+            1 => Some(&mut self.cpu.bus.controller_1),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::test_util::nrom;
+
+    // NROM image that spins on JMP $8000
+    fn build_rom() -> Vec<u8> {
+        nrom(&[0x4C, 0x00, 0x80])
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        assert_eq!(
+            Nes::from_rom(b"not a rom").err(),
+            Some(LoadError::Cartridge(CartridgeError::BadMagic))
+        );
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        nes.run_frame();
+        let frame = nes.frame_count();
+        let cycles = nes.cycles();
+        nes.audio_samples();
+
+        nes.run_frame();
+        assert_eq!(nes.frame_count(), frame + 1);
+        // 341 * 262 / 3 CPU cycles per frame, give or take an instruction
+        assert!((29_775..=29_785).contains(&(nes.cycles() - cycles)));
+        assert_eq!(nes.frame_buffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        // 44100 Hz / 60 fps
+        assert!((730..=740).contains(&nes.audio_samples().len()));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        nes.set_buttons(1, Button::A as u8 | Button::Start as u8);
+        nes.cpu.bus.write(0x4016, 1);
+        nes.cpu.bus.write(0x4016, 0);
+        let bits: Vec<u8> = (0..8).map(|_| nes.cpu.bus.read(0x4017) & 1).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 0]);
+
+        nes.power_cycle();
+        assert_eq!(nes.controller(1).buttons, 0b0000_1001);
+
+        nes.set_buttons(2, Button::B as u8);
+        nes.set_button(2, Button::B, true);
+        assert_eq!(nes.buttons(), [0, 0b0000_1001]);
+    }
+
+    #[test]
+    fn test_save_file() {
+        let mut rom = build_rom();
+        rom[6] |= 0x02;
+        let dir = std::env::temp_dir().join(format!("saka-nes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        assert!(!nes.load_save_file(&rom_path).unwrap());
+        assert!(!nes.flush_save_file(&rom_path).unwrap());
+
+        nes.cpu.bus.write(0x6000, 0x42);
+        nes.power_cycle();
+        assert!(nes.save_ram_dirty());
+        assert!(nes.flush_save_file(&rom_path).unwrap());
+        assert!(!nes.save_ram_dirty());
+
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        assert!(nes.load_save_file(&rom_path).unwrap());
+        assert_eq!(nes.cpu.bus.read(0x6000), 0x42);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        nes.run_frame();
+        nes.run_frame();
+        let state = nes.save_state();
+        nes.audio_samples();
+
+        let run = |nes: &mut Nes| {
+            for _ in 0..3 {
+                nes.run_frame();
+            }
+            (nes.cycles(), nes.frame_count(), nes.audio_samples())
+        };
+        let expected = run(&mut nes);
+        nes.load_state(&state).unwrap();
+        assert_eq!(run(&mut nes), expected);
+    }
+
+    #[test]
+    fn test_reject_state() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        nes.run_frame();
+        let state = nes.save_state();
+
+        let mut other_rom = build_rom();
+        other_rom[16 + 0x100] = 0x00;
+        let mut other = Nes::from_rom(&other_rom).unwrap();
+        assert!(matches!(
+            other.load_state(&state),
+            Err(StateError::RomMismatch { .. })
+        ));
+
+        let mut version = state.clone();
+        version[4] = 0xFF;
+        assert!(matches!(
+            nes.load_state(&version),
+            Err(StateError::UnsupportedVersion(_))
+        ));
+
+        nes.run_frame();
+        let cycles = nes.cycles();
+        assert_eq!(
+            nes.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(nes.cycles(), cycles);
+    }
+
+    #[test]
+    fn test_corrupt_state() {
+        // Battery backed with four-screen VRAM, so every memory dump in the
+        // state can be filled
+        let mut rom = build_rom();
+        rom[6] |= 0x0A;
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        nes.run_frame();
+        nes.ram_mut().fill(0xA5);
+        nes.save_ram_mut().unwrap().fill(0xA5);
+        nes.cpu.bus.write(0x2006, 0x20);
+        nes.cpu.bus.write(0x2006, 0x00);
+        for _ in 0..0x1000 {
+            nes.cpu.bus.write(0x2007, 0xA5);
+        }
+        nes.cpu.bus.write(0x2003, 0x00);
+        for _ in 0..0x100 {
+            nes.cpu.bus.write(0x2004, 0xA5);
+        }
+        let state = nes.save_state();
+
+        // Any value is fine in plain memory, only the fields in between are
+        // worth running a frame for
+        let mut memory = vec![false; state.len()];
+        let mut start = 0;
+        for end in 1..=state.len() {
+            if end == state.len() || state[end] != state[start] {
+                if end - start >= 64 {
+                    memory[start..end].fill(true);
+                }
+                start = end;
+            }
+        }
+
+        for offset in (0..state.len()).filter(|&offset| !memory[offset]) {
+            for value in [0x00, 0xFF] {
+                let mut corrupt = state.clone();
+                corrupt[offset] = value;
+                if nes.load_state(&corrupt).is_ok() {
+                    nes.run_frame();
+                }
+            }
+        }
+    }
+}
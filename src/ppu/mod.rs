@@ -1 +1,788 @@
+// Ppu implements the Ricoh 2C02 picture processing unit
+// https://wiki.nesdev.com/w/index.php/PPU
+
+mod palette;
 mod vram;
+
+use self::palette::SYSTEM_PALETTE;
+use self::vram::Vram;
+use crate::cartridge::Cartridge;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const CYCLES_PER_SCANLINE: u16 = 341;
+const SCANLINES_PER_FRAME: u16 = 262;
+const PRE_RENDER_SCANLINE: u16 = 261;
+const VBLANK_SCANLINE: u16 = 241;
+const OAM_SIZE: usize = 0x100;
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+
+// 7  bit  0
+// ---- ----
+// VPHB SINN
+// |||| ||||
+// |||| ||++- Base nametable address
+// |||| |+--- VRAM address increment per CPU read/write of PPUDATA
+// |||| +---- Sprite pattern table address for 8x8 sprites
+// |||+------ Background pattern table address
+// ||+------- Sprite size (0: 8x8 pixels; 1: 8x16 pixels)
+// |+-------- PPU master/slave select
+// +--------- Generate an NMI at the start of the vertical blanking interval
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct ControlRegister(u8);
+    impl Debug;
+    nametable_id, _: 1, 0;
+    vram_increment, _: 2;
+    sprite_table, _: 3;
+    background_table, _: 4;
+    sprite_size, _: 5;
+    master_slave, _: 6;
+    nmi_enabled, _: 7;
+}
+
+impl ControlRegister {
+    fn vram_address_increment(&self) -> u16 {
+        if self.vram_increment() {
+            32
+        } else {
+            1
+        }
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.sprite_size() {
+            16
+        } else {
+            8
+        }
+    }
+}
+
+// 7  bit  0
+// ---- ----
+// BGRs bMmG
+// |||| ||||
+// |||| |||+- Greyscale
+// |||| ||+-- Show background in leftmost 8 pixels of screen
+// |||| |+--- Show sprites in leftmost 8 pixels of screen
+// |||| +---- Show background
+// |||+------ Show sprites
+// ||+------- Emphasize red
+// |+-------- Emphasize green
+// +--------- Emphasize blue
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct MaskRegister(u8);
+    impl Debug;
+    greyscale, _: 0;
+    show_left_background, _: 1;
+    show_left_sprites, _: 2;
+    show_background, _: 3;
+    show_sprites, _: 4;
+    emphasize_red, _: 5;
+    emphasize_green, _: 6;
+    emphasize_blue, _: 7;
+}
+
+impl MaskRegister {
+    fn rendering_enabled(&self) -> bool {
+        self.show_background() || self.show_sprites()
+    }
+}
+
+// 7  bit  0
+// ---- ----
+// VSO. ....
+// |||| ||||
+// |||+-++++- Least significant bits previously written into a PPU register
+// ||+------- Sprite overflow
+// |+-------- Sprite 0 Hit
+// +--------- Vertical blank has started
+bitfield! {
+    #[derive(Copy, Clone)]
+    struct StatusRegister(u8);
+    impl Debug;
+    sprite_overflow, set_sprite_overflow: 5;
+    sprite_zero_hit, set_sprite_zero_hit: 6;
+    vblank, set_vblank: 7;
+}
+
+pub struct Ppu {
+    vram: Vram,
+    // Current dot (0-340) and scanline (0-261, 261 is the pre-render line)
+    pub cycle: u16,
+    pub scanline: u16,
+    pub frame: u64,
+    ctrl: ControlRegister,
+    mask: MaskRegister,
+    status: StatusRegister,
+    // Last value written to any register, returned for unused bits
+    latch: u8,
+    oam_address: u8,
+    oam: [u8; OAM_SIZE],
+
+    // Loopy scroll registers
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    odd_frame: bool,
+
+    // Background fetch pipeline
+    nametable_byte: u8,
+    attribute_byte: u8,
+    low_tile_byte: u8,
+    high_tile_byte: u8,
+    tile_data: u64,
+
+    // Sprites for the current scanline
+    sprite_count: usize,
+    sprite_patterns: [u32; MAX_SPRITES_PER_SCANLINE],
+    sprite_positions: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_priorities: [u8; MAX_SPRITES_PER_SCANLINE],
+    sprite_indexes: [u8; MAX_SPRITES_PER_SCANLINE],
+
+    frame_buffer: Vec<u32>,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            vram: Vram::new(),
+            cycle: 0,
+            scanline: 0,
+            frame: 0,
+            ctrl: ControlRegister(0),
+            mask: MaskRegister(0),
+            status: StatusRegister(0),
+            latch: 0,
+            oam_address: 0,
+            oam: [0; OAM_SIZE],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            odd_frame: false,
+            nametable_byte: 0,
+            attribute_byte: 0,
+            low_tile_byte: 0,
+            high_tile_byte: 0,
+            tile_data: 0,
+            sprite_count: 0,
+            sprite_patterns: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_positions: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_priorities: [0; MAX_SPRITES_PER_SCANLINE],
+            sprite_indexes: [0; MAX_SPRITES_PER_SCANLINE],
+            frame_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
+        self.vram.set_cartridge(cartridge);
+    }
+
+    pub fn set_code_data_logging(&mut self, enabled: bool) {
+        self.vram.set_code_data_logging(enabled);
+    }
+
+    // The reset button clears PPUCTRL, PPUMASK, the scroll latches and the
+    // read buffer, and restarts the frame. VRAM and OAM are left untouched.
+    // https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+    pub fn reset(&mut self) {
+        self.ctrl = ControlRegister(0);
+        self.mask = MaskRegister(0);
+        self.t = 0;
+        self.x = 0;
+        self.w = false;
+        self.odd_frame = false;
+        self.cycle = 0;
+        self.scanline = 0;
+        self.vram.clear_read_buffer();
+    }
+
+    pub fn frame_buffer(&self) -> &[u32] {
+        &self.frame_buffer
+    }
+
+    // The NMI output of the PPU is active while both the vblank flag
+    // and the NMI enable bit of PPUCTRL are set
+    pub fn nmi_line(&self) -> bool {
+        self.status.vblank() && self.ctrl.nmi_enabled()
+    }
+
+    // Address the next PPUDATA access goes to
+    pub fn vram_address(&self) -> u16 {
+        self.v & 0x3FFF
+    }
+
+    pub fn read_register(&mut self, address: u16) -> u8 {
+        match 0x2000 + address % 8 {
+            0x2002 => {
+                let value = (self.status.0 & 0xE0) | (self.latch & 0x1F);
+                self.status.set_vblank(false);
+                self.w = false;
+                value
+            }
+            0x2004 => self.oam[self.oam_address as usize],
+            0x2007 => {
+                let value = self.vram.buffered_read_byte(self.v & 0x3FFF);
+                self.increment_vram_address();
+                value
+            }
+            _ => self.latch,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        self.latch = value;
+        match 0x2000 + address % 8 {
+            0x2000 => {
+                self.ctrl = ControlRegister(value);
+                // t: ...GH.. ........ <- d: ......GH
+                self.t = (self.t & 0xF3FF) | ((value as u16 & 0x03) << 10);
+            }
+            0x2001 => self.mask = MaskRegister(value),
+            0x2003 => self.oam_address = value,
+            0x2004 => self.write_oam(value),
+            0x2005 => {
+                if !self.w {
+                    // t: ....... ...HGFED <- d: HGFED...
+                    // x:              CBA <- d: .....CBA
+                    self.t = (self.t & 0xFFE0) | (value as u16 >> 3);
+                    self.x = value & 0x07;
+                } else {
+                    // t: CBA..HG FED..... <- d: HGFEDCBA
+                    self.t = (self.t & 0x8FFF) | ((value as u16 & 0x07) << 12);
+                    self.t = (self.t & 0xFC1F) | ((value as u16 & 0xF8) << 2);
+                }
+                self.w = !self.w;
+            }
+            0x2006 => {
+                if !self.w {
+                    // t: .FEDCBA ........ <- d: ..FEDCBA
+                    self.t = (self.t & 0x80FF) | ((value as u16 & 0x3F) << 8);
+                } else {
+                    // t: ....... HGFEDCBA <- d: HGFEDCBA
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            0x2007 => {
+                self.vram.write_byte(self.v & 0x3FFF, value);
+                self.increment_vram_address();
+            }
+            _ => (),
+        }
+    }
+
+    // Used by OAMDATA writes and OAM DMA
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8] {
+        &mut self.oam
+    }
+
+    pub fn palette(&self) -> &[u8] {
+        &self.vram.palette
+    }
+
+    pub fn palette_mut(&mut self) -> &mut [u8] {
+        &mut self.vram.palette
+    }
+
+    // PPU bus access without side effects
+    pub fn peek_vram(&self, address: u16) -> u8 {
+        self.vram.peek(address)
+    }
+
+    pub fn poke_vram(&mut self, address: u16, value: u8) {
+        self.vram.poke(address, value)
+    }
+
+    pub fn write_oam(&mut self, value: u8) {
+        self.oam[self.oam_address as usize] = value;
+        self.oam_address = self.oam_address.wrapping_add(1);
+    }
+
+    fn increment_vram_address(&mut self) {
+        self.v = self.v.wrapping_add(self.ctrl.vram_address_increment()) & 0x7FFF;
+    }
+
+    // Advances the PPU by one dot
+    pub fn step(&mut self) {
+        self.tick();
+
+        let rendering = self.mask.rendering_enabled();
+        let pre_line = self.scanline == PRE_RENDER_SCANLINE;
+        let visible_line = self.scanline < SCREEN_HEIGHT as u16;
+        let render_line = pre_line || visible_line;
+        let pre_fetch_cycle = (321..=336).contains(&self.cycle);
+        let visible_cycle = (1..=256).contains(&self.cycle);
+        let fetch_cycle = pre_fetch_cycle || visible_cycle;
+
+        if rendering {
+            if visible_line && visible_cycle {
+                self.render_pixel();
+            }
+            if render_line && fetch_cycle {
+                self.tile_data <<= 4;
+                match self.cycle % 8 {
+                    1 => self.fetch_nametable_byte(),
+                    3 => self.fetch_attribute_byte(),
+                    5 => self.fetch_low_tile_byte(),
+                    7 => self.fetch_high_tile_byte(),
+                    0 => self.store_tile_data(),
+                    _ => (),
+                }
+            }
+            if pre_line && (280..=304).contains(&self.cycle) {
+                self.copy_y();
+            }
+            if render_line {
+                if fetch_cycle && self.cycle.is_multiple_of(8) {
+                    self.increment_x();
+                }
+                if self.cycle == 256 {
+                    self.increment_y();
+                }
+                if self.cycle == 257 {
+                    self.copy_x();
+                    if visible_line {
+                        self.evaluate_sprites();
+                    } else {
+                        self.sprite_count = 0;
+                    }
+                }
+                // The MMC3 counts scanlines by watching A12 rise during sprite fetches
+                if self.cycle == 260 {
+                    self.vram.signal_scanline();
+                }
+            }
+        } else if visible_line && visible_cycle {
+            self.render_backdrop();
+        }
+
+        if self.scanline == VBLANK_SCANLINE && self.cycle == 1 {
+            self.status.set_vblank(true);
+        }
+        if pre_line && self.cycle == 1 {
+            self.status.set_vblank(false);
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+    }
+
+    fn tick(&mut self) {
+        // The pre-render scanline is one dot shorter on odd frames when rendering
+        if self.mask.rendering_enabled()
+            && self.odd_frame
+            && self.scanline == PRE_RENDER_SCANLINE
+            && self.cycle == CYCLES_PER_SCANLINE - 2
+        {
+            self.cycle = 0;
+            self.next_frame();
+            return;
+        }
+
+        self.cycle += 1;
+        if self.cycle == CYCLES_PER_SCANLINE {
+            self.cycle = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.next_frame();
+            }
+        }
+    }
+
+    fn next_frame(&mut self) {
+        self.scanline = 0;
+        self.frame += 1;
+        self.odd_frame = !self.odd_frame;
+    }
+
+    fn fetch_nametable_byte(&mut self) {
+        let address = 0x2000 | (self.v & 0x0FFF);
+        self.nametable_byte = self.vram.read_byte(address);
+    }
+
+    fn fetch_attribute_byte(&mut self) {
+        let v = self.v;
+        let address = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        let shift = ((v >> 4) & 4) | (v & 2);
+        self.attribute_byte = ((self.vram.read_byte(address) >> shift) & 3) << 2;
+    }
+
+    fn background_tile_address(&self) -> u16 {
+        let fine_y = (self.v >> 12) & 7;
+        let table = self.ctrl.background_table() as u16;
+        0x1000 * table + self.nametable_byte as u16 * 16 + fine_y
+    }
+
+    fn fetch_low_tile_byte(&mut self) {
+        let address = self.background_tile_address();
+        self.low_tile_byte = self.vram.read_byte(address);
+    }
+
+    fn fetch_high_tile_byte(&mut self) {
+        let address = self.background_tile_address() + 8;
+        self.high_tile_byte = self.vram.read_byte(address);
+    }
+
+    fn store_tile_data(&mut self) {
+        let mut data = 0u32;
+        for _ in 0..8 {
+            let p1 = (self.low_tile_byte & 0x80) >> 7;
+            let p2 = (self.high_tile_byte & 0x80) >> 6;
+            self.low_tile_byte <<= 1;
+            self.high_tile_byte <<= 1;
+            data <<= 4;
+            data |= (self.attribute_byte | p1 | p2) as u32;
+        }
+        self.tile_data |= data as u64;
+    }
+
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= 0xFFE0;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= 0x8FFF;
+            let mut y = (self.v & 0x03E0) >> 5;
+            if y == 29 {
+                y = 0;
+                self.v ^= 0x0800;
+            } else if y == 31 {
+                y = 0;
+            } else {
+                y += 1;
+            }
+            self.v = (self.v & 0xFC1F) | (y << 5);
+        }
+    }
+
+    fn copy_x(&mut self) {
+        // v: ....F.. ...EDCBA <- t: ....F.. ...EDCBA
+        self.v = (self.v & 0xFBE0) | (self.t & 0x041F);
+    }
+
+    fn copy_y(&mut self) {
+        // v: IHGF.ED CBA..... <- t: IHGF.ED CBA.....
+        self.v = (self.v & 0x841F) | (self.t & 0x7BE0);
+    }
+
+    fn evaluate_sprites(&mut self) {
+        let height = self.ctrl.sprite_height() as i32;
+        let mut count = 0;
+        for i in 0..OAM_SIZE / 4 {
+            let y = self.oam[i * 4] as i32;
+            let attributes = self.oam[i * 4 + 2];
+            let x = self.oam[i * 4 + 3];
+            let row = self.scanline as i32 - y;
+            if row < 0 || row >= height {
+                continue;
+            }
+            if count < MAX_SPRITES_PER_SCANLINE {
+                self.sprite_patterns[count] = self.fetch_sprite_pattern(i, row as u16);
+                self.sprite_positions[count] = x;
+                self.sprite_priorities[count] = (attributes >> 5) & 1;
+                self.sprite_indexes[count] = i as u8;
+            }
+            count += 1;
+        }
+        if count > MAX_SPRITES_PER_SCANLINE {
+            count = MAX_SPRITES_PER_SCANLINE;
+            self.status.set_sprite_overflow(true);
+        }
+        self.sprite_count = count;
+    }
+
+    fn fetch_sprite_pattern(&mut self, index: usize, mut row: u16) -> u32 {
+        let mut tile = self.oam[index * 4 + 1] as u16;
+        let attributes = self.oam[index * 4 + 2];
+        let flip_vertical = attributes & 0x80 != 0;
+        let flip_horizontal = attributes & 0x40 != 0;
+        let address = if !self.ctrl.sprite_size() {
+            if flip_vertical {
+                row = 7 - row;
+            }
+            let table = self.ctrl.sprite_table() as u16;
+            0x1000 * table + tile * 16 + row
+        } else {
+            if flip_vertical {
+                row = 15 - row;
+            }
+            let table = tile & 1;
+            tile &= 0xFE;
+            if row > 7 {
+                tile += 1;
+                row -= 8;
+            }
+            0x1000 * table + tile * 16 + row
+        };
+
+        let palette = (attributes & 3) << 2;
+        let mut low_tile_byte = self.vram.read_byte(address);
+        let mut high_tile_byte = self.vram.read_byte(address + 8);
+        let mut data = 0u32;
+        for _ in 0..8 {
+            let (p1, p2) = if flip_horizontal {
+                let bits = (low_tile_byte & 1, (high_tile_byte & 1) << 1);
+                low_tile_byte >>= 1;
+                high_tile_byte >>= 1;
+                bits
+            } else {
+                let bits = ((low_tile_byte & 0x80) >> 7, (high_tile_byte & 0x80) >> 6);
+                low_tile_byte <<= 1;
+                high_tile_byte <<= 1;
+                bits
+            };
+            data <<= 4;
+            data |= (palette | p1 | p2) as u32;
+        }
+        data
+    }
+
+    fn background_pixel(&self) -> u8 {
+        if !self.mask.show_background() {
+            return 0;
+        }
+        let data = (self.tile_data >> 32) as u32 >> ((7 - self.x) * 4);
+        (data & 0x0F) as u8
+    }
+
+    fn sprite_pixel(&self) -> (usize, u8) {
+        if !self.mask.show_sprites() {
+            return (0, 0);
+        }
+        for i in 0..self.sprite_count {
+            let offset = (self.cycle as i32 - 1) - self.sprite_positions[i] as i32;
+            if !(0..8).contains(&offset) {
+                continue;
+            }
+            let offset = 7 - offset;
+            let color = ((self.sprite_patterns[i] >> (offset * 4)) & 0x0F) as u8;
+            if color.is_multiple_of(4) {
+                continue;
+            }
+            return (i, color);
+        }
+        (0, 0)
+    }
+
+    fn render_pixel(&mut self) {
+        let x = self.cycle as usize - 1;
+        let y = self.scanline as usize;
+        let mut background = self.background_pixel();
+        let (i, mut sprite) = self.sprite_pixel();
+        if x < 8 && !self.mask.show_left_background() {
+            background = 0;
+        }
+        if x < 8 && !self.mask.show_left_sprites() {
+            sprite = 0;
+        }
+        let opaque_background = !background.is_multiple_of(4);
+        let opaque_sprite = !sprite.is_multiple_of(4);
+        let color = match (opaque_background, opaque_sprite) {
+            (false, false) => 0,
+            (false, true) => sprite | 0x10,
+            (true, false) => background,
+            (true, true) => {
+                if self.sprite_indexes[i] == 0 && x < 255 {
+                    self.status.set_sprite_zero_hit(true);
+                }
+                if self.sprite_priorities[i] == 0 {
+                    sprite | 0x10
+                } else {
+                    background
+                }
+            }
+        };
+        self.put_pixel(x, y, color);
+    }
+
+    // With rendering disabled the backdrop colour is shown, unless v
+    // points into the palette in which case that colour is used
+    fn render_backdrop(&mut self) {
+        let x = self.cycle as usize - 1;
+        let y = self.scanline as usize;
+        let color = if self.v & 0x3F00 == 0x3F00 {
+            (self.v & 0x1F) as u8
+        } else {
+            0
+        };
+        self.put_pixel(x, y, color);
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, color: u8) {
+        let mut index = self.vram.read_byte(0x3F00 + color as u16) & 0x3F;
+        if self.mask.greyscale() {
+            index &= 0x30;
+        }
+        self.frame_buffer[y * SCREEN_WIDTH + x] = SYSTEM_PALETTE[index as usize];
+    }
+}
+
+// The frame buffer isn't part of the state, the next frame redraws it
+impl SaveState for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_section(b"VRAM", &self.vram);
+        w.write_u16(self.cycle);
+        w.write_u16(self.scanline);
+        w.write_u64(self.frame);
+        w.write_u8(self.ctrl.0);
+        w.write_u8(self.mask.0);
+        w.write_u8(self.status.0);
+        w.write_u8(self.latch);
+        w.write_u8(self.oam_address);
+        w.write_bytes(&self.oam);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.x);
+        w.write_bool(self.w);
+        w.write_bool(self.odd_frame);
+        w.write_u8(self.nametable_byte);
+        w.write_u8(self.attribute_byte);
+        w.write_u8(self.low_tile_byte);
+        w.write_u8(self.high_tile_byte);
+        w.write_u64(self.tile_data);
+        w.write_usize(self.sprite_count);
+        for pattern in self.sprite_patterns {
+            w.write_u32(pattern);
+        }
+        w.write_bytes(&self.sprite_positions);
+        w.write_bytes(&self.sprite_priorities);
+        w.write_bytes(&self.sprite_indexes);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_section(b"VRAM", &mut self.vram)?;
+        self.cycle = r.read_u16()?;
+        self.scanline = r.read_u16()?;
+        if self.cycle >= CYCLES_PER_SCANLINE || self.scanline >= SCANLINES_PER_FRAME {
+            return Err(StateError::Invalid("PPU position beyond the frame"));
+        }
+        self.frame = r.read_u64()?;
+        self.ctrl.0 = r.read_u8()?;
+        self.mask.0 = r.read_u8()?;
+        self.status.0 = r.read_u8()?;
+        self.latch = r.read_u8()?;
+        self.oam_address = r.read_u8()?;
+        r.read_bytes(&mut self.oam)?;
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        self.x = r.read_u8()?;
+        if self.x > 7 {
+            return Err(StateError::Invalid("fine X scroll out of range"));
+        }
+        self.w = r.read_bool()?;
+        self.odd_frame = r.read_bool()?;
+        self.nametable_byte = r.read_u8()?;
+        self.attribute_byte = r.read_u8()?;
+        self.low_tile_byte = r.read_u8()?;
+        self.high_tile_byte = r.read_u8()?;
+        self.tile_data = r.read_u64()?;
+        self.sprite_count = r.read_usize()?;
+        if self.sprite_count > MAX_SPRITES_PER_SCANLINE {
+            return Err(StateError::Invalid("too many sprites on the scanline"));
+        }
+        for pattern in &mut self.sprite_patterns {
+            *pattern = r.read_u32()?;
+        }
+        r.read_bytes(&mut self.sprite_positions)?;
+        r.read_bytes(&mut self.sprite_priorities)?;
+        r.read_bytes(&mut self.sprite_indexes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ines;
+
+    #[test]
+    fn test_scroll_registers() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0b0000_0011);
+        assert_eq!(ppu.t, 0x0C00);
+
+        ppu.read_register(0x2002);
+        ppu.write_register(0x2005, 0b0111_1101);
+        assert_eq!(ppu.t, 0x0C0F);
+        assert_eq!(ppu.x, 0b101);
+        assert!(ppu.w);
+
+        ppu.write_register(0x2005, 0b0101_1110);
+        assert_eq!(ppu.t, 0x6D6F);
+        assert!(!ppu.w);
+
+        ppu.write_register(0x2006, 0b0011_1101);
+        assert_eq!(ppu.t, 0x3D6F);
+        ppu.write_register(0x2006, 0b1111_0000);
+        assert_eq!(ppu.t, 0x3DF0);
+        assert_eq!(ppu.v, 0x3DF0);
+    }
+
+    #[test]
+    fn test_palette_access() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x10);
+        ppu.write_register(0x2007, 0x2A);
+
+        // $3F10 mirrors $3F00 and palette reads are not buffered
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x00);
+        assert_eq!(ppu.read_register(0x2007), 0x2A);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0b1000_0000);
+        while !(ppu.scanline == VBLANK_SCANLINE && ppu.cycle == 1) {
+            ppu.step();
+        }
+        assert!(ppu.nmi_line());
+        assert_eq!(ppu.read_register(0x2002) & 0x80, 0x80);
+        assert!(!ppu.nmi_line());
+        assert_eq!(ppu.read_register(0x2002) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_mmc1_single_screen() {
+        let mut cartridge = Cartridge::new(&ines(1, 2, 1)).unwrap();
+        // Control register 0 selects the lower nametable, one bit at a time
+        for _ in 0..5 {
+            cartridge.write_prg_byte(0x8000, 0);
+        }
+        let mut ppu = Ppu::new();
+        ppu.set_cartridge(Rc::new(RefCell::new(cartridge)));
+
+        ppu.write_register(0x2006, 0x2C);
+        ppu.write_register(0x2006, 0x05);
+        ppu.write_register(0x2007, 0x42);
+
+        ppu.write_register(0x2006, 0x20);
+        ppu.write_register(0x2006, 0x05);
+        ppu.read_register(0x2007);
+        assert_eq!(ppu.read_register(0x2007), 0x42);
+    }
+}
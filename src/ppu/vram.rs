@@ -1,4 +1,6 @@
 use crate::cartridge::{Cartridge, Mirroring};
+use crate::cdl::CodeDataLogger;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -6,33 +8,45 @@ const NAMETABLE_SIZE: usize = 0x400;
 const PALETTE_SIZE: usize = 0x20;
 
 pub struct Vram {
-    pub nametables: [u8; 2 * NAMETABLE_SIZE],
+    // The console has 2 KB of nametable memory, the upper half is only
+    // reachable on four-screen boards which supply their own extra 2 KB
+    pub nametables: [u8; 4 * NAMETABLE_SIZE],
     pub palette: [u8; PALETTE_SIZE],
     read_buffer: u8,
     cartridge: Option<Rc<RefCell<Cartridge>>>,
+    // Whether the cartridge has a code/data logger to log CHR reads to
+    code_data_logging: bool,
 }
 
 impl Vram {
     pub fn new() -> Self {
         Vram {
-            nametables: [0; 2 * NAMETABLE_SIZE],
+            nametables: [0; 4 * NAMETABLE_SIZE],
             palette: [0; PALETTE_SIZE],
             read_buffer: 0,
             cartridge: None,
+            code_data_logging: false,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.nametables = [0xFF; 0x800];
-        self.palette = [0; 0x20];
+    pub fn clear_read_buffer(&mut self) {
         self.read_buffer = 0;
-        self.cartridge = None;
     }
 
     pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
         self.cartridge = Some(cartridge);
     }
 
+    pub fn set_code_data_logging(&mut self, enabled: bool) {
+        self.code_data_logging = enabled;
+    }
+
+    pub fn signal_scanline(&mut self) {
+        if let Some(ref c) = self.cartridge {
+            c.borrow_mut().signal_scanline();
+        }
+    }
+
     pub fn mirroring(&self) -> Mirroring {
         if let Some(ref c) = self.cartridge {
             c.borrow().mirroring()
@@ -55,10 +69,22 @@ impl Vram {
         };
     }
 
+    // Reads for rendering
     pub fn read_byte(&mut self, address: u16) -> u8 {
+        self.read_byte_as(address, CodeDataLogger::CHR_RENDERED)
+    }
+
+    // Reads, logging pattern table reads to the code/data logger with
+    // `cdl_flags`
+    fn read_byte_as(&mut self, address: u16, cdl_flags: u8) -> u8 {
         let mirroring = self.mirroring();
         match address {
             0x0000..=0x1FFF => match self.cartridge {
+                Some(ref c) if self.code_data_logging => {
+                    let mut cartridge = c.borrow_mut();
+                    cartridge.log_chr_access(address, cdl_flags);
+                    cartridge.read_chr_byte(address)
+                }
                 Some(ref c) => c.borrow().read_chr_byte(address),
                 None => panic!("tried to read non-existant cartridge memory"),
             },
@@ -68,14 +94,47 @@ impl Vram {
         }
     }
 
+    // Reads without logging or touching the read buffer
+    pub fn peek(&self, address: u16) -> u8 {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => match self.cartridge {
+                Some(ref c) => c.borrow().read_chr_byte(address),
+                None => 0,
+            },
+            0x2000..=0x3EFF => self.nametables[mirror_nametable(self.mirroring(), address)],
+            _ => self.palette[mirror_palette(address)],
+        }
+    }
+
+    // Writes, into CHR ROM too
+    pub fn poke(&mut self, address: u16, value: u8) {
+        let address = address & 0x3FFF;
+        match address {
+            0x0000..=0x1FFF => {
+                if let Some(ref c) = self.cartridge {
+                    let mut cartridge = c.borrow_mut();
+                    let offset = cartridge.chr_offset(address);
+                    cartridge.chr_mut()[offset] = value;
+                }
+            }
+            0x2000..=0x3EFF => {
+                let index = mirror_nametable(self.mirroring(), address);
+                self.nametables[index] = value;
+            }
+            _ => self.palette[mirror_palette(address)] = value,
+        }
+    }
+
     pub fn buffered_read_byte(&mut self, address: u16) -> u8 {
         if address < 0x3F00 {
             let value = self.read_buffer;
-            self.read_buffer = self.read_byte(address);
+            self.read_buffer = self.read_byte_as(address, CodeDataLogger::CHR_READ);
             value
         } else {
+            // The buffer is filled with the nametable byte "underneath" the palette
             let mirroring = self.mirroring();
-            self.read_buffer = self.nametables[mirror_nametable(mirroring, address)];
+            self.read_buffer = self.nametables[mirror_nametable(mirroring, address - 0x1000)];
             self.read_byte(address)
         }
     }
@@ -92,15 +151,35 @@ fn mirror_palette(address: u16) -> usize {
 fn mirror_nametable(mirroring: Mirroring, address: u16) -> usize {
     let address = address as usize;
     match mirroring {
-        Mirroring::None => address - 0x2000,
+        Mirroring::None => (address - 0x2000) % (2 * NAMETABLE_SIZE),
         Mirroring::Horizontal => ((address / 2) & NAMETABLE_SIZE) + (address % NAMETABLE_SIZE),
         Mirroring::Vertical => address % (2 * NAMETABLE_SIZE),
+        Mirroring::FourScreen => (address - 0x2000) % (4 * NAMETABLE_SIZE),
+        Mirroring::SingleScreenLow => address % NAMETABLE_SIZE,
+        Mirroring::SingleScreenHigh => NAMETABLE_SIZE + address % NAMETABLE_SIZE,
+    }
+}
+
+impl SaveState for Vram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.nametables);
+        w.write_bytes(&self.palette);
+        w.write_u8(self.read_buffer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.nametables)?;
+        r.read_bytes(&mut self.palette)?;
+        self.read_buffer = r.read_u8()?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::ines;
+
     #[test]
     fn test_mirror_nametable_horizontally() {
         // Nametable 1 - starting at 0x2000
@@ -109,4 +188,40 @@ mod test {
         assert_eq!(mirror_nametable(Mirroring::Horizontal, 0x2C01), 0x401);
         assert_eq!(mirror_nametable(Mirroring::Horizontal, 0x2E01), 0x601);
     }
+
+    #[test]
+    fn test_mirror_nametable_four_screen() {
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2401), 0x401);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2801), 0x801);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2C01), 0xC01);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x3001), 0x001);
+    }
+
+    #[test]
+    fn test_mirror_nametable_single_screen() {
+        assert_eq!(mirror_nametable(Mirroring::SingleScreenLow, 0x2001), 0x001);
+        assert_eq!(mirror_nametable(Mirroring::SingleScreenLow, 0x2C01), 0x001);
+        assert_eq!(mirror_nametable(Mirroring::SingleScreenHigh, 0x2401), 0x401);
+        assert_eq!(mirror_nametable(Mirroring::SingleScreenHigh, 0x2801), 0x401);
+    }
+
+    #[test]
+    fn test_chr_logging() {
+        let mut cartridge = Cartridge::new(&ines(0, 1, 1)).unwrap();
+        cartridge.enable_code_data_logger();
+        let cartridge = Rc::new(RefCell::new(cartridge));
+        let mut vram = Vram::new();
+        vram.set_cartridge(cartridge.clone());
+        let logged = |address: usize| {
+            let cartridge = cartridge.borrow();
+            cartridge.code_data_logger().unwrap().chr_rom()[address]
+        };
+
+        // Only logged once the bus says a logger is attached
+        vram.read_byte(0x0010);
+        assert_eq!(logged(0x10), 0);
+        vram.set_code_data_logging(true);
+        vram.read_byte(0x0010);
+        assert_eq!(logged(0x10), CodeDataLogger::CHR_RENDERED);
+    }
 }
@@ -0,0 +1,248 @@
+// RAM search, narrowing down where a game keeps a value
+//
+// A search starts with every byte of internal RAM and PRG RAM as a candidate
+// and a snapshot of both. Each filter compares the current values with the
+// snapshot, drops the candidates that don't match and takes a new snapshot,
+// so comparisons are always against the previous filter. Memory is read
+// through a MemoryView, which never disturbs emulation.
+//
+// Found addresses make freeze cheats, e.g. lives at $075A:
+//
+//   let mut search = RamSearch::new(&nes.memory(), ValueSize::Byte, false);
+//   // lose a life
+//   search.filter(&nes.memory(), Comparison::Equal, Operand::Difference(-1));
+
+use crate::memory::{MemorySpace, MemoryView};
+
+const SPACES: [MemorySpace; 2] = [MemorySpace::InternalRam, MemorySpace::PrgRam];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ValueSize {
+    Byte,
+    // Little endian, starting at the candidate's offset
+    Word,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand {
+    // The value at the previous filter
+    Previous,
+    Value(i32),
+    // Compares how much the value moved since the previous filter, so
+    // Equal with Difference(3) means increased by 3
+    Difference(i32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Candidate {
+    pub space: MemorySpace,
+    pub offset: usize,
+    // The value at the last filter
+    pub value: i32,
+}
+
+impl Candidate {
+    // Where the CPU sees the candidate, if PRG RAM is mapped at $6000-$7FFF
+    pub fn cpu_address(&self) -> Option<u16> {
+        match self.space {
+            MemorySpace::InternalRam => Some(self.offset as u16),
+            MemorySpace::PrgRam if self.offset < 0x2000 => Some(0x6000 + self.offset as u16),
+            _ => None,
+        }
+    }
+}
+
+pub struct RamSearch {
+    size: ValueSize,
+    signed: bool,
+    // Internal RAM and PRG RAM as of the last filter
+    snapshot: [Vec<u8>; 2],
+    // Indexes into SPACES and offsets
+    candidates: Vec<(usize, usize)>,
+}
+
+impl RamSearch {
+    pub fn new(memory: &MemoryView, size: ValueSize, signed: bool) -> Self {
+        let mut search = RamSearch {
+            size,
+            signed,
+            snapshot: [Vec::new(), Vec::new()],
+            candidates: Vec::new(),
+        };
+        search.reset(memory);
+        search
+    }
+
+    // Makes every address a candidate again
+    pub fn reset(&mut self, memory: &MemoryView) {
+        self.snapshot = SPACES.map(|space| memory.dump(space));
+        self.candidates = self.all_offsets();
+    }
+
+    // Changes how values are read, keeping the candidates
+    pub fn set_view(&mut self, size: ValueSize, signed: bool) {
+        self.size = size;
+        self.signed = signed;
+        let width = self.width();
+        let snapshot = &self.snapshot;
+        self.candidates
+            .retain(|&(space, offset)| offset + width <= snapshot[space].len());
+    }
+
+    // Keeps the candidates whose current value compares true against
+    // `operand`, returning how many are left
+    pub fn filter(
+        &mut self,
+        memory: &MemoryView,
+        comparison: Comparison,
+        operand: Operand,
+    ) -> usize {
+        let current = SPACES.map(|space| memory.dump(space));
+        let (size, signed) = (self.size, self.signed);
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&(space, offset)| {
+            let value = read_value(&current[space], offset, size, signed);
+            let previous = read_value(&snapshot[space], offset, size, signed);
+            let (left, right) = match operand {
+                Operand::Previous => (value, previous),
+                Operand::Value(n) => (value, n),
+                Operand::Difference(n) => (value - previous, n),
+            };
+            match comparison {
+                Comparison::Equal => left == right,
+                Comparison::NotEqual => left != right,
+                Comparison::Less => left < right,
+                Comparison::Greater => left > right,
+                Comparison::LessOrEqual => left <= right,
+                Comparison::GreaterOrEqual => left >= right,
+            }
+        });
+        self.snapshot = current;
+        self.candidates.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn candidates(&self) -> Vec<Candidate> {
+        self.candidates
+            .iter()
+            .map(|&(space, offset)| Candidate {
+                space: SPACES[space],
+                offset,
+                value: read_value(&self.snapshot[space], offset, self.size, self.signed),
+            })
+            .collect()
+    }
+
+    fn width(&self) -> usize {
+        match self.size {
+            ValueSize::Byte => 1,
+            ValueSize::Word => 2,
+        }
+    }
+
+    fn all_offsets(&self) -> Vec<(usize, usize)> {
+        let width = self.width();
+        let mut offsets = Vec::new();
+        for (space, bytes) in self.snapshot.iter().enumerate() {
+            let end = (bytes.len() + 1).saturating_sub(width);
+            offsets.extend((0..end).map(|offset| (space, offset)));
+        }
+        offsets
+    }
+}
+
+fn read_value(bytes: &[u8], offset: usize, size: ValueSize, signed: bool) -> i32 {
+    match (size, signed) {
+        (ValueSize::Byte, false) => bytes[offset] as i32,
+        (ValueSize::Byte, true) => bytes[offset] as i8 as i32,
+        (ValueSize::Word, signed) => {
+            let word = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            if signed {
+                word as i16 as i32
+            } else {
+                word as i32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    fn build_rom() -> Vec<u8> {
+        nrom(&[0x4C, 0x00, 0x80])
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        let mut search = RamSearch::new(&nes.memory(), ValueSize::Byte, false);
+        assert_eq!(search.len(), 0x800 + 0x2000);
+
+        let mut memory = nes.memory();
+        memory.poke(MemorySpace::InternalRam, 0x10, 5);
+        memory.poke(MemorySpace::PrgRam, 0x20, 3);
+        memory.poke(MemorySpace::InternalRam, 0x30, 0xFF);
+        assert_eq!(
+            search.filter(&memory, Comparison::NotEqual, Operand::Previous),
+            3
+        );
+        memory.poke(MemorySpace::InternalRam, 0x10, 8);
+        memory.poke(MemorySpace::PrgRam, 0x20, 2);
+        assert_eq!(
+            search.filter(&memory, Comparison::LessOrEqual, Operand::Previous),
+            2
+        );
+        let candidates = search.candidates();
+        assert_eq!(candidates[0].value, 0xFF);
+        assert_eq!(candidates[1].cpu_address(), Some(0x6020));
+
+        search.set_view(ValueSize::Byte, true);
+        assert_eq!(
+            search.filter(&memory, Comparison::Equal, Operand::Value(-1)),
+            1
+        );
+        assert_eq!(search.candidates()[0].cpu_address(), Some(0x0030));
+    }
+
+    #[test]
+    fn test_words() {
+        let mut nes = Nes::from_rom(&build_rom()).unwrap();
+        let mut search = RamSearch::new(&nes.memory(), ValueSize::Word, false);
+        assert_eq!(search.len(), 0x7FF + 0x1FFF);
+
+        let mut memory = nes.memory();
+        memory.poke(MemorySpace::InternalRam, 0x40, 0x2C);
+        memory.poke(MemorySpace::InternalRam, 0x41, 0x01);
+        search.filter(&memory, Comparison::Equal, Operand::Difference(300));
+        assert_eq!(search.len(), 1);
+        assert_eq!(search.candidates()[0].offset, 0x40);
+
+        // Emulation doesn't touch the NOP loop's RAM
+        nes.run_frame();
+        search.filter(&nes.memory(), Comparison::Equal, Operand::Previous);
+        assert_eq!(search.candidates()[0].value, 300);
+        search.reset(&nes.memory());
+        assert!(!search.is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+// Rewind keeps recent save states in a ring buffer bounded by memory.
+//
+// A snapshot is taken every `interval` frames. Every KEYFRAME_INTERVAL-th
+// snapshot is a keyframe stored run-length encoded, the ones in between
+// are stored as the RLE of their XOR against the previous keyframe, which
+// is mostly zeros since little of the machine changes in a few frames.
+// The controller input of every frame is logged as well, so rewinding can
+// restore the closest snapshot and replay up to the exact frame asked for.
+// Memory is reclaimed a keyframe at a time, so the limit should leave room
+// for several keyframes and their deltas.
+
+use std::collections::VecDeque;
+
+const KEYFRAME_INTERVAL: usize = 30;
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    interval: u64,
+    memory_limit: usize,
+    memory_used: usize,
+    snapshots: VecDeque<Snapshot>,
+    // Buttons of both controllers, keyed by the frame they were held during
+    inputs: VecDeque<(u64, [u8; 2])>,
+    // Uncompressed copy of the newest keyframe to diff against
+    keyframe: Vec<u8>,
+    deltas_since_keyframe: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, memory_limit: usize) -> Self {
+        Rewind {
+            interval: interval.max(1) as u64,
+            memory_limit,
+            memory_used: 0,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            keyframe: Vec::new(),
+            deltas_since_keyframe: 0,
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval as u32
+    }
+
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    pub fn clear(&mut self) {
+        self.memory_used = 0;
+        self.snapshots.clear();
+        self.inputs.clear();
+        self.keyframe.clear();
+        self.deltas_since_keyframe = 0;
+    }
+
+    pub fn record_input(&mut self, frame: u64, buttons: [u8; 2]) {
+        self.inputs.push_back((frame, buttons));
+        self.memory_used += INPUT_SIZE;
+        self.evict();
+    }
+
+    pub fn input(&self, frame: u64) -> Option<[u8; 2]> {
+        self.inputs
+            .iter()
+            .rev()
+            .find(|&&(f, _)| f == frame)
+            .map(|&(_, buttons)| buttons)
+    }
+
+    pub fn snapshot_due(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.interval)
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        let keyframe = self.deltas_since_keyframe + 1 >= KEYFRAME_INTERVAL
+            || self.keyframe.len() != state.len();
+        let data = if keyframe {
+            let data = encode(&state);
+            self.keyframe = state;
+            self.deltas_since_keyframe = 0;
+            data
+        } else {
+            self.deltas_since_keyframe += 1;
+            encode(&xor(&state, &self.keyframe))
+        };
+        self.memory_used += data.len();
+        self.snapshots.push_back(Snapshot {
+            frame,
+            keyframe,
+            data,
+        });
+        self.evict();
+    }
+
+    // Frame of the oldest snapshot that can still be restored
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.frame)
+    }
+
+    // Newest snapshot taken at or before `frame`, as (frame, state)
+    pub fn restore(&self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        let index = self.snapshots.iter().rposition(|s| s.frame <= frame)?;
+        let keyframe_index = self.snapshots.range(..=index).rposition(|s| s.keyframe)?;
+        let keyframe = decode(&self.snapshots[keyframe_index].data);
+        let snapshot = &self.snapshots[index];
+        let state = if snapshot.keyframe {
+            keyframe
+        } else {
+            xor(&decode(&snapshot.data), &keyframe)
+        };
+        Some((snapshot.frame, state))
+    }
+
+    // Forgets everything after `frame`, which has just been rewound to
+    pub fn truncate(&mut self, frame: u64) {
+        while self.snapshots.back().is_some_and(|s| s.frame > frame) {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            self.memory_used -= snapshot.data.len();
+        }
+        while self.inputs.back().is_some_and(|&(f, _)| f >= frame) {
+            self.inputs.pop_back();
+            self.memory_used -= INPUT_SIZE;
+        }
+        self.reset_keyframe();
+    }
+
+    // Drops the oldest snapshots until the buffer fits its memory limit.
+    // Deltas are useless without their keyframe, so they go along with it
+    fn evict(&mut self) {
+        while self.memory_used > self.memory_limit && !self.snapshots.is_empty() {
+            let snapshot = self.snapshots.pop_front().unwrap();
+            self.memory_used -= snapshot.data.len();
+            while self.snapshots.front().is_some_and(|s| !s.keyframe) {
+                let delta = self.snapshots.pop_front().unwrap();
+                self.memory_used -= delta.data.len();
+            }
+            let oldest = self.oldest_frame().unwrap_or(u64::MAX);
+            while self.inputs.front().is_some_and(|&(f, _)| f < oldest) {
+                self.inputs.pop_front();
+                self.memory_used -= INPUT_SIZE;
+            }
+        }
+        if self.snapshots.is_empty() {
+            self.reset_keyframe();
+        }
+    }
+
+    fn reset_keyframe(&mut self) {
+        match self.snapshots.iter().rposition(|s| s.keyframe) {
+            Some(index) => {
+                self.keyframe = decode(&self.snapshots[index].data);
+                self.deltas_since_keyframe = self.snapshots.len() - 1 - index;
+            }
+            None => {
+                self.keyframe.clear();
+                self.deltas_since_keyframe = 0;
+            }
+        }
+    }
+}
+
+const INPUT_SIZE: usize = std::mem::size_of::<(u64, [u8; 2])>();
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+// PackBits style run-length encoding. A control byte n < 128 is followed by
+// n + 1 literal bytes, n >= 128 by one byte repeated n - 125 times
+const MAX_LITERAL: usize = 128;
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 130;
+
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == data[i])
+            .count();
+        if run >= MIN_RUN {
+            flush_literal(&mut output, &data[literal_start..i]);
+            output.push((run + 125) as u8);
+            output.push(data[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    flush_literal(&mut output, &data[literal_start..]);
+    output
+}
+
+fn flush_literal(output: &mut Vec<u8>, literal: &[u8]) {
+    for chunk in literal.chunks(MAX_LITERAL) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+fn decode(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        if control < 128 {
+            output.extend_from_slice(&data[i + 1..i + 2 + control]);
+            i += 2 + control;
+        } else {
+            output.extend(std::iter::repeat_n(data[i + 1], control - 125));
+            i += 2;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Button;
+    use crate::nes::Nes;
+    use crate::test_util::{nrom, COUNT_A_PRESSES};
+
+    #[test]
+    fn test_rle() {
+        let mut data = vec![0; 1000];
+        data.extend_from_slice(&[1, 2, 3, 3, 4]);
+        data.extend((0..300).map(|i| i as u8));
+        data.extend_from_slice(&[7, 7, 7]);
+        let encoded = encode(&data);
+        assert!(encoded.len() < 350);
+        assert_eq!(decode(&encoded), data);
+        assert!(encode(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for frame in 0..100u64 {
+            let mut state = vec![0u8; 256];
+            state[0] = frame as u8;
+            rewind.push(frame, state);
+        }
+        let (frame, state) = rewind.restore(45).unwrap();
+        assert_eq!((frame, state[0]), (45, 45));
+
+        rewind.truncate(50);
+        assert_eq!(rewind.restore(99).unwrap().0, 50);
+        let mut state = vec![0u8; 256];
+        state[0] = 0xAA;
+        rewind.push(51, state);
+        assert_eq!(rewind.restore(99).unwrap().1[0], 0xAA);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut rewind = Rewind::new(1, 0x10000);
+        for frame in 0..1000u64 {
+            let state: Vec<u8> = (0..256).map(|i| (i as u64 * frame) as u8).collect();
+            rewind.record_input(frame, [frame as u8, 0]);
+            rewind.push(frame, state);
+            assert!(rewind.memory_used <= 0x10000);
+        }
+        let oldest = rewind.oldest_frame().unwrap();
+        assert!(oldest > 700);
+        assert!(rewind.snapshots.front().unwrap().keyframe);
+        assert_eq!(rewind.input(oldest), Some([oldest as u8, 0]));
+        assert_eq!(rewind.input(oldest - 1), None);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut nes = Nes::from_rom(&nrom(&COUNT_A_PRESSES)).unwrap();
+        assert_eq!(nes.rewind_frames(10), 0);
+        nes.enable_rewind(7, 1 << 20);
+
+        let mut history = Vec::new();
+        for frame in 0..60 {
+            nes.set_buttons(0, if frame % 3 == 0 { Button::A as u8 } else { 0 });
+            nes.run_frame();
+            history.push((
+                nes.frame_count(),
+                nes.cycles(),
+                nes.cpu.bus.read(0x0000),
+                nes.cpu.bus.read(0x0001),
+            ));
+        }
+
+        assert_eq!(nes.rewind_frames(25), 25);
+        let now = |nes: &mut Nes| {
+            (
+                nes.frame_count(),
+                nes.cycles(),
+                nes.cpu.bus.read(0x0000),
+                nes.cpu.bus.read(0x0001),
+            )
+        };
+        assert_eq!(now(&mut nes), history[34]);
+
+        // Between snapshots, the frames after the closest one are replayed
+        // with the recorded input
+        assert_eq!(nes.rewind_frames(2), 2);
+        assert_eq!(now(&mut nes), history[32]);
+        for frame in 33..60 {
+            nes.set_buttons(0, if frame % 3 == 0 { Button::A as u8 } else { 0 });
+            nes.run_frame();
+        }
+        assert_eq!(now(&mut nes), history[59]);
+        assert_eq!(nes.rewind_frames(23), 23);
+        assert_eq!(now(&mut nes), history[36]);
+
+        // Further back than the history goes stops at the oldest snapshot
+        let rewound = nes.rewind_frames(1000);
+        assert!(rewound > 0 && rewound < 35);
+        assert_eq!(nes.frame_count() % 7, 0);
+    }
+}
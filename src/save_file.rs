@@ -0,0 +1,51 @@
+// Keeps battery backed RAM in a .sav file next to the ROM, the way most
+// emulators do, so saves can be moved between them
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn save_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("sav")
+}
+
+// Returns None when the game hasn't been saved yet
+pub fn read(rom_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(save_path(rom_path)) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes to a temporary file first and renames it over the old save, so a
+// crash half way through never leaves a truncated .sav behind
+pub fn write(rom_path: &Path, data: &[u8]) -> io::Result<()> {
+    let path = save_path(rom_path);
+    let temp_path = path.with_extension("sav.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("saka-save-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("zelda.nes");
+        assert_eq!(save_path(&rom_path), dir.join("zelda.sav"));
+        assert_eq!(read(&rom_path).unwrap(), None);
+
+        write(&rom_path, &[1, 2, 3]).unwrap();
+        write(&rom_path, &[4, 5]).unwrap();
+        assert_eq!(read(&rom_path).unwrap(), Some(vec![4, 5]));
+        assert!(!dir.join("zelda.sav.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+// Save states
+//
+// A state is a small header followed by tagged sections, one per component:
+//
+//   "SAKA"  magic
+//   u16     format version
+//   u32     CRC-32 of the ROM file the state was taken from
+//   then for every section: 4 byte tag, u32 payload length, payload
+//
+// Sections can nest, e.g. the PPU section contains a VRAM section. All
+// integers are little endian. Components read their sections back in the
+// order they wrote them, so anything unexpected is rejected rather than
+// misinterpreted.
+
+use std::fmt;
+
+pub const STATE_MAGIC: [u8; 4] = *b"SAKA";
+pub const STATE_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    // The state was taken with a different ROM
+    RomMismatch { expected: u32, actual: u32 },
+    Truncated,
+    UnexpectedSection { expected: [u8; 4], found: [u8; 4] },
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch { expected, actual } => write!(
+                f,
+                "save state belongs to ROM {:08X}, not {:08X}",
+                actual, expected
+            ),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::UnexpectedSection { expected, found } => write!(
+                f,
+                "expected section {:?}, found {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            ),
+            StateError::Invalid(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed, read back with `read_bytes` into a buffer of the same size
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_section(&mut self, tag: &[u8; 4], state: &dyn SaveState) {
+        self.data.extend_from_slice(tag);
+        let length_position = self.data.len();
+        self.write_u32(0);
+        state.save_state(self);
+        let length = (self.data.len() - length_position - 4) as u32;
+        self.data[length_position..length_position + 4].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(StateError::Truncated)?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean out of range")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        usize::try_from(self.read_u64()?).map_err(|_| StateError::Invalid("size out of range"))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.take_array()?))
+    }
+
+    // Fills `bytes`, which must be as long as the slice that was written
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        if self.read_u32()? as usize != bytes.len() {
+            return Err(StateError::Invalid("memory size mismatch"));
+        }
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn read_section(
+        &mut self,
+        tag: &[u8; 4],
+        state: &mut dyn SaveState,
+    ) -> Result<(), StateError> {
+        let found: [u8; 4] = self.take_array()?;
+        if &found != tag {
+            return Err(StateError::UnexpectedSection {
+                expected: *tag,
+                found,
+            });
+        }
+        let length = self.read_u32()? as usize;
+        let mut section = StateReader::new(self.take(length)?);
+        state.load_state(&mut section)?;
+        if section.position != length {
+            return Err(StateError::Invalid("section is longer than expected"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Registers {
+        a: u8,
+        pc: u16,
+        ram: [u8; 4],
+    }
+
+    impl SaveState for Registers {
+        fn save_state(&self, w: &mut StateWriter) {
+            w.write_u8(self.a);
+            w.write_u16(self.pc);
+            w.write_bytes(&self.ram);
+        }
+
+        fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+            self.a = r.read_u8()?;
+            self.pc = r.read_u16()?;
+            r.read_bytes(&mut self.ram)
+        }
+    }
+
+    #[test]
+    fn test_sections() {
+        let registers = Registers {
+            a: 0x12,
+            pc: 0xC000,
+            ram: [1, 2, 3, 4],
+        };
+        let mut w = StateWriter::new();
+        w.write_section(b"REGS", &registers);
+        let data = w.into_inner();
+        assert_eq!(&data[0..8], b"REGS\x0B\x00\x00\x00");
+
+        let mut loaded = Registers {
+            a: 0,
+            pc: 0,
+            ram: [0; 4],
+        };
+        StateReader::new(&data)
+            .read_section(b"REGS", &mut loaded)
+            .unwrap();
+        assert_eq!(
+            (loaded.a, loaded.pc, loaded.ram),
+            (0x12, 0xC000, [1, 2, 3, 4])
+        );
+
+        assert_eq!(
+            StateReader::new(&data).read_section(b"CPU ", &mut loaded),
+            Err(StateError::UnexpectedSection {
+                expected: *b"CPU ",
+                found: *b"REGS"
+            })
+        );
+        assert_eq!(
+            StateReader::new(&data[..data.len() - 1]).read_section(b"REGS", &mut loaded),
+            Err(StateError::Truncated)
+        );
+    }
+}
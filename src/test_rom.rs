@@ -0,0 +1,138 @@
+// Runner for test ROMs following blargg's $6000 protocol
+// https://github.com/christopherpow/nes-test-roms/blob/master/README.md
+//
+// Once $6001-$6003 hold DE B0 61, $6000 is the test status: $80 while the
+// test is running, $81 when it wants the reset button pressed after at least
+// 100 ms, and the result code when it is done, 0 meaning it passed. A zero
+// terminated message is written from $6004. The memory is read through the
+// mapper so reading it doesn't disturb the running test.
+
+use crate::nes::Nes;
+
+const STATUS_ADDRESS: u16 = 0x6000;
+const SIGNATURE_ADDRESS: u16 = 0x6001;
+const MESSAGE_ADDRESS: u16 = 0x6004;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// 100 ms rounded up to whole NTSC frames
+const RESET_DELAY_FRAMES: u64 = 7;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    // The result code the ROM reported
+    Failed(u8),
+    // The ROM didn't report a result in time
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub outcome: TestOutcome,
+    pub message: String,
+    pub frames: u64,
+}
+
+// Runs the ROM loaded in `nes` until it reports a result or `timeout_frames`
+// frames have passed, pressing reset whenever it asks for it
+pub fn run_test_rom(nes: &mut Nes, timeout_frames: u64) -> TestResult {
+    let mut reset_at = None;
+    for frame in 1..=timeout_frames {
+        nes.run_frame();
+        if !has_signature(nes) {
+            continue;
+        }
+        match read(nes, STATUS_ADDRESS) {
+            STATUS_RUNNING => (),
+            STATUS_RESET => {
+                let due = *reset_at.get_or_insert(frame + RESET_DELAY_FRAMES);
+                if frame >= due {
+                    nes.reset();
+                    reset_at = None;
+                }
+            }
+            code => {
+                let outcome = match code {
+                    0 => TestOutcome::Passed,
+                    code => TestOutcome::Failed(code),
+                };
+                return TestResult {
+                    outcome,
+                    message: message(nes),
+                    frames: frame,
+                };
+            }
+        }
+    }
+    TestResult {
+        outcome: TestOutcome::TimedOut,
+        message: if has_signature(nes) {
+            message(nes)
+        } else {
+            String::new()
+        },
+        frames: timeout_frames,
+    }
+}
+
+fn read(nes: &Nes, address: u16) -> u8 {
+    nes.cartridge().read_prg_byte(address)
+}
+
+fn has_signature(nes: &Nes) -> bool {
+    (0..3).all(|i| read(nes, SIGNATURE_ADDRESS + i) == SIGNATURE[i as usize])
+}
+
+fn message(nes: &Nes) -> String {
+    let bytes: Vec<u8> = (MESSAGE_ADDRESS..=0x7FFF)
+        .map(|address| read(nes, address))
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::nrom;
+
+    // Asks for a reset on power up, then reports `code` with the message "ok"
+    fn protocol_rom(code: u8) -> Vec<u8> {
+        nrom(&[
+            0xAD, 0x00, 0x60, // LDA $6000
+            0xC9, 0x81, // CMP #$81
+            0xF0, 0x17, // BEQ done
+            0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE, STA $6001
+            0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0, STA $6002
+            0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61, STA $6003
+            0xA9, 0x81, 0x8D, 0x00, 0x60, // LDA #$81, STA $6000
+            0x4C, 0x1B, 0x80, // JMP *
+            // done:
+            0xA9, 0x6F, 0x8D, 0x04, 0x60, // LDA #'o', STA $6004
+            0xA9, 0x6B, 0x8D, 0x05, 0x60, // LDA #'k', STA $6005
+            0xA9, 0x00, 0x8D, 0x06, 0x60, // LDA #0, STA $6006
+            0xA9, code, 0x8D, 0x00, 0x60, // LDA #code, STA $6000
+            0x4C, 0x32, 0x80, // JMP *
+        ])
+    }
+
+    #[test]
+    fn test_protocol() {
+        let mut nes = Nes::from_rom(&protocol_rom(0)).unwrap();
+        let result = run_test_rom(&mut nes, 60);
+        assert_eq!(result.outcome, TestOutcome::Passed);
+        assert_eq!(result.message, "ok");
+        assert!(result.frames > RESET_DELAY_FRAMES);
+
+        let mut nes = Nes::from_rom(&protocol_rom(3)).unwrap();
+        assert_eq!(run_test_rom(&mut nes, 60).outcome, TestOutcome::Failed(3));
+
+        let mut nes = Nes::from_rom(&nrom(&[0x4C, 0x00, 0x80])).unwrap();
+        let result = run_test_rom(&mut nes, 10);
+        assert_eq!(result.outcome, TestOutcome::TimedOut);
+        assert_eq!(result.frames, 10);
+    }
+}
@@ -0,0 +1,44 @@
+// ROM images shared by the unit tests
+
+const HEADER_SIZE: usize = 16;
+const PRG_PAGE_SIZE: usize = 0x4000;
+const CHR_PAGE_SIZE: usize = 0x2000;
+
+// iNES image of `mapper` with zeroed ROM, sized in 16 KB PRG and 8 KB CHR pages
+pub(crate) fn ines(mapper: u8, prg_pages: u8, chr_pages: u8) -> Vec<u8> {
+    let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, prg_pages, chr_pages];
+    rom.extend_from_slice(&[mapper << 4, mapper & 0xF0]);
+    rom.extend_from_slice(&[0; 8]);
+    rom.resize(
+        HEADER_SIZE + prg_pages as usize * PRG_PAGE_SIZE + chr_pages as usize * CHR_PAGE_SIZE,
+        0,
+    );
+    rom
+}
+
+// NROM image running `program` from $8000, padded with NOPs, with every
+// vector pointing at $8000 and 8 KB of zeroed CHR ROM
+pub(crate) fn nrom(program: &[u8]) -> Vec<u8> {
+    let mut rom = ines(0, 1, 1);
+    let prg = &mut rom[HEADER_SIZE..HEADER_SIZE + PRG_PAGE_SIZE];
+    prg.fill(0xEA);
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    rom
+}
+
+// Counts how often the A button is seen held into $00-$01
+pub(crate) const COUNT_A_PRESSES: [u8; 28] = [
+    0xA9, 0x01, // LDA #$01
+    0x8D, 0x16, 0x40, // STA $4016
+    0xA9, 0x00, // LDA #$00
+    0x8D, 0x16, 0x40, // STA $4016
+    0xAD, 0x16, 0x40, // LDA $4016
+    0x29, 0x01, // AND #$01
+    0x65, 0x00, // ADC $00
+    0x85, 0x00, // STA $00
+    0x90, 0x02, // BCC +2
+    0xE6, 0x01, // INC $01
+    0x4C, 0x00, 0x80, // JMP $8000
+    0xEA, 0xEA,
+];
@@ -0,0 +1,313 @@
+// Trace logger writing a line for every executed instruction
+//
+// Lines follow the layouts of other emulators' trace loggers so traces can be
+// diffed against theirs:
+//
+// Nestest  C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+// Mesen    C000  4C F5 C5  JMP $C5F5                   A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Cycle:7
+// Fceux    c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5
+//
+// Like the debugger, the tracer lives on the system bus behind an Option and
+// sees each instruction after the debugger has let it run. Operands are
+// disassembled in ca65 syntax, without the memory values the other loggers
+// annotate them with.
+
+use crate::bus::SystemBus;
+use crate::debugger::{disassemble, Registers};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// The PPU numbers the pre-render line 261, Mesen numbers it -1
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TraceFormat {
+    Nestest,
+    Mesen,
+    Fceux,
+}
+
+enum Output {
+    Writer(Box<dyn Write>),
+    // Keeps the last `capacity` lines
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+    },
+}
+
+pub struct Tracer {
+    format: TraceFormat,
+    output: Output,
+    pc_range: Option<RangeInclusive<u16>>,
+    // Bank number and bank size in bytes of PRG ROM
+    bank: Option<(usize, usize)>,
+    // The first write error, after which nothing more is written
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn to_writer(format: TraceFormat, writer: Box<dyn Write>) -> Self {
+        Tracer::new(format, Output::Writer(writer))
+    }
+
+    pub fn to_file(format: TraceFormat, path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Tracer::to_writer(format, Box::new(file)))
+    }
+
+    pub fn ring_buffer(format: TraceFormat, capacity: usize) -> Self {
+        Tracer::new(
+            format,
+            Output::Ring {
+                lines: VecDeque::with_capacity(capacity),
+                capacity,
+            },
+        )
+    }
+
+    fn new(format: TraceFormat, output: Output) -> Self {
+        Tracer {
+            format,
+            output,
+            pc_range: None,
+            bank: None,
+            error: None,
+        }
+    }
+
+    // Only traces instructions whose PC is in `range`
+    pub fn filter_pc(&mut self, range: RangeInclusive<u16>) {
+        self.pc_range = Some(range);
+    }
+
+    // Only traces instructions in PRG ROM bank `bank`, counting banks of
+    // `bank_size` bytes from the start of PRG ROM
+    pub fn filter_bank(&mut self, bank: usize, bank_size: usize) {
+        assert!(bank_size > 0, "bank size must not be zero");
+        self.bank = Some((bank, bank_size));
+    }
+
+    pub fn clear_filters(&mut self) {
+        self.pc_range = None;
+        self.bank = None;
+    }
+
+    // Lines kept by a ring buffer, oldest first. Empty for other outputs
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        let lines = match &self.output {
+            Output::Ring { lines, .. } => Some(lines.iter().map(String::as_str)),
+            Output::Writer(_) => None,
+        };
+        lines.into_iter().flatten()
+    }
+
+    // Flushes the writer, returning the error that stopped the trace if
+    // there was one
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        match &mut self.output {
+            Output::Writer(writer) => writer.flush(),
+            Output::Ring { .. } => Ok(()),
+        }
+    }
+
+    // Called before every executed instruction
+    pub(crate) fn trace(&mut self, bus: &SystemBus, registers: Registers) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&registers.pc) {
+                return;
+            }
+        }
+        if let Some((bank, bank_size)) = self.bank {
+            match bus.prg_rom_offset(registers.pc) {
+                Some(offset) if offset / bank_size == bank => (),
+                _ => return,
+            }
+        }
+
+        let line = format_line(self.format, bus, registers);
+        match &mut self.output {
+            Output::Writer(writer) => {
+                if let Err(error) = writeln!(writer, "{}", line) {
+                    self.error = Some(error);
+                }
+            }
+            Output::Ring { lines, capacity } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                if *capacity > 0 {
+                    lines.push_back(line);
+                }
+            }
+        }
+    }
+}
+
+// Formats the instruction at PC, before it runs
+pub(crate) fn format_line(format: TraceFormat, bus: &SystemBus, registers: Registers) -> String {
+    let instruction = disassemble(bus, registers.pc, &registers, None);
+    let text = instruction.to_string();
+    let mut bytes = String::new();
+    for byte in &instruction.bytes {
+        write!(bytes, "{:02X} ", byte).unwrap();
+    }
+    let bytes = bytes.trim_end();
+    let marker = if instruction.unofficial { '*' } else { ' ' };
+    let r = registers;
+    let (scanline, dot) = (bus.ppu.scanline, bus.ppu.cycle);
+    match format {
+        TraceFormat::Nestest => format!(
+            "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            r.pc, bytes, marker, text, r.a, r.x, r.y, r.p, r.sp, scanline, dot, bus.cycles
+        ),
+        TraceFormat::Mesen => {
+            let scanline = match scanline {
+                PRE_RENDER_SCANLINE => -1,
+                scanline => scanline as i32,
+            };
+            format!(
+                "{:04X}  {:<8} {}{:<27} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Cycle:{}",
+                r.pc, bytes, marker, text, r.a, r.x, r.y, r.sp, flags(r.p), scanline, dot, bus.cycles
+            )
+        }
+        TraceFormat::Fceux => format!(
+            "c{:<10} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${:04X}:{:<8} {}{}",
+            bus.cycles, r.a, r.x, r.y, r.sp, flags(r.p), r.pc, bytes, marker, text
+        ),
+    }
+}
+
+// Status flags as letters, upper case when set, e.g. nvUbdIzc
+fn flags(p: u8) -> String {
+    "NVUBDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if p & (0x80 >> i) != 0 {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::test_util::nrom;
+
+    fn bus_with(program: &[u8]) -> SystemBus {
+        let mut bus = SystemBus::new();
+        for (i, &byte) in program.iter().enumerate() {
+            bus.write(i as u16, byte);
+        }
+        bus
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut bus = bus_with(&[0x4C, 0xF5, 0xC5, 0xA7, 0x10]);
+        bus.cycles = 7;
+        bus.ppu.cycle = 21;
+        let registers = Registers {
+            pc: 0,
+            a: 0,
+            x: 0,
+            y: 0,
+            sp: 0xFD,
+            p: 0x24,
+        };
+        assert_eq!(
+            format_line(TraceFormat::Nestest, &bus, registers),
+            "0000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+        assert_eq!(
+            format_line(TraceFormat::Mesen, &bus, registers),
+            "0000  4C F5 C5  JMP $C5F5                   A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Cycle:7"
+        );
+        assert_eq!(
+            format_line(TraceFormat::Fceux, &bus, registers),
+            "c7          A:00 X:00 Y:00 S:FD P:nvUbdIzc  $0000:4C F5 C5  JMP $C5F5"
+        );
+
+        bus.ppu.scanline = PRE_RENDER_SCANLINE;
+        let registers = Registers { pc: 3, ..registers };
+        assert_eq!(
+            &format_line(TraceFormat::Mesen, &bus, registers)[..48],
+            "0003  A7 10    *LAX $10                     A:00"
+        );
+        assert!(format_line(TraceFormat::Mesen, &bus, registers).contains(" V:-1  "));
+    }
+
+    #[test]
+    fn test_ring_buffer_and_filters() {
+        let bus = bus_with(&[0xEA, 0xEA, 0xEA, 0xEA]);
+        let registers = |pc| Registers {
+            pc,
+            ..Registers::default()
+        };
+
+        let mut tracer = Tracer::ring_buffer(TraceFormat::Nestest, 2);
+        for pc in 0..4 {
+            tracer.trace(&bus, registers(pc));
+        }
+        let lines: Vec<&str> = tracer.lines().map(|line| &line[..4]).collect();
+        assert_eq!(lines, ["0002", "0003"]);
+
+        let mut tracer = Tracer::ring_buffer(TraceFormat::Nestest, 10);
+        tracer.filter_pc(1..=2);
+        for pc in 0..4 {
+            tracer.trace(&bus, registers(pc));
+        }
+        assert_eq!(tracer.lines().count(), 2);
+
+        // RAM isn't in any PRG ROM bank
+        tracer.filter_bank(0, 0x4000);
+        tracer.trace(&bus, registers(0));
+        assert_eq!(tracer.lines().count(), 2);
+        tracer.clear_filters();
+        tracer.trace(&bus, registers(0));
+        assert_eq!(tracer.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut nes = Nes::from_rom(&nrom(&[
+            0xA9, 0x10, // LDA #$10
+            0x8D, 0x00, 0x02, // STA $0200
+            0x4C, 0x00, 0x80, // JMP $8000
+        ]))
+        .unwrap();
+        let mut tracer = Tracer::ring_buffer(TraceFormat::Nestest, 16);
+        tracer.filter_bank(0, 0x4000);
+        nes.start_trace(tracer);
+        for _ in 0..4 {
+            nes.step_instruction();
+        }
+        let tracer = nes.stop_trace().unwrap();
+        let lines: Vec<&str> = tracer.lines().map(|line| &line[..25]).collect();
+        assert_eq!(
+            lines,
+            [
+                "8000  A9 10     LDA #$10 ",
+                "8002  8D 00 02  STA $0200",
+                "8005  4C 00 80  JMP $8000",
+                "8000  A9 10     LDA #$10 ",
+            ]
+        );
+        assert!(tracer.lines().nth(1).unwrap().contains("A:10"));
+    }
+}
@@ -0,0 +1,60 @@
+// blargg's test ROMs, run with the $6000 status protocol
+// https://github.com/christopherpow/nes-test-roms
+//
+// The ROMs aren't redistributed, so the test is ignored by default. Copy them
+// to tests/fixtures/blargg and run it with
+// `cargo test --release --test blargg -- --ignored`.
+
+mod common;
+
+use saka_nes_simulator::{run_test_rom, Nes, TestOutcome};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// 60 seconds, enough for the slowest of blargg's ROMs
+const TIMEOUT_FRAMES: u64 = 60 * 60;
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|e| e == "nes") {
+            roms.push(path);
+        }
+    }
+}
+
+// Runs every ROM under tests/fixtures/blargg and reports all failures
+#[test]
+#[ignore = "needs blargg's ROMs in tests/fixtures/blargg"]
+fn test_blargg_roms() {
+    let dir = common::fixture("blargg");
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    assert!(!roms.is_empty(), "tests/fixtures/blargg has no .nes files");
+    roms.sort();
+
+    let mut failures = Vec::new();
+    for path in roms {
+        let name = path.strip_prefix(&dir).unwrap().display().to_string();
+        let rom = fs::read(&path).unwrap();
+        let mut nes = match Nes::from_rom(&rom) {
+            Ok(nes) => nes,
+            Err(error) => {
+                failures.push(format!("{}: {}", name, error));
+                continue;
+            }
+        };
+        let result = run_test_rom(&mut nes, TIMEOUT_FRAMES);
+        if result.outcome != TestOutcome::Passed {
+            failures.push(format!(
+                "{}: {:?}\n{}",
+                name, result.outcome, result.message
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
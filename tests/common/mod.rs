@@ -0,0 +1,19 @@
+// Helpers shared by the integration tests
+
+use std::path::PathBuf;
+
+// The path of a file or directory in tests/fixtures. The tests that need
+// fixtures are ignored by default, so one that is asked for and missing
+// fails the test rather than letting it pass unnoticed
+pub fn fixture(name: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name);
+    assert!(
+        path.exists(),
+        "tests/fixtures/{} is missing, see tests/fixtures/README.md",
+        name
+    );
+    path
+}
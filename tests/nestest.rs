@@ -0,0 +1,57 @@
+// Golden log test against nestest.nes
+// https://www.qmtpro.com/~nes/misc/nestest.txt
+//
+// nestest runs every official and unofficial opcode in automation mode when
+// started at $C000 and records its own error codes at $0002 and $0003. The
+// address, bytes and registers traced for every instruction are compared
+// with the reference log.
+//
+// The ROM and log aren't redistributed, so the test is ignored by default.
+// Put them in tests/fixtures and run it with
+// `cargo test --test nestest -- --ignored`.
+
+mod common;
+
+use saka_nes_simulator::{Nes, TraceFormat, Tracer};
+use std::fs;
+
+// Column where the register dump starts in nestest.log
+const REGISTERS_COLUMN: usize = 48;
+// Offset of the reset vector in the 16KB ROM image
+const RESET_VECTOR: usize = 16 + 0x3FFC;
+
+// The disassembly column isn't compared. nestest.log annotates operands with
+// the memory they touch and spells some unofficial opcodes differently (ISB
+// for ISC), while the tracer prints plain ca65 syntax, so the text can't
+// match. The instruction bytes pin down the same instruction.
+fn matches(expected: &str, actual: &str) -> bool {
+    expected.get(..14).map(str::trim_end) == actual.get(..14).map(str::trim_end)
+        && expected.get(REGISTERS_COLUMN..) == actual.get(REGISTERS_COLUMN..)
+}
+
+#[test]
+#[ignore = "needs nestest.nes and nestest.log in tests/fixtures"]
+fn test_nestest() {
+    let mut rom = fs::read(common::fixture("nestest.nes")).unwrap();
+    let log = fs::read_to_string(common::fixture("nestest.log")).unwrap();
+
+    // Automation mode starts at $C000 rather than at the reset vector
+    rom[RESET_VECTOR..RESET_VECTOR + 2].copy_from_slice(&[0x00, 0xC0]);
+    let mut nes = Nes::from_rom(&rom).unwrap();
+    nes.start_trace(Tracer::ring_buffer(TraceFormat::Nestest, 1));
+
+    for (number, expected) in log.lines().enumerate() {
+        nes.step_instruction();
+        let actual = nes.tracer().unwrap().lines().next().unwrap().to_string();
+        assert!(
+            matches(expected, &actual),
+            "nestest diverged at line {}\nexpected: {}\nactual:   {}",
+            number + 1,
+            expected,
+            actual
+        );
+    }
+
+    assert_eq!(nes.ram()[0x0002], 0, "official opcode error code");
+    assert_eq!(nes.ram()[0x0003], 0, "unofficial opcode error code");
+}